@@ -1,5 +1,22 @@
+use std::process::Command;
+
 fn main() {
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+    println!(
+        "cargo:rustc-link-search={}",
+        std::env::var("CARGO_MANIFEST_DIR").unwrap()
+    );
+    println!("cargo:rustc-link-arg-bins=-Tparam_page.x");
+
+    // The git hash is reported by `GetDeviceInfo`, so the host can tell which build is running
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=FW_GIT_HASH={}", git_hash.trim());
 }
@@ -0,0 +1,7 @@
+/* The last page of the flash (2K on the stm32f303vc) stores the device parameters, see
+ * `PARAM_PAGE_OFFSET` in src/hal/stm32.rs. `memory.x` of embassy-stm32 maps the whole flash,
+ * so the link fails instead of placing the program over the page */
+PARAM_PAGE_SIZE = 2K;
+
+ASSERT(__veneer_limit <= ORIGIN(FLASH) + LENGTH(FLASH) - PARAM_PAGE_SIZE, "
+ERROR(fw): the program overlaps the parameter page at the end of the flash");
@@ -0,0 +1,185 @@
+use core::f32::consts::PI;
+
+use num_traits::Float;
+
+use protocol::{AutoTuneConfig, AutoTuneError, AutoTuneResult, AutoTuneStatus, PidGains, TuningRule};
+
+/// Relay feedback auto tuning (Åström–Hägglund). A relay with hysteresis is applied on the
+/// velocity error, which makes the loop oscillate at the ultimate frequency. The amplitude
+/// and period of the oscillation give the ultimate gain and period, and PID gains are
+/// computed from them with the selected tuning rule.
+pub struct RelayAutoTuner {
+    config: AutoTuneConfig,
+    running: bool,
+    // Output of the relay is `bias +/- relay_amplitude`, bias is the output that keeps the
+    // motor running around target velocity
+    bias: f32,
+    feed_forward_gains: (f32, f32),
+    output_high: bool,
+    elapsed_s: f32,
+    last_rising_switch_s: Option<f32>,
+    peak_max: f32,
+    peak_min: f32,
+    // The first cycle is a transient, it is only used to sync the oscillation
+    skipped_first_cycle: bool,
+    measured_cycles: u8,
+    period_sum: f32,
+    amplitude_sum: f32,
+    status: Option<AutoTuneStatus>,
+}
+
+impl Default for RelayAutoTuner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RelayAutoTuner {
+    pub fn new() -> Self {
+        Self {
+            config: AutoTuneConfig::default(),
+            running: false,
+            bias: 0.0,
+            feed_forward_gains: (0.0, 0.0),
+            output_high: true,
+            elapsed_s: 0.0,
+            last_rising_switch_s: None,
+            peak_max: f32::MIN,
+            peak_min: f32::MAX,
+            skipped_first_cycle: false,
+            measured_cycles: 0,
+            period_sum: 0.0,
+            amplitude_sum: 0.0,
+            status: None,
+        }
+    }
+
+    pub fn start(&mut self, config: AutoTuneConfig, curr_gains: &PidGains) {
+        *self = Self::new();
+        self.config = config;
+        self.running = true;
+        self.bias = curr_gains.kff_vel * config.target_vel;
+        self.feed_forward_gains = (curr_gains.kff_vel, curr_gains.kff_acc);
+        self.status = Some(AutoTuneStatus::Running(0));
+    }
+
+    pub fn abort(&mut self) {
+        if self.running {
+            self.running = false;
+            self.status = Some(AutoTuneStatus::Aborted);
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Takes the latest status, the status is only updated when there is progress, so the
+    /// caller can forward it without flooding the communication
+    pub fn take_status(&mut self) -> Option<AutoTuneStatus> {
+        self.status.take()
+    }
+
+    /// Returns the control effort that should be applied to the motor
+    pub fn run(&mut self, act_velocity_rpm: f32, period_s: f32) -> f32 {
+        if !self.running {
+            return 0.0;
+        }
+
+        self.elapsed_s += period_s;
+        self.peak_max = self.peak_max.max(act_velocity_rpm);
+        self.peak_min = self.peak_min.min(act_velocity_rpm);
+
+        let error = self.config.target_vel - act_velocity_rpm;
+        if !self.output_high && error > self.config.hysteresis {
+            self.output_high = true;
+            self.on_rising_switch();
+        } else if self.output_high && error < -self.config.hysteresis {
+            self.output_high = false;
+        }
+
+        if self.running && self.measured_cycles >= self.config.cycles.max(1) {
+            self.finish();
+        } else if self.running && self.elapsed_s > self.config.timeout_s {
+            self.fail(AutoTuneError::Timeout);
+        }
+
+        if !self.running {
+            return 0.0;
+        }
+
+        if self.output_high {
+            self.bias + self.config.relay_amplitude
+        } else {
+            self.bias - self.config.relay_amplitude
+        }
+    }
+
+    fn on_rising_switch(&mut self) {
+        if let Some(prev_switch_s) = self.last_rising_switch_s {
+            if self.skipped_first_cycle {
+                self.period_sum += self.elapsed_s - prev_switch_s;
+                self.amplitude_sum += (self.peak_max - self.peak_min) / 2.0;
+                self.measured_cycles += 1;
+                self.status = Some(AutoTuneStatus::Running(self.measured_cycles));
+            }
+            self.skipped_first_cycle = true;
+        }
+
+        self.last_rising_switch_s = Some(self.elapsed_s);
+        self.peak_max = f32::MIN;
+        self.peak_min = f32::MAX;
+    }
+
+    fn finish(&mut self) {
+        let cycles = self.measured_cycles as f32;
+        let ultimate_period = self.period_sum / cycles;
+        let amplitude = self.amplitude_sum / cycles;
+
+        // The hysteresis shifts the switching point, take it into account when computing
+        // the describing function of the relay
+        let hysteresis = self.config.hysteresis;
+        if amplitude <= hysteresis || ultimate_period <= 0.0 {
+            self.fail(AutoTuneError::NoOscillation);
+            return;
+        }
+
+        let ultimate_gain = 4.0 * self.config.relay_amplitude
+            / (PI * Float::sqrt(amplitude * amplitude - hysteresis * hysteresis));
+
+        self.running = false;
+        self.status = Some(AutoTuneStatus::Finished(AutoTuneResult {
+            ultimate_gain,
+            ultimate_period,
+            gains: self.compute_gains(ultimate_gain, ultimate_period),
+        }));
+    }
+
+    fn fail(&mut self, error: AutoTuneError) {
+        self.running = false;
+        self.status = Some(AutoTuneStatus::Failed(error));
+    }
+
+    fn compute_gains(&self, ultimate_gain: f32, ultimate_period: f32) -> PidGains {
+        let (kp, ti, td) = match self.config.rule {
+            TuningRule::ZieglerNichols => (
+                0.6 * ultimate_gain,
+                ultimate_period / 2.0,
+                ultimate_period / 8.0,
+            ),
+            TuningRule::TyreusLuyben => (
+                ultimate_gain / 2.2,
+                2.2 * ultimate_period,
+                ultimate_period / 6.3,
+            ),
+        };
+
+        PidGains {
+            kp,
+            ki: kp / ti,
+            kd: kp * td,
+            kff_vel: self.feed_forward_gains.0,
+            kff_acc: self.feed_forward_gains.1,
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Wiring of the two motion controllers that is shared by the firmware and the simulated
+//! device, the channels between the control loop and the endpoint handlers and one cycle of
+//! the control loop. The binary only provides the hardware and the timing of the cycle.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::{Receiver, Watch};
+use protocol::{
+    AutoTuneStatus, Heartbeat, MotionEvent, MotorId, SequencedCommand, TelemetryConfig,
+    TelemetrySample, MAX_POSITION_BATCH_SIZE,
+};
+use s_curve::SCurveInterpolator;
+
+use crate::encoder::Encoder;
+use crate::fault::{FaultLimits, FaultSupervisor};
+use crate::hal::{Clock, MotorDriver, PositionSensor};
+use crate::motion::{CommandLimits, Motion, MotorStatus};
+use crate::motor::BldcMotor24H;
+use crate::params::DeviceParams;
+use crate::rpm_to_rad_s;
+use crate::telemetry::{TelemetryRecorder, DEFAULT_TELEMETRY_CONFIG};
+use crate::watchdog::CommWatchdog;
+
+pub const MOTOR_COUNT: u8 = 2;
+
+// control loop
+pub const PERIOD_S: f32 = 0.005;
+pub const VEL_LIMIT_RPM: f32 = 4000.0;
+pub const ENCODER_COUNTS_PER_REV: u16 = 400;
+
+// Fault detection, the overspeed limit has a margin for the quantization of encoder velocity
+pub const STALL_EFFORT: f32 = 0.5;
+pub const STALL_TIME_S: f32 = 0.5;
+pub const FOLLOWING_ERROR_LIMIT_RAD: f32 = 2.0 * core::f32::consts::PI;
+pub const OVERSPEED_MARGIN: f32 = 1.1;
+pub const MAX_CYCLE_TIME_PERIODS: f32 = 2.0;
+
+// Defaults of the parameters that the host can change at runtime, see `crate::params`
+pub const DEFAULT_PARAMS: DeviceParams =
+    DeviceParams::new(PERIOD_S, ENCODER_COUNTS_PER_REV, VEL_LIMIT_RPM);
+
+// The `CHANNEL_SIZE` is used in `PubSubChannel` and `MOTION_CMD_QUEUE_SIZE` is used
+// in motion struct. If the queue in motion struct is full, I want to make sure there
+// are spaces in `PubSubChannel`, so `Halt` command can be sent to motion struct.
+// And for the other commands, since they don't have the same priority as `Halt`, the
+// sender needs to wait until there are spaces in the queue.
+pub const CHANNEL_SIZE: usize = 48;
+pub const MOTION_CMD_QUEUE_SIZE: usize = 32;
+// A full batch fits into an empty queue
+const _: () = assert!(MAX_POSITION_BATCH_SIZE <= MOTION_CMD_QUEUE_SIZE);
+pub const AUTO_TUNE_STATUS_CHANNEL_SIZE: usize = 4;
+// A halt aborts all the queued commands at once, the event queue in motion struct needs to
+// hold them and the events of the command that is set in the same cycle
+pub const MOTION_EVENT_QUEUE_SIZE: usize = MOTION_CMD_QUEUE_SIZE + 8;
+pub const MOTION_EVENT_CHANNEL_SIZE: usize = 2 * MOTION_EVENT_QUEUE_SIZE;
+// About 160ms of the samples of both motors at 200Hz
+pub const TELEMETRY_CHANNEL_SIZE: usize = 64;
+
+pub type CommandChannel =
+    PubSubChannel<CriticalSectionRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>;
+pub type StatusWatch = Watch<CriticalSectionRawMutex, MotorStatus, 2>;
+
+pub type DeviceMotion<S, D, C> = Motion<
+    'static,
+    CriticalSectionRawMutex,
+    S,
+    D,
+    C,
+    CHANNEL_SIZE,
+    MOTION_CMD_QUEUE_SIZE,
+    MOTION_EVENT_QUEUE_SIZE,
+>;
+
+/// Channels between the control loop, the endpoint handlers and the publish tasks, the binary
+/// keeps one instance in a static
+pub struct DeviceChannels {
+    pub left_motor_cmd: CommandChannel,
+    pub right_motor_cmd: CommandChannel,
+    // Auto tuning progress/result is only sent when there is an update, so a small channel is
+    // used instead of `Watch` to make sure the result is not overwritten by the next progress
+    pub auto_tune_status:
+        Channel<CriticalSectionRawMutex, (MotorId, AutoTuneStatus), AUTO_TUNE_STATUS_CHANNEL_SIZE>,
+    // The events are drained from motion struct every cycle, the channel buffers them for the
+    // publish task so the host can follow every command by its sequence id
+    pub motion_event:
+        Channel<CriticalSectionRawMutex, (MotorId, MotionEvent), MOTION_EVENT_CHANNEL_SIZE>,
+    // Ring buffer of the recorded samples, the control loop pushes every sample and the publish
+    // task sends them in batches. When the channel is full the new samples are dropped, and the
+    // host sees the gap in `TelemetrySample::seq`
+    pub telemetry:
+        Channel<CriticalSectionRawMutex, (MotorId, TelemetrySample), TELEMETRY_CHANNEL_SIZE>,
+    // The control loop applies the decimation and the publish task applies the batch length
+    pub telemetry_config: Watch<CriticalSectionRawMutex, TelemetryConfig, 2>,
+    // The heartbeat handler only signals the latest heartbeat, the watchdog is checked in the
+    // control loop, so it still halts the motors when the communication tasks are stuck
+    pub heartbeat: Signal<CriticalSectionRawMutex, Heartbeat>,
+    // The registry is owned by the endpoint handlers, the control loop gets a copy of the
+    // values when a parameter is changed, like the pid gains
+    pub params: Signal<CriticalSectionRawMutex, DeviceParams>,
+    // Set while the parameter page is erased or written, the cpu is stalled and the control
+    // cycles are late on purpose
+    pub param_flash_busy: AtomicBool,
+    pub left_motor_status: StatusWatch,
+    pub right_motor_status: StatusWatch,
+}
+
+impl DeviceChannels {
+    pub const fn new() -> Self {
+        Self {
+            left_motor_cmd: PubSubChannel::new(),
+            right_motor_cmd: PubSubChannel::new(),
+            auto_tune_status: Channel::new(),
+            motion_event: Channel::new(),
+            telemetry: Channel::new(),
+            telemetry_config: Watch::new(),
+            heartbeat: Signal::new(),
+            params: Signal::new(),
+            param_flash_busy: AtomicBool::new(false),
+            left_motor_status: Watch::new(),
+            right_motor_status: Watch::new(),
+        }
+    }
+
+    pub fn motor_cmd(&self, id: MotorId) -> &CommandChannel {
+        match id {
+            MotorId::Left => &self.left_motor_cmd,
+            MotorId::Right => &self.right_motor_cmd,
+        }
+    }
+
+    pub fn motor_status(&self, id: MotorId) -> &StatusWatch {
+        match id {
+            MotorId::Left => &self.left_motor_status,
+            MotorId::Right => &self.right_motor_status,
+        }
+    }
+}
+
+impl Default for DeviceChannels {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn fault_supervisor(params: &DeviceParams) -> FaultSupervisor {
+    FaultSupervisor::new(FaultLimits {
+        stall_effort: STALL_EFFORT,
+        stall_time_s: STALL_TIME_S,
+        following_error_rad: FOLLOWING_ERROR_LIMIT_RAD,
+        overspeed_rpm: params.vel_limit_rpm * OVERSPEED_MARGIN,
+        max_cycle_time_us: (MAX_CYCLE_TIME_PERIODS * params.period_s * 1_000_000.0) as u64,
+    })
+}
+
+// Limits of the commands from host, the wheels rotate continuously, so there is no travel limit
+pub fn command_limits(params: &DeviceParams) -> CommandLimits {
+    CommandLimits {
+        vel_limit_rpm: params.vel_limit_rpm,
+        stop_limits: interpolator(params).stop_limits(),
+        travel_limits_rad: None,
+    }
+}
+
+// The interpolator of a wheel, the limits are updated by `Motion::set_params`
+fn interpolator(params: &DeviceParams) -> SCurveInterpolator {
+    SCurveInterpolator::new(
+        rpm_to_rad_s(params.vel_limit_rpm),
+        params.acc_limit,
+        params.jerk_limit,
+        params.period_s,
+    )
+}
+
+/// Creates the motion controller of one wheel with the parameters that the device is started
+/// with, the commands are taken from the channel of the motor
+pub fn device_motion<S: PositionSensor, D: MotorDriver, C: Clock>(
+    id: MotorId,
+    sensor: S,
+    driver: D,
+    clock: C,
+    params: &DeviceParams,
+    channels: &'static DeviceChannels,
+) -> DeviceMotion<S, D, C> {
+    let motor = BldcMotor24H::new(
+        Encoder::new(sensor, params.counts_per_rev),
+        driver,
+        clock,
+        params.velocity_pid(id),
+        params.period_s,
+    );
+    // The output of the position controller is the velocity correction that is added to the
+    // interpolated velocity
+    let pos_controller = params.position_controller();
+
+    let mut motion = Motion::new(
+        interpolator(params),
+        motor,
+        pos_controller,
+        fault_supervisor(params),
+        channels.motor_cmd(id).subscriber().unwrap(),
+    );
+    motion.set_params(params);
+    motion
+}
+
+/// Both motion controllers and the state of the control loop that is kept between the cycles
+pub struct ControlLoop<SL, SR, D, C>
+where
+    SL: PositionSensor,
+    SR: PositionSensor,
+    D: MotorDriver,
+    C: Clock,
+{
+    pub left: DeviceMotion<SL, D, C>,
+    pub right: DeviceMotion<SR, D, C>,
+    channels: &'static DeviceChannels,
+    telemetry_config: Receiver<'static, CriticalSectionRawMutex, TelemetryConfig, 2>,
+    watchdog: CommWatchdog,
+    flash_was_busy: bool,
+    left_telemetry: TelemetryRecorder,
+    right_telemetry: TelemetryRecorder,
+}
+
+impl<SL, SR, D, C> ControlLoop<SL, SR, D, C>
+where
+    SL: PositionSensor,
+    SR: PositionSensor,
+    D: MotorDriver,
+    C: Clock,
+{
+    pub fn new(
+        left: DeviceMotion<SL, D, C>,
+        right: DeviceMotion<SR, D, C>,
+        channels: &'static DeviceChannels,
+    ) -> Self {
+        Self {
+            left,
+            right,
+            channels,
+            telemetry_config: channels.telemetry_config.receiver().unwrap(),
+            watchdog: CommWatchdog::new(),
+            flash_was_busy: false,
+            left_telemetry: TelemetryRecorder::new(&DEFAULT_TELEMETRY_CONFIG),
+            right_telemetry: TelemetryRecorder::new(&DEFAULT_TELEMETRY_CONFIG),
+        }
+    }
+
+    /// Runs one control cycle, returns true when the motors are halted because the heartbeat
+    /// is lost in this cycle
+    pub fn cycle(&mut self) -> bool {
+        let channels = self.channels;
+
+        // The cycles during and right after a flash write are late, restart the cycle time
+        // check instead of latching an overrun
+        let flash_busy = channels.param_flash_busy.load(Ordering::Relaxed);
+        if flash_busy || self.flash_was_busy {
+            self.left.fault_supervisor.restart_cycle_check();
+            self.right.fault_supervisor.restart_cycle_check();
+        }
+        self.flash_was_busy = flash_busy;
+
+        let now_us = self.left.motor.get_clock().now_us();
+        if let Some(x) = channels.heartbeat.try_take() {
+            self.watchdog.feed(now_us, x.timeout_ms);
+        }
+        let heartbeat_lost = self.watchdog.check(now_us);
+        if heartbeat_lost {
+            self.left.halt();
+            self.right.halt();
+        }
+
+        if let Some(params) = channels.params.try_take() {
+            let fault_limits = FaultLimits {
+                overspeed_rpm: params.vel_limit_rpm * OVERSPEED_MARGIN,
+                ..self.left.fault_supervisor.get_limits()
+            };
+            self.left.set_params(&params);
+            self.left
+                .motor
+                .pid
+                .set_gains(params.pid_gains(MotorId::Left));
+            self.left.fault_supervisor.set_limits(fault_limits);
+            self.right.set_params(&params);
+            self.right
+                .motor
+                .pid
+                .set_gains(params.pid_gains(MotorId::Right));
+            self.right.fault_supervisor.set_limits(fault_limits);
+        }
+
+        if let Some(config) = self.telemetry_config.try_changed() {
+            self.left_telemetry.set_config(&config);
+            self.right_telemetry.set_config(&config);
+        }
+
+        self.left.read_cmd_from_queue();
+        self.right.read_cmd_from_queue();
+
+        self.left.run();
+        self.right.run();
+
+        if let Some(status) = self.left.take_auto_tune_status() {
+            let _ = channels.auto_tune_status.try_send((MotorId::Left, status));
+        }
+        if let Some(status) = self.right.take_auto_tune_status() {
+            let _ = channels.auto_tune_status.try_send((MotorId::Right, status));
+        }
+
+        while let Some(event) = self.left.take_motion_event() {
+            let _ = channels.motion_event.try_send((MotorId::Left, event));
+        }
+        while let Some(event) = self.right.take_motion_event() {
+            let _ = channels.motion_event.try_send((MotorId::Right, event));
+        }
+
+        let mut left_status = self.left.get_motor_status(MotorId::Left);
+        let mut right_status = self.right.get_motor_status(MotorId::Right);
+        left_status.process_data.watchdog_state = self.watchdog.get_state();
+        right_status.process_data.watchdog_state = self.watchdog.get_state();
+        if let Some(sample) = self.left_telemetry.record(&left_status.process_data) {
+            let _ = channels.telemetry.try_send((MotorId::Left, sample));
+        }
+        if let Some(sample) = self.right_telemetry.record(&right_status.process_data) {
+            let _ = channels.telemetry.try_send((MotorId::Right, sample));
+        }
+        channels.left_motor_status.sender().send(left_status);
+        channels.right_motor_status.sender().send(right_status);
+
+        heartbeat_lost
+    }
+}
@@ -1,12 +1,10 @@
 use core::f32::consts::PI;
 
-use embassy_stm32::timer::qei::*;
-use embassy_stm32::timer::GeneralInstance4Channel;
-use embassy_stm32::timer::{Channel1Pin, Channel2Pin};
-use embassy_stm32::Peripheral;
+use crate::hal::PositionSensor;
 
-pub struct Encoder<'a, T: GeneralInstance4Channel, const COUNTS_PER_REV: u16> {
-    qei: Qei<'a, T>,
+pub struct Encoder<S: PositionSensor> {
+    sensor: S,
+    counts_per_rev: u16,
     act_vel: f32,
     act_pos: f32,
     curr_enc_count: i32,
@@ -15,16 +13,11 @@ pub struct Encoder<'a, T: GeneralInstance4Channel, const COUNTS_PER_REV: u16> {
     curr_qei_count: i16,
 }
 
-impl<'a, T: GeneralInstance4Channel, const COUNTS_PER_REV: u16> Encoder<'a, T, COUNTS_PER_REV> {
-    pub fn new(
-        tim: impl Peripheral<P = T> + 'a,
-        enc_a_pin: impl Peripheral<P = impl Channel1Pin<T>> + 'a,
-        enc_b_pin: impl Peripheral<P = impl Channel2Pin<T>> + 'a,
-    ) -> Self {
-        let enc_a_pin = QeiPin::new_ch1(enc_a_pin);
-        let enc_b_pin = QeiPin::new_ch2(enc_b_pin);
+impl<S: PositionSensor> Encoder<S> {
+    pub fn new(sensor: S, counts_per_rev: u16) -> Self {
         Self {
-            qei: Qei::new(tim, enc_a_pin, enc_b_pin),
+            sensor,
+            counts_per_rev,
             act_vel: 0.0,
             act_pos: 0.0,
             curr_enc_count: 0,
@@ -50,7 +43,7 @@ impl<'a, T: GeneralInstance4Channel, const COUNTS_PER_REV: u16> Encoder<'a, T, C
         self.update_encoder_count();
 
         let diff_count: f32 = (self.curr_enc_count - self.prev_enc_count) as f32;
-        let round_s = diff_count / period_s / (COUNTS_PER_REV as f32);
+        let round_s = diff_count / period_s / (self.counts_per_rev as f32);
         self.act_vel = 60.0 * round_s;
         self.act_pos += 2.0 * PI * round_s * period_s;
 
@@ -58,7 +51,7 @@ impl<'a, T: GeneralInstance4Channel, const COUNTS_PER_REV: u16> Encoder<'a, T, C
     }
 
     fn update_encoder_count(&mut self) {
-        self.curr_qei_count = self.qei.count() as i16;
+        self.curr_qei_count = self.sensor.raw_count() as i16;
         self.curr_enc_count += self.curr_qei_count.wrapping_sub(self.prev_qei_count) as i32;
         self.prev_qei_count = self.curr_qei_count;
     }
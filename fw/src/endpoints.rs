@@ -0,0 +1,455 @@
+//! Handlers of the postcard-rpc endpoints and the publish tasks, the binary defines the
+//! dispatcher with its wire implementation and calls them. The handlers only talk to the
+//! control loop through `DeviceChannels`.
+
+use core::sync::atomic::Ordering;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::Publisher;
+use embassy_sync::watch::Receiver;
+use postcard_rpc::header::VarHeader;
+use postcard_rpc::server::{AsWireTxErrorKind, Sender, WireTx, WireTxErrorKind};
+use protocol::*;
+
+use crate::device::{command_limits, DeviceChannels, CHANNEL_SIZE, MOTION_CMD_QUEUE_SIZE};
+use crate::hal::FlashPage;
+use crate::motion::{validate_motor_command, MotorStatus, STANDSTILL_VEL_RPM};
+use crate::param_store::ParamStore;
+use crate::params::{DeviceParams, ParamRegistry};
+use crate::pid::validate_pid_gains;
+use crate::telemetry::{validate_telemetry_config, TelemetryBatcher, DEFAULT_TELEMETRY_CONFIG};
+
+/// The parameter registry and the flash page that stores it, the simulated device shares them
+/// between the sessions, so the handlers only borrow them for one request
+pub trait ParamAccess {
+    type Page: FlashPage;
+
+    fn with<R>(
+        &mut self,
+        f: impl FnOnce(&mut ParamRegistry, &mut ParamStore<Self::Page>) -> R,
+    ) -> R;
+
+    fn values(&mut self) -> DeviceParams {
+        self.with(|registry, _| *registry.values())
+    }
+}
+
+/// Parameters that are owned by the endpoint context
+pub struct ParamState<F: FlashPage> {
+    pub registry: ParamRegistry,
+    pub store: ParamStore<F>,
+}
+
+impl<F: FlashPage> ParamAccess for ParamState<F> {
+    type Page = F;
+
+    fn with<R>(&mut self, f: impl FnOnce(&mut ParamRegistry, &mut ParamStore<F>) -> R) -> R {
+        f(&mut self.registry, &mut self.store)
+    }
+}
+
+// postcard-rpc
+pub struct EndpointContext<P: ParamAccess> {
+    pub left_motor_cmd_pub:
+        Publisher<'static, CriticalSectionRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>,
+    pub right_motor_cmd_pub:
+        Publisher<'static, CriticalSectionRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>,
+    pub left_motor_status: Receiver<'static, CriticalSectionRawMutex, MotorStatus, 2>,
+    pub right_motor_status: Receiver<'static, CriticalSectionRawMutex, MotorStatus, 2>,
+    pub channels: &'static DeviceChannels,
+    pub params: P,
+    // The control period in the info is the one that the device is started with, the period
+    // parameter is only applied on reboot
+    pub info: DeviceInfo,
+}
+
+impl<P: ParamAccess> EndpointContext<P> {
+    /// Returns `None` when the publishers or the status receivers are taken by another context
+    pub fn new(channels: &'static DeviceChannels, params: P, info: DeviceInfo) -> Option<Self> {
+        Some(Self {
+            left_motor_cmd_pub: channels.left_motor_cmd.publisher().ok()?,
+            right_motor_cmd_pub: channels.right_motor_cmd.publisher().ok()?,
+            left_motor_status: channels.left_motor_status.receiver()?,
+            right_motor_status: channels.right_motor_status.receiver()?,
+            channels,
+            params,
+            info,
+        })
+    }
+
+    fn motor(
+        &mut self,
+        id: MotorId,
+    ) -> (
+        &mut Receiver<'static, CriticalSectionRawMutex, MotorStatus, 2>,
+        &Publisher<'static, CriticalSectionRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>,
+    ) {
+        match id {
+            MotorId::Left => (&mut self.left_motor_status, &self.left_motor_cmd_pub),
+            MotorId::Right => (&mut self.right_motor_status, &self.right_motor_cmd_pub),
+        }
+    }
+
+    // The flash is only written when no command is running and both wheels stand still
+    fn motors_idle(&mut self) -> bool {
+        [&mut self.left_motor_status, &mut self.right_motor_status]
+            .into_iter()
+            .all(|x| {
+                x.try_get().is_some_and(|status| {
+                    status.motion_status.active_cmd.is_none()
+                        && status.process_data.actual_vel.abs() < STANDSTILL_VEL_RPM
+                })
+            })
+    }
+
+    // Runs a write of the parameter page, the control loop is told that the cycles are late
+    fn write_flash<R>(
+        &mut self,
+        f: impl FnOnce(&mut ParamRegistry, &mut ParamStore<P::Page>) -> R,
+    ) -> R {
+        let flash_busy = &self.channels.param_flash_busy;
+        flash_busy.store(true, Ordering::Relaxed);
+        let result = self.params.with(f);
+        flash_busy.store(false, Ordering::Relaxed);
+
+        result
+    }
+
+    fn signal_params(&mut self) {
+        let values = self.params.values();
+        self.channels.params.signal(values);
+    }
+}
+
+/// Sends a `Halt` to both motors, it is used when the connection to the host is lost
+pub fn halt_motors(channels: &DeviceChannels) {
+    let halt = SequencedCommand {
+        seq_id: INTERNAL_SEQ_ID,
+        command: MotorCommand::Halt,
+    };
+    let _ = channels
+        .left_motor_cmd
+        .immediate_publisher()
+        .try_publish(halt);
+    let _ = channels
+        .right_motor_cmd
+        .immediate_publisher()
+        .try_publish(halt);
+}
+
+/// Sends the recorded samples in batches. When the connection is lost, both motors are halted
+/// and `on_connection_lost` is called, Ex: to log it
+pub async fn motor_data_publish_loop<Tx: WireTx>(
+    channels: &'static DeviceChannels,
+    app_sender: Sender<Tx>,
+    tick_hz: u32,
+    mut on_connection_lost: impl FnMut(),
+) {
+    let mut topic_seq = 0_u8;
+    let mut connected = false;
+    // The config can be changed by a previous session of the simulated device
+    let mut telemetry_config = channels.telemetry_config.receiver().unwrap();
+    let config = telemetry_config
+        .try_get()
+        .unwrap_or(DEFAULT_TELEMETRY_CONFIG);
+    let mut left_batcher = TelemetryBatcher::new(MotorId::Left, &config, tick_hz);
+    let mut right_batcher = TelemetryBatcher::new(MotorId::Right, &config, tick_hz);
+
+    loop {
+        // The task sleeps until the control loop records a sample
+        let (id, sample) = channels.telemetry.receive().await;
+
+        if let Some(config) = telemetry_config.try_changed() {
+            left_batcher.set_config(&config);
+            right_batcher.set_config(&config);
+        }
+
+        let batch = match id {
+            MotorId::Left => left_batcher.push(sample),
+            MotorId::Right => right_batcher.push(sample),
+        };
+        let Some(batch) = batch else {
+            continue;
+        };
+
+        // Here, I use the error to check if connection is broken. If the board
+        // is previously connected, and `Timeout` error is triggered when
+        // publishing the data, then the connection is treated as broken. In
+        // this case, I will send a `Halt` command to motion struct to stop
+        // motor.
+        match app_sender
+            .publish::<MotorDataBatchTopic>(topic_seq.into(), &batch)
+            .await
+            .map_err(|e| e.as_kind())
+        {
+            Err(WireTxErrorKind::Timeout) if connected => {
+                connected = false;
+                halt_motors(channels);
+                on_connection_lost();
+            }
+            Err(_) => (),
+            Ok(()) => connected = true,
+        }
+
+        topic_seq = topic_seq.wrapping_add(1);
+    }
+}
+
+pub async fn auto_tune_publish_loop<Tx: WireTx>(
+    channels: &'static DeviceChannels,
+    app_sender: Sender<Tx>,
+) {
+    let mut topic_seq = 0_u8;
+
+    loop {
+        let status = channels.auto_tune_status.receive().await;
+        let _ = app_sender
+            .publish::<AutoTuneTopic>(topic_seq.into(), &status)
+            .await;
+        topic_seq = topic_seq.wrapping_add(1);
+    }
+}
+
+pub async fn motion_event_publish_loop<Tx: WireTx>(
+    channels: &'static DeviceChannels,
+    app_sender: Sender<Tx>,
+) {
+    let mut topic_seq = 0_u8;
+
+    loop {
+        let event = channels.motion_event.receive().await;
+        let _ = app_sender
+            .publish::<MotionEventTopic>(topic_seq.into(), &event)
+            .await;
+        topic_seq = topic_seq.wrapping_add(1);
+    }
+}
+
+pub async fn set_motor_cmd_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: (MotorId, SequencedCommand),
+) -> CommandSetResult {
+    // Indicates the internal buffer in motion controller is full or not, need to check
+    // this flag before sending commands to motion controller task
+    //
+    // There are 2 queues in target board:
+    // 1. PubSubChannel, publish command to motion controller task
+    // 2. Deque, queue used as a backup in motion controller
+    //
+    // If the Deque in motion controller is full then, this flag will be set to true then
+    // the handler will return error.
+    // (This could happen if a batch of position commands are pushed to the Deque, and
+    // motion controller is still processing it).
+    //
+    // If the Deque in motion controller is not full but commands are published too fast
+    // and all the spaces in PubSubChannel is consumed, then the handler will return error.
+
+    let limits = command_limits(&context.params.values());
+    let (queue_status, channel_pub) = context.motor(rqst.0);
+
+    // The command is checked against the latest status, Ex: the motion controller drops the
+    // commands in fault mode, reject them here so the host knows the motor needs `ClearFault`
+    let status = queue_status.get().await;
+    validate_motor_command(rqst.0, &rqst.1.command, &status, &limits)?;
+
+    // The `Halt` command has the highest priority, so it can be sent when the queue in motion
+    // struct is full.
+    let can_push = match rqst.1.command {
+        MotorCommand::Halt | MotorCommand::ClearFault | MotorCommand::VelocityCommand(_) => true,
+        MotorCommand::PositionCommand(_) | MotorCommand::AutoTune(_) => {
+            !queue_status.changed().await.is_queue_full
+        }
+    };
+
+    if can_push {
+        channel_pub
+            .try_publish(rqst.1)
+            .map_err(|_e| CommandError::BufferFull(rqst.0))
+    } else {
+        Err(CommandError::BufferFull(rqst.0))
+    }
+}
+
+pub async fn set_position_batch_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: (MotorId, PositionBatch),
+) -> BatchSetResult {
+    // The batch is enqueued all or nothing, the free space in the command queue and channel is
+    // checked before the commands are published. The handler is the only publisher besides the
+    // `Halt` on connection lost, which aborts the queued commands anyway, so the checked space
+    // can't be taken by other commands.
+    let (id, batch) = rqst;
+    let limits = command_limits(&context.params.values());
+    let (motor_status, channel_pub) = context.motor(id);
+
+    let len = batch.commands.len();
+    if len > MOTION_CMD_QUEUE_SIZE {
+        return Err(CommandError::BatchTooLarge {
+            id,
+            len: len as u16,
+            capacity: MOTION_CMD_QUEUE_SIZE as u16,
+        });
+    }
+
+    // Wait for the status of next cycle, so the commands that are published before are counted
+    let _ = motor_status.try_changed();
+    let mut status = motor_status.changed().await;
+    if (status.motion_status.credits() as usize) < len {
+        return Err(CommandError::BufferFull(id));
+    }
+
+    // Each command is checked from the end position of the previous one
+    for cmd in batch.commands.iter() {
+        validate_motor_command(id, &MotorCommand::PositionCommand(*cmd), &status, &limits)?;
+        status.planned_pos += cmd.displacement;
+    }
+
+    for (i, cmd) in batch.commands.iter().enumerate() {
+        let seq_cmd = SequencedCommand {
+            seq_id: batch.first_seq_id.wrapping_add(i as u32),
+            command: MotorCommand::PositionCommand(*cmd),
+        };
+        channel_pub
+            .try_publish(seq_cmd)
+            .map_err(|_e| CommandError::BufferFull(id))?;
+    }
+
+    Ok(len as u16)
+}
+
+pub async fn get_pid_gains_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: MotorId,
+) -> PidGains {
+    let (motor_status, _) = context.motor(rqst);
+
+    motor_status.get().await.pid_gains
+}
+
+pub async fn get_motion_status_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: MotorId,
+) -> MotionStatus {
+    let (motor_status, _) = context.motor(rqst);
+
+    // The status could be taken before the last command is published, skip it and wait for
+    // the next control loop cycle, so the pending commands in the channel are counted
+    let _ = motor_status.try_changed();
+    motor_status.changed().await.motion_status
+}
+
+pub fn get_device_info_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    _rqst: (),
+) -> DeviceInfo {
+    context.info.clone()
+}
+
+pub fn set_pid_gains_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: (MotorId, PidGains),
+) -> PidGainsSetResult {
+    let (id, gains) = rqst;
+    validate_pid_gains(id, &gains)?;
+
+    context
+        .params
+        .with(|registry, _| registry.set_pid_gains(id, &gains));
+    context.signal_params();
+
+    Ok(())
+}
+
+pub fn list_params_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: u16,
+) -> Option<ParamInfo> {
+    context.params.with(|registry, _| registry.info(rqst))
+}
+
+pub fn get_param_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: ParamId,
+) -> ParamGetResult {
+    context.params.with(|registry, _| registry.get(rqst))
+}
+
+pub fn set_param_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: (ParamId, ParamValue),
+) -> ParamSetResult {
+    let (id, value) = rqst;
+    context.params.with(|registry, _| registry.set(id, value))?;
+    context.signal_params();
+
+    Ok(())
+}
+
+pub fn save_params_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    _rqst: (),
+) -> ParamStoreResult {
+    if !context.motors_idle() {
+        return Err(ParamStoreError::MotorsRunning);
+    }
+
+    context.write_flash(|registry, store| store.save(registry))
+}
+
+pub fn load_params_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    _rqst: (),
+) -> ParamStoreResult {
+    context
+        .params
+        .with(|registry, store| store.load(registry))?;
+    context.signal_params();
+
+    Ok(())
+}
+
+pub fn factory_reset_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    _rqst: (),
+) -> ParamStoreResult {
+    if !context.motors_idle() {
+        return Err(ParamStoreError::MotorsRunning);
+    }
+
+    context.write_flash(|registry, store| store.factory_reset(registry))?;
+    context.signal_params();
+
+    Ok(())
+}
+
+pub fn set_telemetry_config_handler<P: ParamAccess>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    rqst: TelemetryConfig,
+) -> TelemetrySetResult {
+    validate_telemetry_config(&rqst)?;
+    context.channels.telemetry_config.sender().send(rqst);
+
+    Ok(())
+}
+
+pub fn heartbeat_handler<P: ParamAccess, Tx: WireTx>(
+    context: &mut EndpointContext<P>,
+    _header: VarHeader,
+    msg: Heartbeat,
+    _out: &Sender<Tx>,
+) {
+    context.channels.heartbeat.signal(msg);
+}
@@ -0,0 +1,293 @@
+use protocol::FaultCode;
+
+#[derive(Clone, Copy, Debug)]
+pub struct FaultLimits {
+    // Control effort (0.0 ~ 1.0) above which the motor is expected to move
+    pub stall_effort: f32,
+    // Time that the encoder doesn't move with high effort before stall is reported, unit: s
+    pub stall_time_s: f32,
+    // Maximum position error between interpolated and actual position, unit: rad
+    pub following_error_rad: f32,
+    // Maximum actual velocity, unit: rpm
+    pub overspeed_rpm: f32,
+    // Maximum time between two control loop cycles, unit: us
+    pub max_cycle_time_us: u64,
+}
+
+// Values sampled in one control loop cycle
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FaultCheckInput {
+    pub now_us: u64,
+    pub period_s: f32,
+    pub control_effort: f32,
+    pub enc_count: i32,
+    pub act_vel_rpm: f32,
+    // `None` if position is not controlled (Ex: velocity mode)
+    pub following_error_rad: Option<f32>,
+}
+
+/// Checks the motor every control loop cycle. The first detected fault is latched until
+/// `clear` is called, so the host can see the root cause instead of the faults that follow it.
+pub struct FaultSupervisor {
+    limits: FaultLimits,
+    fault: FaultCode,
+    stall_elapsed_s: f32,
+    prev_enc_count: Option<i32>,
+    prev_cycle_us: Option<u64>,
+}
+
+impl FaultSupervisor {
+    pub fn new(limits: FaultLimits) -> Self {
+        Self {
+            limits,
+            fault: FaultCode::None,
+            stall_elapsed_s: 0.0,
+            prev_enc_count: None,
+            prev_cycle_us: None,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: FaultLimits) {
+        self.limits = limits;
+    }
+
+    pub fn get_limits(&self) -> FaultLimits {
+        self.limits
+    }
+
+    pub fn get_fault(&self) -> FaultCode {
+        self.fault
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.fault != FaultCode::None
+    }
+
+    /// Clears the latched fault and the detection history
+    pub fn clear(&mut self) {
+        self.fault = FaultCode::None;
+        self.stall_elapsed_s = 0.0;
+        self.prev_enc_count = None;
+        self.prev_cycle_us = None;
+    }
+
+    /// Forgets the time of last cycle, so the next cycle is not checked for overrun (Ex: the
+    /// cpu is stalled by a flash erase on purpose)
+    pub fn restart_cycle_check(&mut self) {
+        self.prev_cycle_us = None;
+    }
+
+    /// Returns the latched fault, `FaultCode::None` if the motor is healthy
+    pub fn check(&mut self, input: &FaultCheckInput) -> FaultCode {
+        // The history is updated even when a fault is latched, so the detection restarts from
+        // the current state after the fault is cleared
+        let overrun = self.check_overrun(input.now_us);
+        let stall = self.check_stall(input);
+
+        if self.is_faulted() {
+            return self.fault;
+        }
+
+        self.fault = if overrun {
+            FaultCode::ControlLoopOverrun
+        } else if input.act_vel_rpm.abs() > self.limits.overspeed_rpm {
+            FaultCode::Overspeed
+        } else if input
+            .following_error_rad
+            .is_some_and(|x| x.abs() > self.limits.following_error_rad)
+        {
+            FaultCode::FollowingError
+        } else if stall {
+            FaultCode::Stall
+        } else {
+            FaultCode::None
+        };
+
+        self.fault
+    }
+
+    fn check_overrun(&mut self, now_us: u64) -> bool {
+        let overrun = self
+            .prev_cycle_us
+            .is_some_and(|x| now_us.wrapping_sub(x) > self.limits.max_cycle_time_us);
+        self.prev_cycle_us = Some(now_us);
+        overrun
+    }
+
+    fn check_stall(&mut self, input: &FaultCheckInput) -> bool {
+        let moved = self.prev_enc_count.is_none_or(|x| x != input.enc_count);
+        self.prev_enc_count = Some(input.enc_count);
+
+        // A stalled wheel or a disconnected encoder both look like high effort without any
+        // encoder count
+        if !moved && input.control_effort.abs() >= self.limits.stall_effort {
+            self.stall_elapsed_s += input.period_s;
+        } else {
+            self.stall_elapsed_s = 0.0;
+        }
+
+        self.stall_elapsed_s >= self.limits.stall_time_s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_S: f32 = 0.005;
+    const PERIOD_US: u64 = 5_000;
+
+    fn supervisor() -> FaultSupervisor {
+        FaultSupervisor::new(FaultLimits {
+            stall_effort: 0.5,
+            stall_time_s: 0.1,
+            following_error_rad: 1.0,
+            overspeed_rpm: 4400.0,
+            max_cycle_time_us: 2 * PERIOD_US,
+        })
+    }
+
+    // Input of cycle `i` of a healthy motor that moves one count per cycle
+    fn input(i: u64) -> FaultCheckInput {
+        FaultCheckInput {
+            now_us: i * PERIOD_US,
+            period_s: PERIOD_S,
+            control_effort: 0.8,
+            enc_count: i as i32,
+            act_vel_rpm: 30.0,
+            following_error_rad: Some(0.1),
+        }
+    }
+
+    // Runs `cycles` cycles from cycle `start` and returns the fault of the last one
+    fn run(
+        supervisor: &mut FaultSupervisor,
+        start: u64,
+        cycles: u64,
+        f: impl Fn(&mut FaultCheckInput),
+    ) -> FaultCode {
+        let mut fault = FaultCode::None;
+        for i in start..start + cycles {
+            let mut input = input(i);
+            f(&mut input);
+            fault = supervisor.check(&input);
+        }
+        fault
+    }
+
+    #[test]
+    fn healthy_motor_has_no_fault() {
+        let mut supervisor = supervisor();
+        assert_eq!(run(&mut supervisor, 0, 100, |_| ()), FaultCode::None);
+        assert!(!supervisor.is_faulted());
+    }
+
+    #[test]
+    fn stall_is_reported_after_stall_time() {
+        let mut supervisor = supervisor();
+        // 20 cycles of 5 ms without encoder count, the first one still sees the count change
+        let stalled = |x: &mut FaultCheckInput| x.enc_count = 0;
+        assert_eq!(run(&mut supervisor, 0, 20, stalled), FaultCode::None);
+        assert_eq!(run(&mut supervisor, 20, 1, stalled), FaultCode::Stall);
+    }
+
+    #[test]
+    fn stall_time_restarts_when_motor_moves_or_effort_is_low() {
+        let mut supervisor = supervisor();
+        let stalled = |x: &mut FaultCheckInput| x.enc_count = 0;
+        run(&mut supervisor, 0, 15, stalled);
+        run(&mut supervisor, 15, 1, |_| ());
+        assert_eq!(run(&mut supervisor, 16, 15, stalled), FaultCode::None);
+
+        let low_effort = |x: &mut FaultCheckInput| {
+            x.enc_count = 0;
+            x.control_effort = -0.4;
+        };
+        assert_eq!(run(&mut supervisor, 31, 100, low_effort), FaultCode::None);
+    }
+
+    #[test]
+    fn following_error_is_reported_in_both_directions() {
+        let mut supervisor = supervisor();
+        let behind = |x: &mut FaultCheckInput| x.following_error_rad = Some(-1.5);
+        assert_eq!(
+            run(&mut supervisor, 0, 1, behind),
+            FaultCode::FollowingError
+        );
+
+        let mut supervisor = self::supervisor();
+        let ahead = |x: &mut FaultCheckInput| x.following_error_rad = Some(1.5);
+        assert_eq!(run(&mut supervisor, 0, 1, ahead), FaultCode::FollowingError);
+    }
+
+    #[test]
+    fn following_error_is_not_checked_without_position_control() {
+        let mut supervisor = supervisor();
+        let velocity_mode = |x: &mut FaultCheckInput| x.following_error_rad = None;
+        assert_eq!(run(&mut supervisor, 0, 10, velocity_mode), FaultCode::None);
+    }
+
+    #[test]
+    fn overspeed_is_reported_in_both_directions() {
+        let mut supervisor = supervisor();
+        let forward = |x: &mut FaultCheckInput| x.act_vel_rpm = 4500.0;
+        assert_eq!(run(&mut supervisor, 0, 1, forward), FaultCode::Overspeed);
+
+        let mut supervisor = self::supervisor();
+        let reverse = |x: &mut FaultCheckInput| x.act_vel_rpm = -4500.0;
+        assert_eq!(run(&mut supervisor, 0, 1, reverse), FaultCode::Overspeed);
+    }
+
+    #[test]
+    fn late_cycle_is_reported_as_overrun() {
+        let mut supervisor = supervisor();
+        run(&mut supervisor, 0, 10, |_| ());
+        assert_eq!(
+            run(&mut supervisor, 13, 1, |_| ()),
+            FaultCode::ControlLoopOverrun
+        );
+    }
+
+    #[test]
+    fn restarted_cycle_check_skips_one_cycle() {
+        let mut supervisor = supervisor();
+        run(&mut supervisor, 0, 10, |_| ());
+        supervisor.restart_cycle_check();
+        assert_eq!(run(&mut supervisor, 100, 10, |_| ()), FaultCode::None);
+    }
+
+    #[test]
+    fn first_fault_is_latched() {
+        let mut supervisor = supervisor();
+        let behind = |x: &mut FaultCheckInput| x.following_error_rad = Some(2.0);
+        assert_eq!(
+            run(&mut supervisor, 0, 1, behind),
+            FaultCode::FollowingError
+        );
+
+        // The fault stays after the error is gone, and the faults that follow don't replace it
+        assert_eq!(
+            run(&mut supervisor, 1, 10, |_| ()),
+            FaultCode::FollowingError
+        );
+        let overspeed = |x: &mut FaultCheckInput| x.act_vel_rpm = 5000.0;
+        assert_eq!(
+            run(&mut supervisor, 11, 1, overspeed),
+            FaultCode::FollowingError
+        );
+        assert_eq!(supervisor.get_fault(), FaultCode::FollowingError);
+    }
+
+    #[test]
+    fn clear_restarts_detection() {
+        let mut supervisor = supervisor();
+        let stalled = |x: &mut FaultCheckInput| x.enc_count = 0;
+        assert_eq!(run(&mut supervisor, 0, 30, stalled), FaultCode::Stall);
+
+        supervisor.clear();
+        assert!(!supervisor.is_faulted());
+        // The stall time starts from 0 and the time of the last cycle is forgotten
+        assert_eq!(run(&mut supervisor, 1000, 19, stalled), FaultCode::None);
+        assert_eq!(run(&mut supervisor, 1019, 2, stalled), FaultCode::Stall);
+    }
+}
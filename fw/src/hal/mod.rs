@@ -0,0 +1,52 @@
+// Hardware abstraction used by the motion stack. The control logic in `encoder`, `motor` and
+// `motion` only depends on these traits, so it can run on target with the embassy backend or
+// on PC with a simulated motor.
+
+#[cfg(feature = "stm32")]
+pub mod stm32;
+
+/// Sensor that reports the raw count of a quadrature encoder. The count is a free running 16
+/// bits counter, and it is allowed to wrap around.
+pub trait PositionSensor {
+    fn raw_count(&mut self) -> u16;
+}
+
+/// Power stage of the motor: PWM output, direction pin and brake pin.
+pub trait MotorDriver {
+    /// 0%: no torque, 100%: full torque. The polarity of the PWM output is handled by the
+    /// driver implementation.
+    fn set_duty_cycle_percent(&mut self, duty_cycle_percent: u8);
+    /// Low: forward, high: backward
+    fn set_dir_pin(&mut self, high: bool);
+    /// Brake is active low
+    fn set_break_pin(&mut self, high: bool);
+}
+
+/// Time source of the control loop
+pub trait Clock {
+    /// Rate of `now_ticks`
+    const TICK_HZ: u32;
+
+    fn now_us(&self) -> u64;
+    /// Raw device time, it is reported to the host with the process data
+    fn now_ticks(&self) -> u64;
+    fn delay_us(&mut self, us: u32);
+}
+
+/// Flash page that is reserved for non-volatile data (Ex: parameters). The offsets are relative
+/// to the start of the page
+pub trait FlashPage {
+    /// Size of the page in bytes
+    const SIZE: usize;
+    /// The offset and the length of a write are multiples of it
+    const WRITE_SIZE: usize;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError>;
+    /// Erases the whole page, the erased bytes read as 0xff
+    fn erase(&mut self) -> Result<(), FlashError>;
+    /// Programs the bytes, the area has to be erased first
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlashError;
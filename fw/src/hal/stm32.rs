@@ -0,0 +1,141 @@
+use embassy_stm32::flash::{self, Blocking, Flash};
+use embassy_stm32::gpio::Output;
+use embassy_stm32::peripherals::FLASH;
+use embassy_stm32::timer::low_level::OutputPolarity;
+use embassy_stm32::timer::qei::*;
+use embassy_stm32::timer::simple_pwm::SimplePwmChannel;
+use embassy_stm32::timer::GeneralInstance4Channel;
+use embassy_stm32::timer::{Channel1Pin, Channel2Pin};
+use embassy_stm32::Peripheral;
+use embassy_time::{block_for, Duration, Instant};
+
+use super::{Clock, FlashError, FlashPage, MotorDriver, PositionSensor};
+
+pub struct QeiSensor<'a, T: GeneralInstance4Channel> {
+    qei: Qei<'a, T>,
+}
+
+impl<'a, T: GeneralInstance4Channel> QeiSensor<'a, T> {
+    pub fn new(
+        tim: impl Peripheral<P = T> + 'a,
+        enc_a_pin: impl Peripheral<P = impl Channel1Pin<T>> + 'a,
+        enc_b_pin: impl Peripheral<P = impl Channel2Pin<T>> + 'a,
+    ) -> Self {
+        let enc_a_pin = QeiPin::new_ch1(enc_a_pin);
+        let enc_b_pin = QeiPin::new_ch2(enc_b_pin);
+        Self {
+            qei: Qei::new(tim, enc_a_pin, enc_b_pin),
+        }
+    }
+}
+
+impl<T: GeneralInstance4Channel> PositionSensor for QeiSensor<'_, T> {
+    fn raw_count(&mut self) -> u16 {
+        self.qei.count()
+    }
+}
+
+pub struct PwmMotorDriver<'a, T: GeneralInstance4Channel> {
+    pwm_channel: SimplePwmChannel<'a, T>,
+    dir_pin: Output<'a>,
+    break_pin: Output<'a>,
+}
+
+impl<'a, T: GeneralInstance4Channel> PwmMotorDriver<'a, T> {
+    pub fn new(
+        mut pwm_channel: SimplePwmChannel<'a, T>,
+        dir_pin: Output<'a>,
+        break_pin: Output<'a>,
+    ) -> Self {
+        // 24H motor, 0% duty: full speed, 100% duty: 0 speed
+        pwm_channel.set_polarity(OutputPolarity::ActiveLow);
+        pwm_channel.enable();
+
+        Self {
+            pwm_channel,
+            dir_pin,
+            break_pin,
+        }
+    }
+}
+
+impl<T: GeneralInstance4Channel> MotorDriver for PwmMotorDriver<'_, T> {
+    fn set_duty_cycle_percent(&mut self, duty_cycle_percent: u8) {
+        self.pwm_channel.set_duty_cycle_percent(duty_cycle_percent);
+    }
+
+    fn set_dir_pin(&mut self, high: bool) {
+        if high {
+            self.dir_pin.set_high();
+        } else {
+            self.dir_pin.set_low();
+        }
+    }
+
+    fn set_break_pin(&mut self, high: bool) {
+        if high {
+            self.break_pin.set_high();
+        } else {
+            self.break_pin.set_low();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct EmbassyClock;
+
+impl Clock for EmbassyClock {
+    const TICK_HZ: u32 = embassy_time::TICK_HZ as u32;
+
+    fn now_us(&self) -> u64 {
+        Instant::now().as_micros()
+    }
+
+    fn now_ticks(&self) -> u64 {
+        Instant::now().as_ticks()
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        block_for(Duration::from_micros(us as u64));
+    }
+}
+
+// The last page of the internal flash, `param_page.x` fails the link if the program grows into
+// the page
+const PARAM_PAGE_OFFSET: u32 = (flash::FLASH_SIZE - flash::MAX_ERASE_SIZE) as u32;
+
+/// Last page of the internal flash, the cpu is stalled while the page is erased or written
+pub struct InternalFlashPage<'a> {
+    flash: Flash<'a, Blocking>,
+}
+
+impl<'a> InternalFlashPage<'a> {
+    pub fn new(flash: impl Peripheral<P = FLASH> + 'a) -> Self {
+        Self {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+}
+
+impl FlashPage for InternalFlashPage<'_> {
+    const SIZE: usize = flash::MAX_ERASE_SIZE;
+    const WRITE_SIZE: usize = flash::WRITE_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        self.flash
+            .blocking_read(PARAM_PAGE_OFFSET + offset, bytes)
+            .map_err(|_e| FlashError)
+    }
+
+    fn erase(&mut self) -> Result<(), FlashError> {
+        self.flash
+            .blocking_erase(PARAM_PAGE_OFFSET, PARAM_PAGE_OFFSET + Self::SIZE as u32)
+            .map_err(|_e| FlashError)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.flash
+            .blocking_write(PARAM_PAGE_OFFSET + offset, bytes)
+            .map_err(|_e| FlashError)
+    }
+}
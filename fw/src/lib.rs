@@ -1,16 +1,26 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+pub mod autotune;
+pub mod device;
 pub mod encoder;
+pub mod endpoints;
+pub mod fault;
+pub mod hal;
 pub mod motion;
 pub mod motor;
+pub mod param_store;
+pub mod params;
 pub mod pid;
+pub mod position_control;
+pub mod telemetry;
+pub mod watchdog;
 
 use core::f32;
 
-pub fn rpm_to_rad_s(val: f32) -> f32 {
+pub const fn rpm_to_rad_s(val: f32) -> f32 {
     val * 2.0 * f32::consts::PI / 60.0
 }
 
-pub fn rad_s_to_rpm(val: f32) -> f32 {
+pub const fn rad_s_to_rpm(val: f32) -> f32 {
     val * 60.0 / (2.0 * f32::consts::PI)
 }
@@ -1,4 +1,3 @@
-use embassy_stm32::timer::GeneralInstance4Channel;
 use embassy_sync::{
     blocking_mutex::raw::RawMutex,
     pubsub::{Subscriber, WaitResult},
@@ -7,11 +6,34 @@ use embassy_sync::{
 #[cfg(feature = "debug-motion")]
 use defmt::{debug, Debug2Format};
 
-use heapless::Deque;
-use protocol::{ControlMode, MotorCommand, MotorProcessData, PositionCommand};
+use heapless::{Deque, Vec};
+use protocol::{
+    AutoTuneStatus, CommandError, CommandField, CommandSetResult, ControlMode, FaultCode,
+    HaltState, InterpolationState, MotionEvent, MotionEventKind, MotionStatus, MotorCommand,
+    MotorId, MotorProcessData, PidGains, PositionCommand, SequencedCommand, WatchdogState,
+    INTERNAL_SEQ_ID,
+};
+
+use crate::{
+    autotune::RelayAutoTuner,
+    fault::{FaultCheckInput, FaultSupervisor},
+    hal::{Clock, MotorDriver, PositionSensor},
+    motor::*,
+    params::DeviceParams,
+    position_control::PositionController,
+    rad_s_to_rpm, rpm_to_rad_s,
+};
+use s_curve::{planner::*, *};
+
+// Velocity error below which the velocity command is completed, unit: rpm
+pub const DEFAULT_READY_VEL_ERROR_RPM: f32 = 60.0;
 
-use crate::{motor::*, rad_s_to_rpm, rpm_to_rad_s};
-use s_curve::*;
+// Measured velocity below which the motor stands still, unit: rpm. The encoder velocity is
+// quantized by one count per cycle, so any value below that count is a standstill
+pub const STANDSTILL_VEL_RPM: f32 = 1.0;
+
+// Interpolated velocity below which the interpolator stands still, unit: rad/s
+const STANDSTILL_INTP_VEL_RAD_S: f32 = 1e-3;
 
 #[derive(PartialEq)]
 enum HaltProcessState {
@@ -21,46 +43,246 @@ enum HaltProcessState {
     Finished,
 }
 
+// Snapshot of motion controller that is shared from control loop to the communication tasks
+#[derive(Clone, Copy)]
+pub struct MotorStatus {
+    pub id: MotorId,
+    pub is_queue_full: bool,
+    pub motion_status: MotionStatus,
+    pub process_data: MotorProcessData,
+    pub pid_gains: PidGains,
+    // Position where the motor stops after the active and queued position commands, unit: rad
+    pub planned_pos: f32,
+}
+
+#[derive(Clone, Copy)]
+pub struct CommandLimits {
+    pub vel_limit_rpm: f32,
+    // Limits of the stop of the interpolator, see `SCurveInterpolator::stop_limits`
+    pub stop_limits: PlanLimits,
+    // Position range of the axis, unit: rad. `None` if the axis rotates continuously (Ex: wheel)
+    pub travel_limits_rad: Option<(f32, f32)>,
+}
+
+/// Checks the command from host against the limits and the latest status of the motion
+/// controller, so a bad command is rejected before it is queued
+pub fn validate_motor_command(
+    id: MotorId,
+    cmd: &MotorCommand,
+    status: &MotorStatus,
+    limits: &CommandLimits,
+) -> CommandSetResult {
+    let in_range = |x: f32, max: f32| x.is_finite() && x.abs() <= max;
+    let invalid = |field| Err(CommandError::InvalidValue(id, field));
+    let mode = status.process_data.control_mode_display;
+
+    match *cmd {
+        // `Halt` is harmless, and `ClearFault` is the only way to leave fault mode
+        MotorCommand::Halt | MotorCommand::ClearFault => Ok(()),
+        _ if status.process_data.fault_code != FaultCode::None => Err(CommandError::Faulted(id)),
+        // The relay drives the motor directly during auto tuning, only `Halt` can stop it
+        _ if mode == ControlMode::AutoTune => Err(CommandError::UnsupportedInMode(id, mode)),
+        MotorCommand::VelocityCommand(x) => {
+            if !in_range(x, limits.vel_limit_rpm) {
+                return invalid(CommandField::Velocity);
+            }
+            Ok(())
+        }
+        MotorCommand::PositionCommand(x) => {
+            // A command without displacement is allowed, it switches the control mode only
+            if !x.displacement.is_finite() {
+                return invalid(CommandField::Displacement);
+            }
+            if !in_range(x.vel_max, limits.vel_limit_rpm)
+                || (x.displacement != 0.0 && x.vel_max == 0.0)
+            {
+                return invalid(CommandField::VelMax);
+            }
+            if !in_range(x.vel_end, x.vel_max.abs()) {
+                return invalid(CommandField::VelEnd);
+            }
+            // The limits of the move are clamped to the limits of the device, only a value that
+            // can't be a limit is rejected
+            let is_limit = |x: Option<f32>| x.is_none_or(|x| x.is_finite() && x > 0.0);
+            if !is_limit(x.acc_max) {
+                return invalid(CommandField::AccMax);
+            }
+            if !is_limit(x.dec_max) {
+                return invalid(CommandField::DecMax);
+            }
+            if !is_limit(x.jerk_max) {
+                return invalid(CommandField::JerkMax);
+            }
+            if !is_limit(x.jerk_out_max) {
+                return invalid(CommandField::JerkOutMax);
+            }
+            // A move that stops or is blended with the next one ends without acceleration
+            if let Some(acc_end) = x.acc_end {
+                if !acc_end.is_finite() || (acc_end != 0.0 && (x.vel_end == 0.0 || x.blend)) {
+                    return invalid(CommandField::AccEnd);
+                }
+            }
+            if let Some((min, max)) = limits.travel_limits_rad {
+                let end_pos = status.planned_pos + x.displacement;
+                if !(min..=max).contains(&end_pos)
+                    || !(min..=max).contains(&(end_pos + stop_distance(&x, limits)))
+                {
+                    return Err(CommandError::OutOfTravelLimits(id));
+                }
+            }
+            Ok(())
+        }
+        MotorCommand::AutoTune(x) => {
+            if !in_range(x.target_vel, limits.vel_limit_rpm) || x.target_vel == 0.0 {
+                return invalid(CommandField::AutoTuneTargetVel);
+            }
+            if !(x.relay_amplitude > 0.0 && x.relay_amplitude <= 1.0) {
+                return invalid(CommandField::AutoTuneRelayAmplitude);
+            }
+            if !(x.hysteresis.is_finite() && x.hysteresis >= 0.0) {
+                return invalid(CommandField::AutoTuneHysteresis);
+            }
+            if x.cycles == 0 {
+                return invalid(CommandField::AutoTuneCycles);
+            }
+            if !(x.timeout_s.is_finite() && x.timeout_s > 0.0) {
+                return invalid(CommandField::AutoTuneTimeout);
+            }
+
+            // The relay measures the oscillation around target velocity, it starts when the
+            // previous motion is finished
+            let motion_status = status.motion_status;
+            if motion_status.active_cmd.is_some() || motion_status.halt_state != HaltState::Idle {
+                return Err(CommandError::NotReady(id));
+            }
+            Ok(())
+        }
+    }
+}
+
+// Distance that the axis runs past the end of the command if no command follows, unit: rad. A
+// command that ends with velocity runs on with it, the distance is planned like the stop of the
+// interpolator. A blended command stops at its end if it is the last one
+fn stop_distance(cmd: &PositionCommand, limits: &CommandLimits) -> f32 {
+    if cmd.vel_end == 0.0 || cmd.blend {
+        return 0.0;
+    }
+
+    let dir = if cmd.displacement >= 0.0 { 1.0 } else { -1.0 };
+    let vel_end = dir * rpm_to_rad_s(cmd.vel_end);
+    SCurvePlan::stop(vel_end, move_options(cmd).acc_end, &limits.stop_limits).displacement()
+}
+
+// The segment takes over the acceleration of the running one, and it ends with the
+// acceleration of the command. The blended commands are planned with `vel_end` only, so they
+// end without acceleration
+fn move_options(cmd: &PositionCommand) -> MoveOptions {
+    let dir = if cmd.displacement >= 0.0 { 1.0 } else { -1.0 };
+    let acc_end = if cmd.blend {
+        0.0
+    } else {
+        dir * cmd.acc_end.unwrap_or(0.0)
+    };
+
+    // The command is in rpm/s and rpm/s^2, the interpolator and its limits in rad/s^2 and
+    // rad/s^3. The conversion of rpm is linear, so it converts the derivatives of velocity as
+    // well
+    MoveOptions {
+        acc_max: cmd.acc_max.map(rpm_to_rad_s),
+        dec_max: cmd.dec_max.map(rpm_to_rad_s),
+        jerk_max: cmd.jerk_max.map(rpm_to_rad_s),
+        jerk_out_max: cmd.jerk_out_max.map(rpm_to_rad_s),
+        acc_end: rpm_to_rad_s(acc_end),
+    }
+}
+
 pub struct Motion<
     'a,
     M: RawMutex,
-    T1: GeneralInstance4Channel,
-    T2: GeneralInstance4Channel,
+    S: PositionSensor,
+    D: MotorDriver,
+    C: Clock,
     const CHANNEL_SIZE: usize,
     const MOTION_QUEUE_SIZE: usize,
+    const EVENT_QUEUE_SIZE: usize,
 > {
-    pub motor: BldcMotor24H<'a, T1, T2>,
+    pub motor: BldcMotor24H<S, D, C>,
     pub s_curve_intper: SCurveInterpolator,
+    pub pos_controller: PositionController,
+    pub fault_supervisor: FaultSupervisor,
+    auto_tuner: RelayAutoTuner,
     halt_process_state: HaltProcessState,
-    cmd_sub: Subscriber<'a, M, MotorCommand, CHANNEL_SIZE, 1, 2>,
-    cmd_queue: Deque<MotorCommand, MOTION_QUEUE_SIZE>,
+    cmd_sub: Subscriber<'a, M, SequencedCommand, CHANNEL_SIZE, 1, 2>,
+    cmd_queue: Deque<SequencedCommand, MOTION_QUEUE_SIZE>,
+    // The command that is set and not completed yet
+    active_cmd: Option<SequencedCommand>,
+    // A halt aborts the whole command queue in one cycle, so the event queue should be larger
+    // than the command queue, the events are dropped if it is full
+    motion_events: Deque<MotionEvent, EVENT_QUEUE_SIZE>,
     control_mode: ControlMode,
+    // End position of the active position command, unit: rad
+    pos_target_end: f32,
+    // The position controller tracked the interpolated position in the last cycle, the
+    // following error is carried over to the next segment only if it did
+    pos_tracking: bool,
+    ready_vel_error_rpm: f32,
+    // Device time and number of the current control cycle, they are reported with the process
+    // data, so the host can tell the samples apart
+    cycle_ticks: u64,
+    sample_counter: u32,
 }
 
 impl<
         'a,
         M: RawMutex,
-        T1: GeneralInstance4Channel,
-        T2: GeneralInstance4Channel,
+        S: PositionSensor,
+        D: MotorDriver,
+        C: Clock,
         const CHANNEL_SIZE: usize,
         const MOTION_QUEUE_SIZE: usize,
-    > Motion<'a, M, T1, T2, CHANNEL_SIZE, MOTION_QUEUE_SIZE>
+        const EVENT_QUEUE_SIZE: usize,
+    > Motion<'a, M, S, D, C, CHANNEL_SIZE, MOTION_QUEUE_SIZE, EVENT_QUEUE_SIZE>
 {
     pub fn new(
         s_curve_intper: SCurveInterpolator,
-        motor: BldcMotor24H<'a, T1, T2>,
-        cmd_sub: Subscriber<'a, M, MotorCommand, CHANNEL_SIZE, 1, 2>,
+        motor: BldcMotor24H<S, D, C>,
+        pos_controller: PositionController,
+        fault_supervisor: FaultSupervisor,
+        cmd_sub: Subscriber<'a, M, SequencedCommand, CHANNEL_SIZE, 1, 2>,
     ) -> Self {
         Self {
             motor,
             s_curve_intper,
+            pos_controller,
+            fault_supervisor,
+            auto_tuner: RelayAutoTuner::new(),
             halt_process_state: HaltProcessState::Idle,
             cmd_sub,
             cmd_queue: Deque::new(),
+            active_cmd: None,
+            motion_events: Deque::new(),
             control_mode: ControlMode::Velocity,
+            pos_target_end: 0.0,
+            pos_tracking: false,
+            ready_vel_error_rpm: DEFAULT_READY_VEL_ERROR_RPM,
+            cycle_ticks: 0,
+            sample_counter: 0,
         }
     }
 
+    /// Applies the parameters that can be changed at runtime, the parameters that require
+    /// reboot are ignored
+    pub fn set_params(&mut self, params: &DeviceParams) {
+        let vel_limit_rad_s = rpm_to_rad_s(params.vel_limit_rpm);
+        self.s_curve_intper
+            .set_limits(vel_limit_rad_s, params.acc_limit, params.jerk_limit);
+        self.ready_vel_error_rpm = params.ready_vel_error_rpm;
+        self.pos_controller.set_gains(params.pos_kp, params.pos_ki);
+        self.pos_controller
+            .set_output_limit(rpm_to_rad_s(params.pos_correction_limit_rpm));
+        self.motor.pid.set_config(&params.velocity_pid_config());
+    }
+
     pub fn read_cmd_from_queue(&mut self) {
         if self.cmd_queue.is_full() {
             return;
@@ -69,25 +291,95 @@ impl<
         if let Some(cmd) = self.cmd_sub.try_next_message() {
             match cmd {
                 WaitResult::Message(cmd) => {
-                    if cmd == MotorCommand::Halt {
-                        self.cmd_queue.clear();
+                    if cmd.command == MotorCommand::Halt {
+                        self.abort_queued_commands();
                     }
 
                     // cmd_queue is used as a cache to hold commands from host
-                    let _ = self.cmd_queue.push_back(cmd);
+                    if self.cmd_queue.push_back(cmd).is_ok() {
+                        self.push_event(cmd.seq_id, MotionEventKind::Accepted);
+                    }
                 }
                 _ => (),
             }
         }
     }
 
+    /// Halts the motor like a `Halt` command from host, it is used when the controller decides
+    /// to stop the motor by itself (Ex: communication watchdog expires)
+    pub fn halt(&mut self) {
+        self.abort_queued_commands();
+        let _ = self.cmd_queue.push_back(SequencedCommand {
+            seq_id: INTERNAL_SEQ_ID,
+            command: MotorCommand::Halt,
+        });
+    }
+
     pub fn is_queue_full(&self) -> bool {
         self.cmd_queue.is_full()
     }
 
+    pub fn get_motor_status(&self, id: MotorId) -> MotorStatus {
+        MotorStatus {
+            id,
+            is_queue_full: self.is_queue_full(),
+            motion_status: self.get_motion_status(),
+            process_data: self.get_motor_process_data(),
+            pid_gains: self.motor.pid.get_gains(),
+            planned_pos: self.get_planned_position(),
+        }
+    }
+
+    // The velocity commands in the queue are ignored, the position is only planned with
+    // position commands
+    fn get_planned_position(&self) -> f32 {
+        let pos = if self.control_mode == ControlMode::Position && self.pos_tracking {
+            self.pos_target_end
+        } else {
+            self.motor.encoder.get_act_position_in_rad()
+        };
+
+        self.cmd_queue.iter().fold(pos, |pos, x| match x.command {
+            MotorCommand::PositionCommand(cmd) => pos + cmd.displacement,
+            _ => pos,
+        })
+    }
+
+    pub fn get_motion_status(&self) -> MotionStatus {
+        let intp_state = match self.s_curve_intper.get_intp_status() {
+            InterpolationStatus::Done => InterpolationState::Done,
+            InterpolationStatus::Busy => InterpolationState::Busy,
+            InterpolationStatus::Error => InterpolationState::Error,
+        };
+        let halt_state = match self.halt_process_state {
+            HaltProcessState::Idle => HaltState::Idle,
+            _ => HaltState::Running,
+        };
+
+        MotionStatus {
+            queue_free: (MOTION_QUEUE_SIZE - self.cmd_queue.len()) as u16,
+            channel_free: self.cmd_sub.free_capacity() as u16,
+            channel_pending: self.cmd_sub.available() as u16,
+            intp_state,
+            halt_state,
+            active_cmd: self.active_cmd,
+            remaining_time_s: self.s_curve_intper.remaining_time(),
+        }
+    }
+
+    pub fn take_auto_tune_status(&mut self) -> Option<AutoTuneStatus> {
+        self.auto_tuner.take_status()
+    }
+
+    pub fn take_motion_event(&mut self) -> Option<MotionEvent> {
+        self.motion_events.pop_front()
+    }
+
     pub fn get_motor_process_data(&self) -> MotorProcessData {
         let s_curve_intp_data = self.s_curve_intper.get_intp_data();
         MotorProcessData {
+            timestamp: self.cycle_ticks,
+            sample_counter: self.sample_counter,
             control_mode_display: self.control_mode,
             actual_pos: self.motor.encoder.get_act_position_in_rad(),
             actual_vel: self.motor.encoder.get_act_velocity_in_rpm(),
@@ -95,15 +387,56 @@ impl<
             intp_vel: s_curve_intp_data.vel,
             intp_acc: s_curve_intp_data.acc,
             intp_jerk: s_curve_intp_data.jerk,
+            following_error: self.pos_controller.get_following_error(),
+            fault_code: self.fault_supervisor.get_fault(),
+            // The watchdog is shared by the motors, it is filled by the control loop task
+            watchdog_state: WatchdogState::default(),
         }
     }
 
     pub fn run(&mut self) {
+        self.cycle_ticks = self.motor.get_clock().now_ticks();
+        self.sample_counter = self.sample_counter.wrapping_add(1);
+
+        // The motor stays braked in fault mode until the fault is cleared
+        if self.control_mode == ControlMode::Fault {
+            self.run_fault();
+            return;
+        }
+
+        self.run_control();
+        self.update_active_command();
+
+        // Check the motor after it is driven, the stall detection compares the effort with the
+        // encoder counts over several cycles
+        let following_error_rad = if self.control_mode == ControlMode::Position {
+            Some(self.pos_controller.get_following_error())
+        } else {
+            None
+        };
+        let fault = self.fault_supervisor.check(&FaultCheckInput {
+            now_us: self.motor.get_clock().now_us(),
+            period_s: self.motor.get_period_s(),
+            control_effort: self.motor.get_control_effort(),
+            enc_count: self.motor.encoder.get_enc_count(),
+            act_vel_rpm: self.motor.encoder.get_act_velocity_in_rpm(),
+            following_error_rad,
+        });
+
+        if fault != FaultCode::None {
+            self.enter_fault();
+        }
+    }
+
+    fn run_control(&mut self) {
         // Process that reads command from queue and set command if it is ok
-        if let Some(&cmd) = self.cmd_queue.front() {
+        if let Some(&seq_cmd) = self.cmd_queue.front() {
+            let cmd = seq_cmd.command;
             let mut ready_to_set = match cmd {
-                MotorCommand::VelocityCommand(_) | MotorCommand::Halt => true,
-                MotorCommand::PositionCommand(_) => self.ready(),
+                MotorCommand::VelocityCommand(_)
+                | MotorCommand::Halt
+                | MotorCommand::ClearFault => true,
+                MotorCommand::PositionCommand(_) | MotorCommand::AutoTune(_) => self.ready(),
             };
 
             if self.halt_process_state != HaltProcessState::Idle {
@@ -112,23 +445,39 @@ impl<
             }
 
             if ready_to_set {
+                self.start_command(seq_cmd);
                 match cmd {
                     MotorCommand::Halt => {
                         self.halt_process_state = HaltProcessState::Ignite;
                         match self.control_mode {
                             ControlMode::Position => self.s_curve_intper.stop(),
-                            ControlMode::Velocity => self.motor.set_target_velocity(0.0),
+                            ControlMode::Velocity => {
+                                self.motor.set_target_velocity(0.0);
+                                self.motor.set_target_acceleration(0.0);
+                            }
+                            ControlMode::AutoTune => self.stop_auto_tune(),
                             _ => (),
                         }
                     }
                     MotorCommand::PositionCommand(x) => {
-                        self.control_mode = ControlMode::Position;
+                        self.set_control_mode(ControlMode::Position);
                         self.set_pos_command(x);
                     }
                     MotorCommand::VelocityCommand(x) => {
-                        self.control_mode = ControlMode::Velocity;
+                        self.set_control_mode(ControlMode::Velocity);
                         self.motor.set_target_velocity(x);
+                        self.motor.set_target_acceleration(0.0);
+                    }
+                    MotorCommand::AutoTune(x) => {
+                        self.set_control_mode(ControlMode::AutoTune);
+                        self.auto_tuner.start(x, &self.motor.pid.get_gains());
+                        // The pid is not running during auto tuning, but target velocity is set,
+                        // so the motor doesn't apply brake
+                        self.motor.set_target_velocity(x.target_vel);
+                        self.motor.set_target_acceleration(0.0);
                     }
+                    // There is no fault to clear
+                    MotorCommand::ClearFault => (),
                 }
 
                 // Command is set, pop it from queue
@@ -140,17 +489,62 @@ impl<
         self.process_halt();
 
         // Interpolate position command if current operation if IntpPos and update
-        // target velocity in pid velocity control loop
-        if self.control_mode == ControlMode::Position
-            && self.s_curve_intper.get_intp_status() != InterpolationStatus::Done
-        {
-            self.s_curve_intper.interpolate();
+        // target velocity in pid velocity control loop.
+        //
+        // The position controller is cascaded on top of the velocity control loop, it
+        // compares interpolated position with actual position and adds a correction to
+        // interpolated velocity, so the position error won't drift during the motion.
+        //
+        // When interpolation is done and the axis stops at the end, the position controller keeps
+        // holding the end position. If the segment ends with non-zero velocity, the axis keeps
+        // running with end velocity until next segment starts, so the position is not corrected.
+        if self.control_mode == ControlMode::Position {
+            let intp_busy = self.s_curve_intper.get_intp_status() != InterpolationStatus::Done;
+            if intp_busy {
+                self.s_curve_intper.interpolate();
+            }
 
-            let intp_vel = rad_s_to_rpm(self.s_curve_intper.get_intp_data().vel);
+            let s_curve_intp_data = self.s_curve_intper.get_intp_data();
+            self.pos_tracking =
+                intp_busy || s_curve_intp_data.vel.abs() <= STANDSTILL_INTP_VEL_RAD_S;
+            let vel_correction = if self.pos_tracking {
+                self.pos_controller.run(
+                    s_curve_intp_data.pos,
+                    self.motor.encoder.get_act_position_in_rad(),
+                    self.motor.get_period_s(),
+                )
+            } else {
+                0.0
+            };
+
+            let intp_vel = rad_s_to_rpm(s_curve_intp_data.vel + vel_correction);
+            let intp_acc = rad_s_to_rpm(s_curve_intp_data.acc);
             self.motor.set_target_velocity(intp_vel);
+            self.motor.set_target_acceleration(intp_acc);
 
             #[cfg(feature = "debug-motion")]
-            debug!("run, intp pos, {}", intp_vel);
+            debug!(
+                "run, intp pos, {}, {}",
+                intp_vel,
+                self.pos_controller.get_following_error()
+            );
+        }
+
+        // The relay in auto tuning drives the motor directly, when auto tuning is finished (or
+        // failed), the motor is stopped in velocity mode and halt process switches it to
+        // standstill
+        if self.control_mode == ControlMode::AutoTune {
+            let control_effort = self.auto_tuner.run(
+                self.motor.encoder.get_act_velocity_in_rpm(),
+                self.motor.get_period_s(),
+            );
+
+            if self.auto_tuner.is_running() {
+                self.motor.run_open_loop_control(control_effort);
+                return;
+            }
+
+            self.stop_auto_tune();
         }
 
         // The pid velocity control loop will always be run since we need to drive
@@ -161,6 +555,104 @@ impl<
         self.motor.run_pid_velocity_control();
     }
 
+    fn enter_fault(&mut self) {
+        #[cfg(feature = "debug-motion")]
+        debug!(
+            "enter_fault, {}",
+            Debug2Format(&self.fault_supervisor.get_fault())
+        );
+
+        // Drop everything that drives the motor and brake it right away
+        self.abort_active_command();
+        self.abort_queued_commands();
+        self.auto_tuner.abort();
+        self.s_curve_intper.abort();
+        self.halt_process_state = HaltProcessState::Idle;
+        self.set_control_mode(ControlMode::Fault);
+        self.motor.brake();
+    }
+
+    fn run_fault(&mut self) {
+        // Only `ClearFault` is processed in fault mode, the other commands are dropped
+        if let Some(cmd) = self.cmd_queue.pop_front() {
+            if cmd.command == MotorCommand::ClearFault {
+                self.fault_supervisor.clear();
+                self.set_control_mode(ControlMode::StandStill);
+                self.push_event(cmd.seq_id, MotionEventKind::Started);
+                self.push_event(cmd.seq_id, MotionEventKind::Completed);
+            } else {
+                self.push_event(cmd.seq_id, MotionEventKind::Aborted);
+            }
+        }
+
+        // Keep updating the encoder, so the host can still see the motor
+        self.motor.set_target_velocity(0.0);
+        self.motor.set_target_acceleration(0.0);
+        self.motor.run_open_loop_control(0.0);
+    }
+
+    fn push_event(&mut self, seq_id: u32, kind: MotionEventKind) {
+        let _ = self.motion_events.push_back(MotionEvent { seq_id, kind });
+    }
+
+    fn abort_queued_commands(&mut self) {
+        while let Some(cmd) = self.cmd_queue.pop_front() {
+            self.push_event(cmd.seq_id, MotionEventKind::Aborted);
+        }
+    }
+
+    fn abort_active_command(&mut self) {
+        if let Some(cmd) = self.active_cmd.take() {
+            self.push_event(cmd.seq_id, MotionEventKind::Aborted);
+        }
+    }
+
+    // The new command replaces the active one, the active command is aborted if it is not
+    // completed yet (Ex: velocity command is changed before the target velocity is reached)
+    fn start_command(&mut self, cmd: SequencedCommand) {
+        self.abort_active_command();
+        self.push_event(cmd.seq_id, MotionEventKind::Started);
+        self.active_cmd = Some(cmd);
+    }
+
+    fn update_active_command(&mut self) {
+        let Some(cmd) = self.active_cmd else {
+            return;
+        };
+
+        let completed = match cmd.command {
+            MotorCommand::Halt => {
+                self.control_mode == ControlMode::StandStill
+                    && self.halt_process_state == HaltProcessState::Idle
+            }
+            MotorCommand::VelocityCommand(_) => {
+                self.control_mode == ControlMode::Velocity && self.ready()
+            }
+            MotorCommand::PositionCommand(_) => {
+                self.control_mode == ControlMode::Position && self.ready()
+            }
+            // The result of auto tuning is reported by `AutoTuneStatus`
+            MotorCommand::AutoTune(_) => !self.auto_tuner.is_running(),
+            MotorCommand::ClearFault => true,
+        };
+
+        if completed {
+            self.active_cmd = None;
+            self.push_event(cmd.seq_id, MotionEventKind::Completed);
+        }
+    }
+
+    fn stop_auto_tune(&mut self) {
+        self.auto_tuner.abort();
+        self.set_control_mode(ControlMode::Velocity);
+        self.motor.set_target_velocity(0.0);
+        self.motor.set_target_acceleration(0.0);
+
+        if self.halt_process_state == HaltProcessState::Idle {
+            self.halt_process_state = HaltProcessState::Ignite;
+        }
+    }
+
     fn process_halt(&mut self) {
         match self.halt_process_state {
             HaltProcessState::Ignite => self.halt_process_state = HaltProcessState::Running,
@@ -172,21 +664,70 @@ impl<
             HaltProcessState::Finished => {
                 // Standstill control mode will be set when halt process is finished
                 self.halt_process_state = HaltProcessState::Idle;
-                self.control_mode = ControlMode::StandStill;
+                self.set_control_mode(ControlMode::StandStill);
             }
             _ => (),
         }
     }
 
+    fn set_control_mode(&mut self, control_mode: ControlMode) {
+        if self.control_mode != control_mode {
+            // Clear the controller memory when control mode is changed, the integral term
+            // and derivative history of previous mode should not affect the new mode
+            self.motor.pid.reset();
+            self.pos_controller.reset();
+            self.pos_tracking = false;
+        }
+
+        if control_mode == ControlMode::StandStill {
+            // The target velocity could be left with a position correction, make sure the motor
+            // stops in standstill mode
+            self.motor.set_target_velocity(0.0);
+            self.motor.set_target_acceleration(0.0);
+        }
+        self.control_mode = control_mode;
+    }
+
     fn set_pos_command(&mut self, cmd: PositionCommand) {
+        // The segment continues from the interpolated position, so the following error of the
+        // previous segment is still corrected. It starts from the actual position only if the
+        // interpolated position was not tracked, Ex: the axis was not in position mode, or it
+        // kept running with the end velocity of the previous segment
+        let intp_pos = self.s_curve_intper.get_intp_data().pos;
+        let pos_offset = if self.pos_tracking {
+            0.0
+        } else {
+            self.motor.encoder.get_act_position_in_rad() - intp_pos
+        };
+
+        // A segment that ends with velocity runs on with it until the next one starts, up to one
+        // cycle past its end. The segment that follows a tracked one is planned to the end
+        // position of the previous one, so the distance is not added to the end of the chain.
+        // The displacement is kept if it would reverse the direction
+        let mut cmd = cmd;
+        if self.pos_tracking {
+            let displacement = self.pos_target_end + cmd.displacement - intp_pos;
+            if displacement * cmd.displacement > 0.0 {
+                cmd.displacement = displacement;
+            }
+        }
+
         let vel_max = rpm_to_rad_s(cmd.vel_max);
-        let vel_start = rpm_to_rad_s(self.motor.encoder.get_act_velocity_in_rpm());
-        let vel_end = rpm_to_rad_s(cmd.vel_end);
+        // The segment continues the interpolated velocity, the encoder velocity is quantized and
+        // lags behind, it would change the velocity at the junction of blended segments
+        let vel_start = self.s_curve_intper.get_intp_data().vel;
+        let vel_end = self.plan_vel_end(&cmd);
+        let options = move_options(&cmd);
 
-        let pos_offset =
-            self.motor.encoder.get_act_position_in_rad() - self.s_curve_intper.get_intp_data().pos;
-        self.s_curve_intper
-            .set_target(pos_offset, cmd.displacement, vel_start, vel_end, vel_max);
+        self.pos_target_end = intp_pos + pos_offset + cmd.displacement;
+        self.s_curve_intper.set_target(
+            pos_offset,
+            cmd.displacement,
+            vel_start,
+            vel_end,
+            vel_max,
+            &options,
+        );
 
         #[cfg(feature = "debug-motion")]
         debug!(
@@ -198,6 +739,51 @@ impl<
         );
     }
 
+    // End velocity of `cmd`, the position command at the front of the queue, unit: rad/s. The
+    // look-ahead runs over the position commands that follow it in the queue, so `vel_end` is
+    // lowered to what the next commands can still stop from, and a blended command runs into
+    // the next one as fast as possible. The last blended command in the queue stops, because the
+    // command after it is not known yet
+    fn plan_vel_end(&self, cmd: &PositionCommand) -> f32 {
+        let mut moves: Vec<ChainMove, MOTION_QUEUE_SIZE> = Vec::new();
+        let mut cmds = core::iter::once(*cmd)
+            .chain(
+                self.cmd_queue
+                    .iter()
+                    .skip(1)
+                    .map_while(|x| match x.command {
+                        MotorCommand::PositionCommand(cmd) => Some(cmd),
+                        _ => None,
+                    }),
+            )
+            .peekable();
+        while let Some(cmd) = cmds.next() {
+            let dir = if cmd.displacement >= 0.0 { 1.0 } else { -1.0 };
+            let vel_max = rpm_to_rad_s(cmd.vel_max);
+            let vel_end = match (cmd.blend, cmds.peek()) {
+                (true, Some(_)) => vel_max.abs(),
+                (true, None) => 0.0,
+                (false, _) => dir * rpm_to_rad_s(cmd.vel_end),
+            };
+            let limits = self
+                .s_curve_intper
+                .plan_limits(vel_max, &move_options(&cmd));
+            // The queue can't hold more moves than the vector
+            let _ = moves.push(ChainMove {
+                displacement: cmd.displacement,
+                vel_end,
+                limits,
+            });
+        }
+
+        let vel_start = self.s_curve_intper.get_intp_data().vel;
+        plan_junctions(vel_start, &mut moves);
+        moves.first().map_or(0.0, |x| {
+            let dir = if x.displacement >= 0.0 { 1.0 } else { -1.0 };
+            dir * x.vel_end
+        })
+    }
+
     fn ready(&self) -> bool {
         let is_ready = match self.control_mode {
             ControlMode::Position => {
@@ -213,11 +799,343 @@ impl<
                 #[cfg(feature = "debug-motion")]
                 debug!("ready, vel, {}", self.motor.get_error());
 
-                self.motor.pid.get_error().abs() <= 60.0
+                self.motor.pid.get_error().abs() <= self.ready_vel_error_rpm
             }
             ControlMode::StandStill => true,
+            ControlMode::AutoTune => !self.auto_tuner.is_running(),
+            ControlMode::Fault => false,
         };
 
         is_ready
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{command_limits, DEFAULT_PARAMS};
+    use protocol::{AutoTuneConfig, TuningRule};
+
+    const ID: MotorId = MotorId::Left;
+    const TRAVEL_LIMITS_RAD: (f32, f32) = (-10.0, 10.0);
+
+    fn status() -> MotorStatus {
+        MotorStatus {
+            id: ID,
+            is_queue_full: false,
+            motion_status: MotionStatus::default(),
+            process_data: MotorProcessData::default(),
+            pid_gains: PidGains::default(),
+            planned_pos: 0.0,
+        }
+    }
+
+    fn limits() -> CommandLimits {
+        CommandLimits {
+            travel_limits_rad: Some(TRAVEL_LIMITS_RAD),
+            ..command_limits(&DEFAULT_PARAMS)
+        }
+    }
+
+    fn position(displacement: f32, vel_max: f32) -> PositionCommand {
+        PositionCommand {
+            displacement,
+            vel_max,
+            ..Default::default()
+        }
+    }
+
+    fn auto_tune() -> AutoTuneConfig {
+        AutoTuneConfig {
+            target_vel: 500.0,
+            relay_amplitude: 0.2,
+            hysteresis: 5.0,
+            cycles: 4,
+            timeout_s: 5.0,
+            rule: TuningRule::ZieglerNichols,
+        }
+    }
+
+    fn validate(cmd: MotorCommand) -> CommandSetResult {
+        validate_motor_command(ID, &cmd, &status(), &limits())
+    }
+
+    fn invalid(field: CommandField) -> CommandSetResult {
+        Err(CommandError::InvalidValue(ID, field))
+    }
+
+    #[test]
+    fn valid_commands_are_accepted() {
+        let vel_limit = limits().vel_limit_rpm;
+        assert_eq!(validate(MotorCommand::VelocityCommand(vel_limit)), Ok(()));
+        assert_eq!(validate(MotorCommand::VelocityCommand(-vel_limit)), Ok(()));
+        assert_eq!(
+            validate(MotorCommand::PositionCommand(position(5.0, 500.0))),
+            Ok(())
+        );
+        // Switches the control mode only
+        assert_eq!(
+            validate(MotorCommand::PositionCommand(position(0.0, 0.0))),
+            Ok(())
+        );
+        assert_eq!(validate(MotorCommand::AutoTune(auto_tune())), Ok(()));
+        assert_eq!(validate(MotorCommand::Halt), Ok(()));
+        assert_eq!(validate(MotorCommand::ClearFault), Ok(()));
+    }
+
+    #[test]
+    fn velocity_out_of_range_is_rejected() {
+        let vel_limit = limits().vel_limit_rpm;
+        for x in [vel_limit + 1.0, -vel_limit - 1.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(
+                validate(MotorCommand::VelocityCommand(x)),
+                invalid(CommandField::Velocity)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_position_fields_are_rejected() {
+        let vel_limit = limits().vel_limit_rpm;
+        let cases = [
+            (position(f32::NAN, 500.0), CommandField::Displacement),
+            (position(f32::INFINITY, 500.0), CommandField::Displacement),
+            (position(5.0, vel_limit + 1.0), CommandField::VelMax),
+            (position(5.0, f32::NAN), CommandField::VelMax),
+            (position(5.0, 0.0), CommandField::VelMax),
+            (
+                PositionCommand {
+                    vel_end: 600.0,
+                    ..position(5.0, 500.0)
+                },
+                CommandField::VelEnd,
+            ),
+            (
+                PositionCommand {
+                    acc_max: Some(0.0),
+                    ..position(5.0, 500.0)
+                },
+                CommandField::AccMax,
+            ),
+            (
+                PositionCommand {
+                    dec_max: Some(-1.0),
+                    ..position(5.0, 500.0)
+                },
+                CommandField::DecMax,
+            ),
+            (
+                PositionCommand {
+                    jerk_max: Some(f32::INFINITY),
+                    ..position(5.0, 500.0)
+                },
+                CommandField::JerkMax,
+            ),
+            (
+                PositionCommand {
+                    jerk_out_max: Some(f32::NAN),
+                    ..position(5.0, 500.0)
+                },
+                CommandField::JerkOutMax,
+            ),
+            (
+                PositionCommand {
+                    acc_end: Some(f32::NAN),
+                    vel_end: 100.0,
+                    ..position(5.0, 500.0)
+                },
+                CommandField::AccEnd,
+            ),
+            // A move that stops or is blended ends without acceleration
+            (
+                PositionCommand {
+                    acc_end: Some(10.0),
+                    ..position(5.0, 500.0)
+                },
+                CommandField::AccEnd,
+            ),
+            (
+                PositionCommand {
+                    acc_end: Some(10.0),
+                    vel_end: 100.0,
+                    blend: true,
+                    ..position(5.0, 500.0)
+                },
+                CommandField::AccEnd,
+            ),
+        ];
+        for (cmd, field) in cases {
+            assert_eq!(
+                validate(MotorCommand::PositionCommand(cmd)),
+                invalid(field),
+                "{:?}",
+                cmd
+            );
+        }
+    }
+
+    #[test]
+    fn position_beyond_travel_limits_is_rejected() {
+        let out_of_limits = Err(CommandError::OutOfTravelLimits(ID));
+        let (min, max) = TRAVEL_LIMITS_RAD;
+        assert_eq!(
+            validate(MotorCommand::PositionCommand(position(max, 500.0))),
+            Ok(())
+        );
+        assert_eq!(
+            validate(MotorCommand::PositionCommand(position(max + 0.1, 500.0))),
+            out_of_limits
+        );
+        assert_eq!(
+            validate(MotorCommand::PositionCommand(position(min - 0.1, 500.0))),
+            out_of_limits
+        );
+
+        // The end is checked from the position after the queued commands
+        let queued = MotorStatus {
+            planned_pos: max - 1.0,
+            ..status()
+        };
+        let cmd = MotorCommand::PositionCommand(position(2.0, 500.0));
+        assert_eq!(
+            validate_motor_command(ID, &cmd, &queued, &limits()),
+            out_of_limits
+        );
+
+        // The axis runs on with the end velocity if no command follows
+        let cmd = PositionCommand {
+            vel_end: 500.0,
+            ..position(max - 0.1, 500.0)
+        };
+        assert_eq!(validate(MotorCommand::PositionCommand(cmd)), out_of_limits);
+        let cmd = PositionCommand { blend: true, ..cmd };
+        assert_eq!(validate(MotorCommand::PositionCommand(cmd)), Ok(()));
+
+        // No limits on a wheel
+        let limits = CommandLimits {
+            travel_limits_rad: None,
+            ..limits()
+        };
+        let cmd = MotorCommand::PositionCommand(position(max * 100.0, 500.0));
+        assert_eq!(validate_motor_command(ID, &cmd, &status(), &limits), Ok(()));
+    }
+
+    #[test]
+    fn invalid_auto_tune_fields_are_rejected() {
+        let vel_limit = limits().vel_limit_rpm;
+        let cases = [
+            (
+                AutoTuneConfig {
+                    target_vel: 0.0,
+                    ..auto_tune()
+                },
+                CommandField::AutoTuneTargetVel,
+            ),
+            (
+                AutoTuneConfig {
+                    target_vel: vel_limit + 1.0,
+                    ..auto_tune()
+                },
+                CommandField::AutoTuneTargetVel,
+            ),
+            (
+                AutoTuneConfig {
+                    relay_amplitude: 0.0,
+                    ..auto_tune()
+                },
+                CommandField::AutoTuneRelayAmplitude,
+            ),
+            (
+                AutoTuneConfig {
+                    relay_amplitude: 1.5,
+                    ..auto_tune()
+                },
+                CommandField::AutoTuneRelayAmplitude,
+            ),
+            (
+                AutoTuneConfig {
+                    hysteresis: -1.0,
+                    ..auto_tune()
+                },
+                CommandField::AutoTuneHysteresis,
+            ),
+            (
+                AutoTuneConfig {
+                    cycles: 0,
+                    ..auto_tune()
+                },
+                CommandField::AutoTuneCycles,
+            ),
+            (
+                AutoTuneConfig {
+                    timeout_s: f32::NAN,
+                    ..auto_tune()
+                },
+                CommandField::AutoTuneTimeout,
+            ),
+        ];
+        for (cmd, field) in cases {
+            assert_eq!(
+                validate(MotorCommand::AutoTune(cmd)),
+                invalid(field),
+                "{:?}",
+                cmd
+            );
+        }
+    }
+
+    #[test]
+    fn auto_tune_waits_for_the_previous_motion() {
+        let not_ready = Err(CommandError::NotReady(ID));
+        let cmd = MotorCommand::AutoTune(auto_tune());
+
+        let mut moving = status();
+        moving.motion_status.active_cmd = Some(SequencedCommand {
+            seq_id: 1,
+            command: MotorCommand::VelocityCommand(100.0),
+        });
+        assert_eq!(
+            validate_motor_command(ID, &cmd, &moving, &limits()),
+            not_ready
+        );
+
+        let mut halting = status();
+        halting.motion_status.halt_state = HaltState::Running;
+        assert_eq!(
+            validate_motor_command(ID, &cmd, &halting, &limits()),
+            not_ready
+        );
+    }
+
+    #[test]
+    fn commands_are_rejected_in_fault_and_auto_tune_mode() {
+        let commands = [
+            MotorCommand::VelocityCommand(100.0),
+            MotorCommand::PositionCommand(position(5.0, 500.0)),
+            MotorCommand::AutoTune(auto_tune()),
+        ];
+
+        let mut faulted = status();
+        faulted.process_data.fault_code = FaultCode::Stall;
+        faulted.process_data.control_mode_display = ControlMode::Fault;
+        let mut tuning = status();
+        tuning.process_data.control_mode_display = ControlMode::AutoTune;
+
+        for cmd in commands {
+            assert_eq!(
+                validate_motor_command(ID, &cmd, &faulted, &limits()),
+                Err(CommandError::Faulted(ID))
+            );
+            assert_eq!(
+                validate_motor_command(ID, &cmd, &tuning, &limits()),
+                Err(CommandError::UnsupportedInMode(ID, ControlMode::AutoTune))
+            );
+        }
+        // `Halt` stops the motor in any mode, `ClearFault` leaves the fault mode
+        for status in [faulted, tuning] {
+            for cmd in [MotorCommand::Halt, MotorCommand::ClearFault] {
+                assert_eq!(validate_motor_command(ID, &cmd, &status, &limits()), Ok(()));
+            }
+        }
+    }
+}
@@ -1,48 +1,33 @@
 #[cfg(feature = "debug-motor")]
 use defmt::debug;
 
-use embassy_stm32::gpio::Output;
-use embassy_stm32::timer::low_level::OutputPolarity;
-use embassy_stm32::timer::simple_pwm::SimplePwmChannel;
-use embassy_stm32::timer::GeneralInstance4Channel;
-use embassy_time::{block_for, Duration};
-
 use crate::encoder::Encoder;
+use crate::hal::{Clock, MotorDriver, PositionSensor};
 use crate::pid::Pid;
 
-pub struct BldcMotor24H<'a, T1: GeneralInstance4Channel, T2: GeneralInstance4Channel> {
-    pub encoder: Encoder<'a, T1, 400>,
+pub struct BldcMotor24H<S: PositionSensor, D: MotorDriver, C: Clock> {
+    pub encoder: Encoder<S>,
     pub pid: Pid,
-    pwm_channel: SimplePwmChannel<'a, T2>,
-    dir_pin: Output<'a>,
-    break_pin: Output<'a>,
+    driver: D,
+    clock: C,
     period_s: f32,
     break_applied: bool,
     target_velocity_rpm: f32,
+    // Control effort applied in last cycle, 0 when the brake is applied
+    control_effort: f32,
 }
 
-impl<'a, T1: GeneralInstance4Channel, T2: GeneralInstance4Channel> BldcMotor24H<'a, T1, T2> {
-    pub fn new(
-        encoder: Encoder<'a, T1, 400>,
-        mut pwm_channel: SimplePwmChannel<'a, T2>,
-        dir_pin: Output<'a>,
-        break_pin: Output<'a>,
-        pid: Pid,
-        period_s: f32,
-    ) -> Self {
-        // 24H motor, 0% duty: full speed, 100% duty: 0 speed
-        pwm_channel.set_polarity(OutputPolarity::ActiveLow);
-        pwm_channel.enable();
-
+impl<S: PositionSensor, D: MotorDriver, C: Clock> BldcMotor24H<S, D, C> {
+    pub fn new(encoder: Encoder<S>, driver: D, clock: C, pid: Pid, period_s: f32) -> Self {
         Self {
             encoder,
             pid,
-            pwm_channel,
-            dir_pin,
-            break_pin,
+            driver,
+            clock,
             period_s,
             break_applied: false,
             target_velocity_rpm: 0.0,
+            control_effort: 0.0,
         }
     }
 
@@ -51,16 +36,35 @@ impl<'a, T1: GeneralInstance4Channel, T2: GeneralInstance4Channel> BldcMotor24H<
         self.pid.set_target_velocity(target_velocity_rpm);
     }
 
+    pub fn set_target_acceleration(&mut self, target_acceleration_rpm_s: f32) {
+        self.pid.set_target_acceleration(target_acceleration_rpm_s);
+    }
+
     pub fn get_period_s(&self) -> f32 {
         self.period_s
     }
 
+    pub fn get_clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn get_control_effort(&self) -> f32 {
+        self.control_effort
+    }
+
     pub fn break_on(&mut self) {
-        self.break_pin.set_low();
-        self.dir_pin.set_low();
-        block_for(Duration::from_micros(2));
-        self.break_pin.set_high();
-        self.dir_pin.set_high();
+        self.driver.set_break_pin(false);
+        self.driver.set_dir_pin(false);
+        self.clock.delay_us(2);
+        self.driver.set_break_pin(true);
+        self.driver.set_dir_pin(true);
+    }
+
+    /// Clears the targets and applies the brake right away without waiting for the pid
+    pub fn brake(&mut self) {
+        self.set_target_velocity(0.0);
+        self.set_target_acceleration(0.0);
+        self.drive(0.0);
     }
 
     pub fn run_pid_velocity_control(&mut self) {
@@ -76,14 +80,21 @@ impl<'a, T1: GeneralInstance4Channel, T2: GeneralInstance4Channel> BldcMotor24H<
         let control_effort: f32 = self
             .pid
             .run(self.encoder.get_act_velocity_in_rpm(), self.period_s);
+        self.drive(control_effort);
+    }
+
+    /// Drives the motor with given control effort without running pid, it is used when the
+    /// output is decided by other controllers (Ex: relay in auto tuning)
+    pub fn run_open_loop_control(&mut self, control_effort: f32) {
+        self.encoder.update_act_velocity_in_rpm(self.period_s);
+        self.drive(control_effort.clamp(-1.0, 1.0));
+    }
+
+    fn drive(&mut self, control_effort: f32) {
         let dir = if control_effort >= 0.0 { 1.0 } else { -1.0 };
 
         let mut duty_cycle_percent: u8 = (control_effort * dir * 100.0) as u8;
-        if dir < 0.0 {
-            self.dir_pin.set_high();
-        } else {
-            self.dir_pin.set_low();
-        }
+        self.driver.set_dir_pin(dir < 0.0);
 
         if self.target_velocity_rpm == 0.0 {
             if !self.break_applied {
@@ -91,11 +102,13 @@ impl<'a, T1: GeneralInstance4Channel, T2: GeneralInstance4Channel> BldcMotor24H<
                 self.break_on();
             }
             duty_cycle_percent = 0;
+            self.control_effort = 0.0;
         } else {
-            self.break_pin.set_high();
+            self.driver.set_break_pin(true);
             self.break_applied = false;
+            self.control_effort = control_effort;
         }
 
-        self.pwm_channel.set_duty_cycle_percent(duty_cycle_percent);
+        self.driver.set_duty_cycle_percent(duty_cycle_percent);
     }
 }
@@ -0,0 +1,424 @@
+use heapless::Vec;
+use protocol::{ParamId, ParamStoreError, ParamStoreResult, ParamValue};
+
+use crate::hal::{FlashError, FlashPage};
+use crate::params::ParamRegistry;
+
+// Record at the start of the flash page:
+// | magic: u32 | layout version: u16 | payload length: u16 | crc32: u32 | payload |
+// The header is little endian, the crc covers the version, the length and the payload. The
+// payload is the postcard encoded list of (id, value) pairs.
+const MAGIC: u32 = 0x5041_524d;
+const HEADER_LEN: usize = 12;
+
+/// Layout of the record, a new layout takes a new version and `decode_payload` converts the
+/// records of the older versions
+pub const LAYOUT_VERSION: u16 = 1;
+
+// The values are stored by id instead of the whole `DeviceParams`, so a parameter that is added
+// later gets its default, a removed parameter is skipped, and a value that is out of the new
+// range is reset to its default
+pub const MAX_STORED_PARAMS: usize = 32;
+// Length of the list, then the id (varint) and the value (variant + f32 or varint) of each entry
+const MAX_PAYLOAD_LEN: usize = 1 + MAX_STORED_PARAMS * (3 + 1 + 5);
+// Multiple of the write size of the flash
+const RECORD_BUF_LEN: usize = (HEADER_LEN + MAX_PAYLOAD_LEN).next_multiple_of(8);
+
+type StoredParams = Vec<(ParamId, ParamValue), MAX_STORED_PARAMS>;
+
+impl From<FlashError> for ParamStoreError {
+    fn from(_err: FlashError) -> Self {
+        ParamStoreError::Flash
+    }
+}
+
+/// Saves the values of the parameter registry in a flash page. The encoding doesn't depend on
+/// the flash, so it can be checked on PC with an in-memory page (Ex: `motor_sim::SimFlashPage`)
+pub struct ParamStore<F: FlashPage> {
+    flash: F,
+}
+
+impl<F: FlashPage> ParamStore<F> {
+    pub fn new(flash: F) -> Self {
+        Self { flash }
+    }
+
+    /// Writes the current values. The page is erased first, if the power is lost before the
+    /// record is written, the record is corrupted and the defaults are used on next boot
+    pub fn save(&mut self, registry: &ParamRegistry) -> ParamStoreResult {
+        // The padding reads like erased flash
+        let mut buf = [0xff_u8; RECORD_BUF_LEN];
+        let len = encode_record(registry, &mut buf)?.next_multiple_of(F::WRITE_SIZE);
+        if len > buf.len() || len > F::SIZE {
+            return Err(ParamStoreError::Encode);
+        }
+
+        self.flash.erase()?;
+        self.flash.write(0, &buf[..len])?;
+        Ok(())
+    }
+
+    /// Replaces the values with the saved ones, the registry is not changed if there is no
+    /// valid record
+    pub fn load(&mut self, registry: &mut ParamRegistry) -> ParamStoreResult {
+        let mut buf = [0_u8; RECORD_BUF_LEN];
+        let len = buf.len().min(F::SIZE);
+        self.flash.read(0, &mut buf[..len])?;
+        decode_record(&buf[..len], registry)
+    }
+
+    /// Erases the saved values and restores the defaults
+    pub fn factory_reset(&mut self, registry: &mut ParamRegistry) -> ParamStoreResult {
+        self.flash.erase()?;
+        registry.reset();
+        Ok(())
+    }
+}
+
+/// Encodes the current values of the registry into `buf` and returns the length of the record
+pub fn encode_record(registry: &ParamRegistry, buf: &mut [u8]) -> Result<usize, ParamStoreError> {
+    let mut entries = StoredParams::new();
+    for entry in registry.entries() {
+        entries.push(entry).map_err(|_e| ParamStoreError::Encode)?;
+    }
+
+    if buf.len() < HEADER_LEN {
+        return Err(ParamStoreError::Encode);
+    }
+    let (header, payload) = buf.split_at_mut(HEADER_LEN);
+    let payload_len = postcard::to_slice(&entries, payload)
+        .map_err(|_e| ParamStoreError::Encode)?
+        .len();
+    let crc = record_crc(LAYOUT_VERSION, payload_len as u16, &payload[..payload_len]);
+
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&LAYOUT_VERSION.to_le_bytes());
+    header[6..8].copy_from_slice(&(payload_len as u16).to_le_bytes());
+    header[8..12].copy_from_slice(&crc.to_le_bytes());
+
+    Ok(HEADER_LEN + payload_len)
+}
+
+/// Checks the record and sets the stored values on top of the defaults. The registry is only
+/// changed if the whole record is valid
+pub fn decode_record(record: &[u8], registry: &mut ParamRegistry) -> ParamStoreResult {
+    let header = record.get(..HEADER_LEN).ok_or(ParamStoreError::Corrupted)?;
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    let payload_len = u16::from_le_bytes([header[6], header[7]]);
+    let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+
+    if magic == u32::MAX {
+        // The page is erased, nothing is saved yet
+        return Err(ParamStoreError::Empty);
+    }
+    if magic != MAGIC {
+        return Err(ParamStoreError::Corrupted);
+    }
+
+    let payload = record
+        .get(HEADER_LEN..HEADER_LEN + payload_len as usize)
+        .ok_or(ParamStoreError::Corrupted)?;
+    if record_crc(version, payload_len, payload) != crc {
+        return Err(ParamStoreError::Corrupted);
+    }
+
+    let entries = decode_payload(version, payload)?;
+
+    // The values that are rejected by the registry (unknown id, wrong type or out of range) keep
+    // their defaults
+    registry.reset();
+    for (id, value) in entries {
+        let _ = registry.set(id, value);
+    }
+
+    Ok(())
+}
+
+// Converts the payload of each layout version to the entries of current layout
+fn decode_payload(version: u16, payload: &[u8]) -> Result<StoredParams, ParamStoreError> {
+    match version {
+        1 => postcard::from_bytes(payload).map_err(|_e| ParamStoreError::Corrupted),
+        // The record is written by a newer firmware
+        _ => Err(ParamStoreError::UnsupportedVersion(version)),
+    }
+}
+
+fn record_crc(version: u16, payload_len: u16, payload: &[u8]) -> u32 {
+    let crc = crc32_update(u32::MAX, &version.to_le_bytes());
+    let crc = crc32_update(crc, &payload_len.to_le_bytes());
+    !crc32_update(crc, payload)
+}
+
+// CRC-32 (IEEE 802.3) without lookup table, the record is small and only checked on load
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{MotorId, PidGains};
+
+    use super::*;
+    use crate::params::{
+        DeviceParams, PARAM_ACC_LIMIT, PARAM_LEFT_VEL_KP, PARAM_RIGHT_VEL_KFF_VEL, PARAM_VEL_LIMIT,
+    };
+
+    const PAGE_SIZE: usize = 2048;
+    const DEFAULTS: DeviceParams = DeviceParams::new(0.005, 400, 4000.0);
+
+    // NOR flash in memory, a write only clears bits like the flash of the MCU
+    struct MemFlashPage {
+        bytes: [u8; PAGE_SIZE],
+    }
+
+    impl MemFlashPage {
+        fn new() -> Self {
+            Self {
+                bytes: [0xff; PAGE_SIZE],
+            }
+        }
+    }
+
+    impl FlashPage for MemFlashPage {
+        const SIZE: usize = PAGE_SIZE;
+        const WRITE_SIZE: usize = 2;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+            let start = offset as usize;
+            let src = self
+                .bytes
+                .get(start..start + bytes.len())
+                .ok_or(FlashError)?;
+            bytes.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn erase(&mut self) -> Result<(), FlashError> {
+            self.bytes.fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+            let start = offset as usize;
+            if !start.is_multiple_of(Self::WRITE_SIZE)
+                || !bytes.len().is_multiple_of(Self::WRITE_SIZE)
+            {
+                return Err(FlashError);
+            }
+            let dst = self
+                .bytes
+                .get_mut(start..start + bytes.len())
+                .ok_or(FlashError)?;
+            for (x, byte) in dst.iter_mut().zip(bytes) {
+                *x &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    const TUNED_GAINS: PidGains = PidGains {
+        kp: 0.0002,
+        ki: 0.003,
+        kd: 0.000001,
+        kff_vel: 0.0003,
+        kff_acc: -0.00001,
+    };
+
+    fn changed_registry() -> ParamRegistry {
+        let mut registry = ParamRegistry::new(DEFAULTS);
+        registry
+            .set(PARAM_VEL_LIMIT, ParamValue::F32(3000.0))
+            .unwrap();
+        registry.set_pid_gains(MotorId::Right, &TUNED_GAINS);
+        registry
+    }
+
+    // Writes a record of any layout version with the given entries, like an older or a newer
+    // firmware would
+    fn write_record(flash: &mut MemFlashPage, version: u16, entries: &[(ParamId, ParamValue)]) {
+        let mut payload = [0_u8; MAX_PAYLOAD_LEN];
+        let payload = postcard::to_slice(entries, &mut payload).unwrap();
+        let payload_len = payload.len() as u16;
+        let crc = record_crc(version, payload_len, payload);
+
+        let record = &mut flash.bytes;
+        record.fill(0xff);
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&version.to_le_bytes());
+        record[6..8].copy_from_slice(&payload_len.to_le_bytes());
+        record[8..12].copy_from_slice(&crc.to_le_bytes());
+        record[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+    }
+
+    #[test]
+    fn saved_values_are_loaded() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        let saved = changed_registry();
+        store.save(&saved).unwrap();
+
+        let mut registry = ParamRegistry::new(DEFAULTS);
+        store.load(&mut registry).unwrap();
+
+        assert_eq!(registry.values(), saved.values());
+        assert_eq!(*registry.values().pid_gains(MotorId::Right), TUNED_GAINS);
+        assert_eq!(
+            registry.values().pid_gains(MotorId::Left),
+            DEFAULTS.pid_gains(MotorId::Left)
+        );
+    }
+
+    #[test]
+    fn record_is_decoded_from_encoded_bytes() {
+        let saved = changed_registry();
+        let mut buf = [0xff_u8; RECORD_BUF_LEN];
+        let len = encode_record(&saved, &mut buf).unwrap();
+
+        let mut registry = ParamRegistry::new(DEFAULTS);
+        decode_record(&buf[..len], &mut registry).unwrap();
+
+        assert_eq!(registry.values(), saved.values());
+    }
+
+    #[test]
+    fn save_replaces_the_previous_record() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        store.save(&changed_registry()).unwrap();
+        store.save(&ParamRegistry::new(DEFAULTS)).unwrap();
+
+        let mut registry = changed_registry();
+        store.load(&mut registry).unwrap();
+
+        assert_eq!(*registry.values(), DEFAULTS);
+    }
+
+    #[test]
+    fn erased_page_is_empty() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        let mut registry = changed_registry();
+
+        assert_eq!(store.load(&mut registry), Err(ParamStoreError::Empty));
+        assert_eq!(registry.values(), changed_registry().values());
+    }
+
+    #[test]
+    fn record_with_wrong_crc_is_rejected() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        store.save(&changed_registry()).unwrap();
+        // A bit of the payload is lost
+        store.flash.bytes[HEADER_LEN + 3] ^= 0x10;
+
+        let mut registry = ParamRegistry::new(DEFAULTS);
+        assert_eq!(store.load(&mut registry), Err(ParamStoreError::Corrupted));
+        assert_eq!(*registry.values(), DEFAULTS);
+    }
+
+    #[test]
+    fn record_with_wrong_length_is_rejected() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        store.save(&changed_registry()).unwrap();
+        store.flash.bytes[6] = store.flash.bytes[6].wrapping_sub(1);
+
+        let mut registry = ParamRegistry::new(DEFAULTS);
+        assert_eq!(store.load(&mut registry), Err(ParamStoreError::Corrupted));
+        assert_eq!(*registry.values(), DEFAULTS);
+    }
+
+    #[test]
+    fn record_with_wrong_magic_is_rejected() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        store.save(&changed_registry()).unwrap();
+        store.flash.bytes[0] = 0;
+
+        let mut registry = ParamRegistry::new(DEFAULTS);
+        assert_eq!(store.load(&mut registry), Err(ParamStoreError::Corrupted));
+    }
+
+    #[test]
+    fn record_of_newer_layout_is_rejected() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        write_record(
+            &mut store.flash,
+            LAYOUT_VERSION + 1,
+            &[(PARAM_VEL_LIMIT, ParamValue::F32(3000.0))],
+        );
+
+        let mut registry = ParamRegistry::new(DEFAULTS);
+        assert_eq!(
+            store.load(&mut registry),
+            Err(ParamStoreError::UnsupportedVersion(LAYOUT_VERSION + 1))
+        );
+        assert_eq!(*registry.values(), DEFAULTS);
+    }
+
+    #[test]
+    fn parameters_missing_from_older_record_get_defaults() {
+        // Saved before the velocity gains were stored
+        let mut store = ParamStore::new(MemFlashPage::new());
+        write_record(
+            &mut store.flash,
+            1,
+            &[(PARAM_VEL_LIMIT, ParamValue::F32(3000.0))],
+        );
+
+        let mut registry = changed_registry();
+        store.load(&mut registry).unwrap();
+
+        assert_eq!(registry.get(PARAM_VEL_LIMIT), Ok(ParamValue::F32(3000.0)));
+        assert_eq!(
+            registry.values().pid_gains(MotorId::Right),
+            DEFAULTS.pid_gains(MotorId::Right)
+        );
+    }
+
+    #[test]
+    fn unknown_and_out_of_range_values_get_defaults() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        write_record(
+            &mut store.flash,
+            1,
+            &[
+                // Removed parameter
+                (1000, ParamValue::F32(1.0)),
+                // Negative gain, out of the range of the newer firmware
+                (PARAM_LEFT_VEL_KP, ParamValue::F32(-1.0)),
+                // Wrong type
+                (PARAM_ACC_LIMIT, ParamValue::U32(1)),
+                (PARAM_RIGHT_VEL_KFF_VEL, ParamValue::F32(0.0005)),
+            ],
+        );
+
+        let mut registry = ParamRegistry::new(DEFAULTS);
+        store.load(&mut registry).unwrap();
+
+        let values = registry.values();
+        assert_eq!(
+            values.pid_gains(MotorId::Left),
+            DEFAULTS.pid_gains(MotorId::Left)
+        );
+        assert_eq!(values.acc_limit, DEFAULTS.acc_limit);
+        assert_eq!(values.pid_gains(MotorId::Right).kff_vel, 0.0005);
+    }
+
+    #[test]
+    fn factory_reset_erases_the_record() {
+        let mut store = ParamStore::new(MemFlashPage::new());
+        store.save(&changed_registry()).unwrap();
+
+        let mut registry = changed_registry();
+        store.factory_reset(&mut registry).unwrap();
+
+        assert_eq!(*registry.values(), DEFAULTS);
+        assert_eq!(store.load(&mut registry), Err(ParamStoreError::Empty));
+    }
+}
@@ -0,0 +1,621 @@
+use heapless::String;
+use protocol::{
+    MotorId, ParamError, ParamGetResult, ParamId, ParamInfo, ParamSetResult, ParamUnit, ParamValue,
+    PidGains,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    motion::DEFAULT_READY_VEL_ERROR_RPM,
+    pid::{
+        default_velocity_gains, AntiWindup, DerivativeMode, Pid, PidConfig,
+        DEFAULT_DERIVATIVE_FILTER_PERIODS, DEFAULT_TRACKING_GAIN,
+    },
+    position_control::{
+        PositionController, DEFAULT_POS_CORRECTION_LIMIT_RPM, DEFAULT_POS_KI, DEFAULT_POS_KP,
+    },
+    rpm_to_rad_s,
+};
+
+pub const PARAM_CONTROL_PERIOD: ParamId = 0;
+pub const PARAM_ENCODER_COUNTS_PER_REV: ParamId = 1;
+pub const PARAM_VEL_LIMIT: ParamId = 2;
+pub const PARAM_ACC_LIMIT: ParamId = 3;
+pub const PARAM_JERK_LIMIT: ParamId = 4;
+pub const PARAM_READY_VEL_ERROR: ParamId = 5;
+pub const PARAM_POS_KP: ParamId = 6;
+pub const PARAM_POS_KI: ParamId = 7;
+pub const PARAM_POS_CORRECTION_LIMIT: ParamId = 8;
+pub const PARAM_VEL_ANTI_WINDUP: ParamId = 9;
+pub const PARAM_VEL_TRACKING_GAIN: ParamId = 10;
+pub const PARAM_VEL_DERIVATIVE_MODE: ParamId = 11;
+pub const PARAM_VEL_DERIVATIVE_FILTER: ParamId = 12;
+pub const PARAM_VEL_SETPOINT_WEIGHT_P: ParamId = 13;
+pub const PARAM_VEL_SETPOINT_WEIGHT_D: ParamId = 14;
+pub const PARAM_LEFT_VEL_KP: ParamId = 15;
+pub const PARAM_LEFT_VEL_KI: ParamId = 16;
+pub const PARAM_LEFT_VEL_KD: ParamId = 17;
+pub const PARAM_LEFT_VEL_KFF_VEL: ParamId = 18;
+pub const PARAM_LEFT_VEL_KFF_ACC: ParamId = 19;
+pub const PARAM_RIGHT_VEL_KP: ParamId = 20;
+pub const PARAM_RIGHT_VEL_KI: ParamId = 21;
+pub const PARAM_RIGHT_VEL_KD: ParamId = 22;
+pub const PARAM_RIGHT_VEL_KFF_VEL: ParamId = 23;
+pub const PARAM_RIGHT_VEL_KFF_ACC: ParamId = 24;
+
+// Values of `vel_anti_windup`
+pub const ANTI_WINDUP_NONE: u32 = 0;
+pub const ANTI_WINDUP_CONDITIONAL_INTEGRATION: u32 = 1;
+pub const ANTI_WINDUP_BACK_CALCULATION: u32 = 2;
+// Values of `vel_derivative_mode`
+pub const DERIVATIVE_ON_ERROR: u32 = 0;
+pub const DERIVATIVE_ON_MEASUREMENT: u32 = 1;
+
+// Parameters of the motion stack that used to be compile-time constants, the board defines
+// the defaults with `DeviceParams::new`. The parameters that require reboot are only read when the motion stack is
+// created, the others are applied by `Motion::set_params`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct DeviceParams {
+    // Control loop period, unit: s
+    pub period_s: f32,
+    // Encoder counts per motor revolution after quadrature decoding
+    pub counts_per_rev: u16,
+    // Velocity limit of the commands and the interpolator, unit: rpm
+    pub vel_limit_rpm: f32,
+    // Limits of s-curve interpolator, unit: rad/s^2, rad/s^3
+    pub acc_limit: f32,
+    pub jerk_limit: f32,
+    // Velocity error below which the velocity command is completed and the next command can
+    // start, unit: rpm
+    pub ready_vel_error_rpm: f32,
+    // Gains of the position controller, unit: 1/s, 1/s^2, and the limit of the velocity
+    // correction that it adds to the interpolated velocity, unit: rpm
+    pub pos_kp: f32,
+    pub pos_ki: f32,
+    pub pos_correction_limit_rpm: f32,
+    // Modes of the velocity controller, see `pid::PidConfig`. The anti-windup and the derivative
+    // mode are the values above, the tracking gain is only used by back-calculation, unit: 1/s
+    pub vel_anti_windup: u32,
+    pub vel_tracking_gain: f32,
+    pub vel_derivative_mode: u32,
+    // Time constant of the derivative filter, unit: s
+    pub vel_derivative_filter_s: f32,
+    pub vel_setpoint_weight_p: f32,
+    pub vel_setpoint_weight_d: f32,
+    // Gains of the velocity controller of the left and right motor, they are also set with
+    // `SetPidGainsEndPoint` (Ex: the result of auto tuning)
+    pub vel_pid_gains: [PidGains; 2],
+}
+
+impl DeviceParams {
+    /// Defaults of a board with the given control loop period, encoder and velocity limit, the
+    /// limits of the interpolator are derived from the velocity limit
+    pub const fn new(period_s: f32, counts_per_rev: u16, vel_limit_rpm: f32) -> Self {
+        Self {
+            period_s,
+            counts_per_rev,
+            vel_limit_rpm,
+            acc_limit: rpm_to_rad_s(vel_limit_rpm) * 10.0,
+            jerk_limit: rpm_to_rad_s(vel_limit_rpm) * 100.0,
+            ready_vel_error_rpm: DEFAULT_READY_VEL_ERROR_RPM,
+            pos_kp: DEFAULT_POS_KP,
+            pos_ki: DEFAULT_POS_KI,
+            pos_correction_limit_rpm: DEFAULT_POS_CORRECTION_LIMIT_RPM,
+            // The encoder velocity is quantized and the setpoint jumps when the velocity
+            // command is changed, so the derivative is on the measurement and filtered
+            vel_anti_windup: ANTI_WINDUP_CONDITIONAL_INTEGRATION,
+            vel_tracking_gain: DEFAULT_TRACKING_GAIN,
+            vel_derivative_mode: DERIVATIVE_ON_MEASUREMENT,
+            vel_derivative_filter_s: DEFAULT_DERIVATIVE_FILTER_PERIODS * period_s,
+            vel_setpoint_weight_p: 1.0,
+            vel_setpoint_weight_d: 1.0,
+            // The motors reach about the velocity limit at full duty cycle
+            vel_pid_gains: [default_velocity_gains(vel_limit_rpm); 2],
+        }
+    }
+
+    pub fn pid_gains(&self, id: MotorId) -> &PidGains {
+        &self.vel_pid_gains[id as usize]
+    }
+
+    pub fn position_controller(&self) -> PositionController {
+        PositionController::new(
+            self.pos_kp,
+            self.pos_ki,
+            rpm_to_rad_s(self.pos_correction_limit_rpm),
+        )
+    }
+
+    pub fn velocity_pid_config(&self) -> PidConfig {
+        let anti_windup = match self.vel_anti_windup {
+            ANTI_WINDUP_CONDITIONAL_INTEGRATION => AntiWindup::ConditionalIntegration,
+            ANTI_WINDUP_BACK_CALCULATION => AntiWindup::BackCalculation(self.vel_tracking_gain),
+            _ => AntiWindup::None,
+        };
+        let derivative_mode = match self.vel_derivative_mode {
+            DERIVATIVE_ON_MEASUREMENT => DerivativeMode::OnMeasurement,
+            _ => DerivativeMode::OnError,
+        };
+
+        PidConfig {
+            anti_windup,
+            derivative_mode,
+            derivative_filter_tau_s: self.vel_derivative_filter_s,
+            setpoint_weight_p: self.vel_setpoint_weight_p,
+            setpoint_weight_d: self.vel_setpoint_weight_d,
+        }
+    }
+
+    /// Velocity controller of the motor, the output is the duty cycle
+    pub fn velocity_pid(&self, id: MotorId) -> Pid {
+        let gains = self.pid_gains(id);
+        let mut pid = Pid::new(gains.kp, gains.ki, gains.kd, 1.0);
+        pid.set_feed_forward_gains(gains.kff_vel, gains.kff_acc);
+        pid.set_config(&self.velocity_pid_config());
+        pid
+    }
+}
+
+struct ParamDef {
+    id: ParamId,
+    name: &'static str,
+    unit: ParamUnit,
+    min: ParamValue,
+    max: ParamValue,
+    requires_reboot: bool,
+    get: fn(&DeviceParams) -> ParamValue,
+    // The value is checked against the range before it is set, so it has the right variant
+    set: fn(&mut DeviceParams, ParamValue),
+}
+
+// The table is listed in this order, a new parameter takes a new id, the ids of removed
+// parameters are not reused. The ranges of the gains are the same as `pid::validate_pid_gains`
+static PARAM_DEFS: [ParamDef; 25] = [
+    ParamDef {
+        id: PARAM_CONTROL_PERIOD,
+        name: "control_period",
+        unit: ParamUnit::Second,
+        min: ParamValue::F32(0.001),
+        max: ParamValue::F32(0.02),
+        requires_reboot: true,
+        get: |x| ParamValue::F32(x.period_s),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.period_s = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_ENCODER_COUNTS_PER_REV,
+        name: "encoder_counts_per_rev",
+        unit: ParamUnit::CountsPerRev,
+        min: ParamValue::U32(1),
+        max: ParamValue::U32(u16::MAX as u32),
+        requires_reboot: true,
+        get: |x| ParamValue::U32(x.counts_per_rev as u32),
+        set: |x, value| {
+            if let ParamValue::U32(value) = value {
+                x.counts_per_rev = value as u16;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_VEL_LIMIT,
+        name: "vel_limit",
+        unit: ParamUnit::Rpm,
+        min: ParamValue::F32(1.0),
+        max: ParamValue::F32(6000.0),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_limit_rpm),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_limit_rpm = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_ACC_LIMIT,
+        name: "acc_limit",
+        unit: ParamUnit::RadPerSec2,
+        min: ParamValue::F32(1.0),
+        max: ParamValue::F32(1.0e5),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.acc_limit),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.acc_limit = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_JERK_LIMIT,
+        name: "jerk_limit",
+        unit: ParamUnit::RadPerSec3,
+        min: ParamValue::F32(1.0),
+        max: ParamValue::F32(1.0e7),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.jerk_limit),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.jerk_limit = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_READY_VEL_ERROR,
+        name: "ready_vel_error",
+        unit: ParamUnit::Rpm,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(1000.0),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.ready_vel_error_rpm),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.ready_vel_error_rpm = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_POS_KP,
+        name: "pos_kp",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(100.0),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.pos_kp),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.pos_kp = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_POS_KI,
+        name: "pos_ki",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(100.0),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.pos_ki),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.pos_ki = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_POS_CORRECTION_LIMIT,
+        name: "pos_correction_limit",
+        unit: ParamUnit::Rpm,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(6000.0),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.pos_correction_limit_rpm),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.pos_correction_limit_rpm = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_VEL_ANTI_WINDUP,
+        name: "vel_anti_windup",
+        unit: ParamUnit::None,
+        min: ParamValue::U32(0),
+        max: ParamValue::U32(ANTI_WINDUP_BACK_CALCULATION),
+        requires_reboot: false,
+        get: |x| ParamValue::U32(x.vel_anti_windup),
+        set: |x, value| {
+            if let ParamValue::U32(value) = value {
+                x.vel_anti_windup = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_VEL_TRACKING_GAIN,
+        name: "vel_tracking_gain",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(1.0e4),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_tracking_gain),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_tracking_gain = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_VEL_DERIVATIVE_MODE,
+        name: "vel_derivative_mode",
+        unit: ParamUnit::None,
+        min: ParamValue::U32(0),
+        max: ParamValue::U32(DERIVATIVE_ON_MEASUREMENT),
+        requires_reboot: false,
+        get: |x| ParamValue::U32(x.vel_derivative_mode),
+        set: |x, value| {
+            if let ParamValue::U32(value) = value {
+                x.vel_derivative_mode = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_VEL_DERIVATIVE_FILTER,
+        name: "vel_derivative_filter",
+        unit: ParamUnit::Second,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(1.0),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_derivative_filter_s),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_derivative_filter_s = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_VEL_SETPOINT_WEIGHT_P,
+        name: "vel_setpoint_weight_p",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(1.0),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_setpoint_weight_p),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_setpoint_weight_p = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_VEL_SETPOINT_WEIGHT_D,
+        name: "vel_setpoint_weight_d",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(1.0),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_setpoint_weight_d),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_setpoint_weight_d = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_LEFT_VEL_KP,
+        name: "left_vel_kp",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[0].kp),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[0].kp = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_LEFT_VEL_KI,
+        name: "left_vel_ki",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[0].ki),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[0].ki = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_LEFT_VEL_KD,
+        name: "left_vel_kd",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[0].kd),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[0].kd = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_LEFT_VEL_KFF_VEL,
+        name: "left_vel_kff_vel",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(f32::MIN),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[0].kff_vel),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[0].kff_vel = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_LEFT_VEL_KFF_ACC,
+        name: "left_vel_kff_acc",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(f32::MIN),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[0].kff_acc),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[0].kff_acc = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_RIGHT_VEL_KP,
+        name: "right_vel_kp",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[1].kp),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[1].kp = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_RIGHT_VEL_KI,
+        name: "right_vel_ki",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[1].ki),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[1].ki = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_RIGHT_VEL_KD,
+        name: "right_vel_kd",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(0.0),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[1].kd),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[1].kd = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_RIGHT_VEL_KFF_VEL,
+        name: "right_vel_kff_vel",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(f32::MIN),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[1].kff_vel),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[1].kff_vel = value;
+            }
+        },
+    },
+    ParamDef {
+        id: PARAM_RIGHT_VEL_KFF_ACC,
+        name: "right_vel_kff_acc",
+        unit: ParamUnit::None,
+        min: ParamValue::F32(f32::MIN),
+        max: ParamValue::F32(f32::MAX),
+        requires_reboot: false,
+        get: |x| ParamValue::F32(x.vel_pid_gains[1].kff_acc),
+        set: |x, value| {
+            if let ParamValue::F32(value) = value {
+                x.vel_pid_gains[1].kff_acc = value;
+            }
+        },
+    },
+];
+
+impl ParamDef {
+    fn check(&self, value: ParamValue) -> ParamSetResult {
+        // NaN is never in range
+        let in_range = match (value, self.min, self.max) {
+            (ParamValue::F32(x), ParamValue::F32(min), ParamValue::F32(max)) => {
+                (min..=max).contains(&x)
+            }
+            (ParamValue::U32(x), ParamValue::U32(min), ParamValue::U32(max)) => {
+                (min..=max).contains(&x)
+            }
+            _ => return Err(ParamError::TypeMismatch(self.id)),
+        };
+
+        if in_range {
+            Ok(())
+        } else {
+            Err(ParamError::OutOfRange(self.id))
+        }
+    }
+}
+
+/// Holds the current values of the parameters and describes them to the host, so a new
+/// parameter only needs an entry in `PARAM_DEFS` and no protocol change
+pub struct ParamRegistry {
+    defaults: DeviceParams,
+    values: DeviceParams,
+}
+
+impl ParamRegistry {
+    pub const fn new(defaults: DeviceParams) -> Self {
+        Self {
+            defaults,
+            values: defaults,
+        }
+    }
+
+    pub fn values(&self) -> &DeviceParams {
+        &self.values
+    }
+
+    pub fn defaults(&self) -> &DeviceParams {
+        &self.defaults
+    }
+
+    /// Restores the defaults of all the parameters
+    pub fn reset(&mut self) {
+        self.values = self.defaults;
+    }
+
+    /// Returns the id and current value of each parameter in the order of `PARAM_DEFS`
+    pub fn entries(&self) -> impl Iterator<Item = (ParamId, ParamValue)> + '_ {
+        PARAM_DEFS.iter().map(|x| (x.id, (x.get)(&self.values)))
+    }
+
+    /// Returns the description of the parameter at `index` of the table, `None` if the index
+    /// is past the end
+    pub fn info(&self, index: u16) -> Option<ParamInfo> {
+        let def = PARAM_DEFS.get(index as usize)?;
+        Some(ParamInfo {
+            id: def.id,
+            name: String::try_from(def.name).unwrap_or_default(),
+            unit: def.unit,
+            min: def.min,
+            max: def.max,
+            default: (def.get)(&self.defaults),
+            requires_reboot: def.requires_reboot,
+        })
+    }
+
+    pub fn get(&self, id: ParamId) -> ParamGetResult {
+        let def = Self::find(id)?;
+        Ok((def.get)(&self.values))
+    }
+
+    /// Checks the value against the range of the parameter, the current value is kept if it
+    /// is rejected
+    pub fn set(&mut self, id: ParamId, value: ParamValue) -> ParamSetResult {
+        let def = Self::find(id)?;
+        def.check(value)?;
+        (def.set)(&mut self.values, value);
+        Ok(())
+    }
+
+    /// The gains are checked with `pid::validate_pid_gains` before they are set, it accepts
+    /// the same range as the gain parameters
+    pub fn set_pid_gains(&mut self, id: MotorId, gains: &PidGains) {
+        self.values.vel_pid_gains[id as usize] = *gains;
+    }
+
+    fn find(id: ParamId) -> Result<&'static ParamDef, ParamError> {
+        PARAM_DEFS
+            .iter()
+            .find(|x| x.id == id)
+            .ok_or(ParamError::UnknownParam(id))
+    }
+}
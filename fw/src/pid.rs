@@ -1,12 +1,78 @@
+use protocol::{MotorId, PidGains, PidGainsError, PidGainsSetResult};
+
+// Time constant of the derivative filter of the velocity controller in control loop periods
+pub const DEFAULT_DERIVATIVE_FILTER_PERIODS: f32 = 4.0;
+// Tracking gain of back-calculation, about 1 / sqrt(Ti * Td) of the default gains, unit: 1/s
+pub const DEFAULT_TRACKING_GAIN: f32 = 40.0;
+
+/// Default gains of the velocity controller of a motor that reaches `vel_max_rpm` at full duty
+/// cycle, the output of the controller is the duty cycle, so the velocity feed-forward gain is
+/// the inverse of that velocity
+pub const fn default_velocity_gains(vel_max_rpm: f32) -> PidGains {
+    PidGains {
+        kp: 0.00006,
+        ki: 0.00124,
+        kd: 0.000000728,
+        kff_vel: 1.0 / vel_max_rpm,
+        kff_acc: 0.0,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum AntiWindup {
+    /// Integrate error without limit
+    #[default]
+    None,
+    /// Stop integrating when output is saturated and error drives output further into saturation
+    ConditionalIntegration,
+    /// Feed the difference between saturated and unsaturated output back to integral term,
+    /// the value is the tracking gain (1 / tracking time constant)
+    BackCalculation(f32),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum DerivativeMode {
+    /// Differentiate the (weighted) error, a setpoint step causes derivative kick
+    #[default]
+    OnError,
+    /// Differentiate the negative measurement, setpoint changes don't affect derivative term
+    OnMeasurement,
+}
+
+/// Modes of the controller, they are changed at runtime with the parameters, see `fw::params`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PidConfig {
+    pub anti_windup: AntiWindup,
+    pub derivative_mode: DerivativeMode,
+    // Time constant of the derivative filter, unit: s
+    pub derivative_filter_tau_s: f32,
+    pub setpoint_weight_p: f32,
+    pub setpoint_weight_d: f32,
+}
+
 pub struct Pid {
     kp: f32,
     ki: f32,
     kd: f32,
+    kff_vel: f32,
+    kff_acc: f32,
     target_velocity_rpm: f32,
+    target_acceleration_rpm_s: f32,
     curr_error: f32,
-    prev_error: f32,
-    accumulated_error: f32,
+    // Integral term is stored in output unit (`ki` is already applied), so changing `ki` at
+    // runtime doesn't cause a bump in the output
+    integral_term: f32,
     output_limit: f32,
+    anti_windup: AntiWindup,
+    derivative_mode: DerivativeMode,
+    // Time constant of first-order low pass filter on derivative term, 0 disables the filter
+    derivative_filter_tau_s: f32,
+    filtered_derivative: f32,
+    prev_derivative_input: f32,
+    // Setpoint weights of proportional and derivative term
+    setpoint_weight_p: f32,
+    setpoint_weight_d: f32,
+    initialized: bool,
 }
 
 impl Pid {
@@ -15,37 +81,353 @@ impl Pid {
             kp,
             ki,
             kd,
+            kff_vel: 0.0,
+            kff_acc: 0.0,
             target_velocity_rpm: 0.0,
+            target_acceleration_rpm_s: 0.0,
             curr_error: 0.0,
-            prev_error: 0.0,
-            accumulated_error: 0.0,
+            integral_term: 0.0,
             output_limit,
+            anti_windup: AntiWindup::default(),
+            derivative_mode: DerivativeMode::default(),
+            derivative_filter_tau_s: 0.0,
+            filtered_derivative: 0.0,
+            prev_derivative_input: 0.0,
+            setpoint_weight_p: 1.0,
+            setpoint_weight_d: 1.0,
+            initialized: false,
+        }
+    }
+
+    pub fn get_gains(&self) -> PidGains {
+        PidGains {
+            kp: self.kp,
+            ki: self.ki,
+            kd: self.kd,
+            kff_vel: self.kff_vel,
+            kff_acc: self.kff_acc,
+        }
+    }
+
+    pub fn set_gains(&mut self, gains: &PidGains) {
+        self.kp = gains.kp;
+        self.ki = gains.ki;
+        self.kd = gains.kd;
+        self.kff_vel = gains.kff_vel;
+        self.kff_acc = gains.kff_acc;
+    }
+
+    pub fn set_feed_forward_gains(&mut self, kff_vel: f32, kff_acc: f32) {
+        self.kff_vel = kff_vel;
+        self.kff_acc = kff_acc;
+    }
+
+    pub fn set_anti_windup(&mut self, anti_windup: AntiWindup) {
+        self.anti_windup = anti_windup;
+    }
+
+    pub fn set_derivative_mode(&mut self, derivative_mode: DerivativeMode) {
+        self.derivative_mode = derivative_mode;
+        // Derivative input is changed, restart from current measurement to prevent a kick
+        self.initialized = false;
+    }
+
+    pub fn set_derivative_filter(&mut self, tau_s: f32) {
+        self.derivative_filter_tau_s = tau_s.max(0.0);
+    }
+
+    pub fn set_setpoint_weights(&mut self, weight_p: f32, weight_d: f32) {
+        self.setpoint_weight_p = weight_p;
+        self.setpoint_weight_d = weight_d;
+    }
+
+    pub fn get_config(&self) -> PidConfig {
+        PidConfig {
+            anti_windup: self.anti_windup,
+            derivative_mode: self.derivative_mode,
+            derivative_filter_tau_s: self.derivative_filter_tau_s,
+            setpoint_weight_p: self.setpoint_weight_p,
+            setpoint_weight_d: self.setpoint_weight_d,
         }
     }
 
+    pub fn set_config(&mut self, config: &PidConfig) {
+        self.set_anti_windup(config.anti_windup);
+        // Only restart the derivative when the mode is changed
+        if config.derivative_mode != self.derivative_mode {
+            self.set_derivative_mode(config.derivative_mode);
+        }
+        self.set_derivative_filter(config.derivative_filter_tau_s);
+        self.set_setpoint_weights(config.setpoint_weight_p, config.setpoint_weight_d);
+    }
+
+    /// Clears the controller memory (integral term, derivative filter), it should be called
+    /// when the control mode is changed, so the history of previous mode doesn't affect the
+    /// new mode
+    pub fn reset(&mut self) {
+        self.curr_error = 0.0;
+        self.integral_term = 0.0;
+        self.filtered_derivative = 0.0;
+        self.prev_derivative_input = 0.0;
+        self.initialized = false;
+    }
+
     pub(crate) fn set_target_velocity(&mut self, target_velocity_rpm: f32) {
         self.target_velocity_rpm = target_velocity_rpm;
     }
 
+    pub(crate) fn set_target_acceleration(&mut self, target_acceleration_rpm_s: f32) {
+        self.target_acceleration_rpm_s = target_acceleration_rpm_s;
+    }
+
     pub(crate) fn get_error(&self) -> f32 {
         self.curr_error
     }
 
     pub(crate) fn run(&mut self, act_velocity_prm: f32, period_s: f32) -> f32 {
-        self.prev_error = self.curr_error;
-        self.curr_error = self.target_velocity_rpm - act_velocity_prm;
-        self.accumulated_error += self.curr_error * period_s;
-
-        let mut control_effort = self.kp * self.curr_error
-            + self.ki * self.accumulated_error
-            + self.kd * (self.curr_error - self.prev_error) / period_s;
-
-        if control_effort > self.output_limit {
-            control_effort = self.output_limit
-        } else if control_effort < -self.output_limit {
-            control_effort = -self.output_limit;
+        let target = self.target_velocity_rpm;
+        self.curr_error = target - act_velocity_prm;
+
+        // Proportional term with setpoint weighting
+        let proportional_error = self.setpoint_weight_p * target - act_velocity_prm;
+
+        // Derivative term, the input is filtered by a first-order low pass filter. On the first
+        // run, previous input is not available, so the derivative is treated as 0
+        let derivative_input = match self.derivative_mode {
+            DerivativeMode::OnError => self.setpoint_weight_d * target - act_velocity_prm,
+            DerivativeMode::OnMeasurement => -act_velocity_prm,
+        };
+        let raw_derivative = if self.initialized {
+            (derivative_input - self.prev_derivative_input) / period_s
+        } else {
+            0.0
+        };
+        self.prev_derivative_input = derivative_input;
+        self.initialized = true;
+
+        let alpha = period_s / (self.derivative_filter_tau_s + period_s);
+        self.filtered_derivative += alpha * (raw_derivative - self.filtered_derivative);
+
+        // Feed-forward terms, the PID only needs to correct the remaining error instead of
+        // catching up with the reference during acceleration phase
+        let feed_forward = self.kff_vel * target + self.kff_acc * self.target_acceleration_rpm_s;
+
+        let integral_term = self.integral_term + self.ki * self.curr_error * period_s;
+        let control_effort = self.kp * proportional_error
+            + integral_term
+            + self.kd * self.filtered_derivative
+            + feed_forward;
+
+        let saturated_effort = control_effort.clamp(-self.output_limit, self.output_limit);
+
+        self.integral_term = match self.anti_windup {
+            AntiWindup::None => integral_term,
+            AntiWindup::ConditionalIntegration => {
+                // Keep integrating when the output is not saturated or when the error pulls
+                // output back from saturation
+                let saturated = saturated_effort != control_effort;
+                if !saturated || control_effort * self.curr_error < 0.0 {
+                    integral_term
+                } else {
+                    self.integral_term
+                }
+            }
+            AntiWindup::BackCalculation(tracking_gain) => {
+                integral_term + tracking_gain * (saturated_effort - control_effort) * period_s
+            }
+        };
+
+        saturated_effort
+    }
+}
+
+pub fn validate_pid_gains(id: MotorId, gains: &PidGains) -> PidGainsSetResult {
+    let values = [gains.kp, gains.ki, gains.kd, gains.kff_vel, gains.kff_acc];
+    if values.iter().any(|x| !x.is_finite()) {
+        return Err(PidGainsError::NonFiniteGain(id));
+    }
+
+    // Feed-forward gains can be negative to compensate the plant, but negative feedback
+    // gains make the loop unstable
+    if gains.kp < 0.0 || gains.ki < 0.0 || gains.kd < 0.0 {
+        return Err(PidGainsError::NegativeGain(id));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_S: f32 = 0.01;
+
+    // Runs the controller with a constant target and measurement, the error saturates the output
+    fn wind_up(pid: &mut Pid, cycles: usize) {
+        pid.set_target_velocity(10.0);
+        for _ in 0..cycles {
+            assert_eq!(pid.run(0.0, PERIOD_S), 1.0);
+        }
+    }
+
+    #[test]
+    fn integral_winds_up_without_anti_windup() {
+        let mut pid = Pid::new(0.05, 10.0, 0.0, 1.0);
+        wind_up(&mut pid, 100);
+
+        assert!((pid.integral_term - 100.0).abs() < 1e-3);
+        // The output stays saturated after the error is gone
+        pid.set_target_velocity(0.0);
+        assert_eq!(pid.run(0.0, PERIOD_S), 1.0);
+    }
+
+    #[test]
+    fn conditional_integration_stops_integrating_under_saturation() {
+        let mut pid = Pid::new(0.05, 10.0, 0.0, 1.0);
+        pid.set_anti_windup(AntiWindup::ConditionalIntegration);
+        wind_up(&mut pid, 100);
+
+        assert_eq!(pid.integral_term, 0.0);
+        pid.set_target_velocity(0.0);
+        assert_eq!(pid.run(0.0, PERIOD_S), 0.0);
+    }
+
+    #[test]
+    fn conditional_integration_integrates_when_error_leaves_saturation() {
+        let mut pid = Pid::new(0.05, 10.0, 0.0, 1.0);
+        pid.set_anti_windup(AntiWindup::ConditionalIntegration);
+        pid.integral_term = 2.0;
+
+        // The output is saturated, but the negative error pulls it back
+        pid.set_target_velocity(0.0);
+        assert_eq!(pid.run(1.0, PERIOD_S), 1.0);
+        assert!((pid.integral_term - (2.0 - 10.0 * PERIOD_S)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn back_calculation_bounds_integral_under_saturation() {
+        let (kp, ki, tracking_gain, error) = (0.05, 10.0, 100.0, 10.0);
+        let mut pid = Pid::new(kp, ki, 0.0, 1.0);
+        pid.set_anti_windup(AntiWindup::BackCalculation(tracking_gain));
+        wind_up(&mut pid, 100);
+
+        // In steady state, the integration of the error is balanced by the tracking term:
+        // ki * error = tracking_gain * (control_effort - output_limit), the control effort
+        // includes the integration of the current cycle
+        let control_effort = 1.0 + ki * error / tracking_gain;
+        let expected = control_effort - kp * error - ki * error * PERIOD_S;
+        assert!((pid.integral_term - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn derivative_on_error_kicks_on_setpoint_step() {
+        let mut pid = Pid::new(0.0, 0.0, 1.0, 1.0e6);
+        pid.run(0.0, PERIOD_S);
+
+        pid.set_target_velocity(10.0);
+        assert!((pid.run(0.0, PERIOD_S) - 10.0 / PERIOD_S).abs() < 1e-2);
+    }
+
+    #[test]
+    fn derivative_on_measurement_ignores_setpoint_step() {
+        let mut pid = Pid::new(0.0, 0.0, 1.0, 1.0e6);
+        pid.set_derivative_mode(DerivativeMode::OnMeasurement);
+        pid.run(0.0, PERIOD_S);
+
+        pid.set_target_velocity(10.0);
+        assert_eq!(pid.run(0.0, PERIOD_S), 0.0);
+        // It still reacts to the change of the measurement
+        assert!((pid.run(1.0, PERIOD_S) + 1.0 / PERIOD_S).abs() < 1e-2);
+    }
+
+    #[test]
+    fn derivative_filter_has_time_constant_tau() {
+        let tau_s = 4.0 * PERIOD_S;
+        let slope = 100.0;
+        let mut pid = Pid::new(0.0, 0.0, 1.0, 1.0e6);
+        pid.set_derivative_mode(DerivativeMode::OnMeasurement);
+        pid.set_derivative_filter(tau_s);
+        pid.run(0.0, PERIOD_S);
+
+        // The derivative of a falling measurement is a step of `slope`, the filter output follows
+        // the step response of the discrete first-order low pass filter
+        let alpha = PERIOD_S / (tau_s + PERIOD_S);
+        for i in 1..=20 {
+            let output = pid.run(-slope * PERIOD_S * i as f32, PERIOD_S);
+            let expected = slope * (1.0 - (1.0 - alpha).powi(i));
+            assert!(
+                (output - expected).abs() < 1e-2,
+                "cycle {i}: {output} != {expected}"
+            );
         }
+    }
+
+    #[test]
+    fn derivative_without_filter_follows_raw_derivative() {
+        let mut pid = Pid::new(0.0, 0.0, 1.0, 1.0e6);
+        pid.set_derivative_mode(DerivativeMode::OnMeasurement);
+        pid.set_derivative_filter(-1.0);
+        pid.run(0.0, PERIOD_S);
+
+        assert!((pid.run(-1.0, PERIOD_S) - 1.0 / PERIOD_S).abs() < 1e-2);
+    }
+
+    #[test]
+    fn setpoint_weights_scale_target_of_proportional_and_derivative_terms() {
+        let mut pid = Pid::new(1.0, 0.0, 0.0, 1.0e6);
+        pid.set_setpoint_weights(0.5, 1.0);
+        pid.set_target_velocity(10.0);
+        assert!((pid.run(2.0, PERIOD_S) - (0.5 * 10.0 - 2.0)).abs() < 1e-6);
+
+        let mut pid = Pid::new(0.0, 0.0, 1.0, 1.0e6);
+        pid.set_setpoint_weights(1.0, 0.25);
+        pid.run(0.0, PERIOD_S);
+        pid.set_target_velocity(10.0);
+        assert!((pid.run(0.0, PERIOD_S) - 0.25 * 10.0 / PERIOD_S).abs() < 1e-2);
+    }
+
+    #[test]
+    fn setpoint_weights_do_not_affect_integral_term() {
+        let mut pid = Pid::new(0.0, 1.0, 0.0, 1.0e6);
+        pid.set_setpoint_weights(0.0, 0.0);
+        pid.set_target_velocity(10.0);
+
+        assert!((pid.run(2.0, PERIOD_S) - 8.0 * PERIOD_S).abs() < 1e-6);
+    }
+
+    #[test]
+    fn feed_forward_reaches_output() {
+        let (kff_vel, kff_acc) = (0.01, 0.001);
+        let mut pid = Pid::new(0.0, 0.0, 0.0, 1.0e6);
+        pid.set_feed_forward_gains(kff_vel, kff_acc);
+        pid.set_target_velocity(100.0);
+        pid.set_target_acceleration(50.0);
+
+        // Without feedback gains the output is only the feed-forward of the reference, the
+        // measurement doesn't change it
+        let expected = kff_vel * 100.0 + kff_acc * 50.0;
+        assert!((pid.run(0.0, PERIOD_S) - expected).abs() < 1e-6);
+        assert!((pid.run(100.0, PERIOD_S) - expected).abs() < 1e-6);
+
+        // It adds to the feedback terms
+        let mut pid = Pid::new(0.1, 0.0, 0.0, 1.0e6);
+        pid.set_feed_forward_gains(kff_vel, 0.0);
+        pid.set_target_velocity(100.0);
+        assert!((pid.run(90.0, PERIOD_S) - (0.1 * 10.0 + kff_vel * 100.0)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn config_is_applied() {
+        let config = PidConfig {
+            anti_windup: AntiWindup::BackCalculation(20.0),
+            derivative_mode: DerivativeMode::OnMeasurement,
+            derivative_filter_tau_s: 0.02,
+            setpoint_weight_p: 0.8,
+            setpoint_weight_d: 0.0,
+        };
+        let mut pid = Pid::new(1.0, 1.0, 1.0, 1.0);
+        pid.set_config(&config);
 
-        control_effort
+        assert_eq!(pid.get_config(), config);
     }
 }
@@ -0,0 +1,63 @@
+// Defaults of the gains, unit: 1/s, 1/s^2, and of the limit of the velocity correction, unit:
+// rpm. They can be changed at runtime with the parameters, see `fw::params`
+pub const DEFAULT_POS_KP: f32 = 5.0;
+pub const DEFAULT_POS_KI: f32 = 0.5;
+pub const DEFAULT_POS_CORRECTION_LIMIT_RPM: f32 = 300.0;
+
+pub struct PositionController {
+    kp: f32,
+    ki: f32,
+    following_error: f32,
+    accumulated_error: f32,
+    output_limit: f32,
+}
+
+impl PositionController {
+    pub fn new(kp: f32, ki: f32, output_limit: f32) -> Self {
+        Self {
+            kp,
+            ki,
+            following_error: 0.0,
+            accumulated_error: 0.0,
+            output_limit,
+        }
+    }
+
+    pub fn set_gains(&mut self, kp: f32, ki: f32) {
+        self.kp = kp;
+        self.ki = ki;
+    }
+
+    pub fn set_output_limit(&mut self, output_limit: f32) {
+        self.output_limit = output_limit;
+    }
+
+    pub fn get_following_error(&self) -> f32 {
+        self.following_error
+    }
+
+    pub fn reset(&mut self) {
+        self.following_error = 0.0;
+        self.accumulated_error = 0.0;
+    }
+
+    /// Returns the velocity correction (rad/s) that should be added on top of the
+    /// interpolated velocity to pull actual position back to target position
+    pub fn run(&mut self, target_pos_rad: f32, act_pos_rad: f32, period_s: f32) -> f32 {
+        self.following_error = target_pos_rad - act_pos_rad;
+
+        let accumulated_error = self.accumulated_error + self.following_error * period_s;
+        let correction = self.kp * self.following_error + self.ki * accumulated_error;
+
+        // Only keep integrating when the output is not saturated, otherwise the integral
+        // term keeps growing and causes overshoot when the error changes sign
+        if correction > self.output_limit {
+            self.output_limit
+        } else if correction < -self.output_limit {
+            -self.output_limit
+        } else {
+            self.accumulated_error = accumulated_error;
+            correction
+        }
+    }
+}
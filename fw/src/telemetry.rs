@@ -0,0 +1,114 @@
+use heapless::Vec;
+use protocol::{
+    MotorDataBatch, MotorId, MotorProcessData, TelemetryConfig, TelemetryConfigError,
+    TelemetrySample, TelemetrySetResult, MAX_TELEMETRY_BATCH_LEN, MAX_TELEMETRY_DECIMATION,
+};
+
+/// Every control cycle, 10 samples per message
+pub const DEFAULT_TELEMETRY_CONFIG: TelemetryConfig = TelemetryConfig {
+    decimation: 1,
+    batch_len: 10,
+};
+
+// A batch that is not full is sent once its first sample is older than this, unit: us
+pub const MAX_BATCH_AGE_US: u64 = 100_000;
+
+pub fn validate_telemetry_config(config: &TelemetryConfig) -> TelemetrySetResult {
+    if !(1..=MAX_TELEMETRY_DECIMATION).contains(&config.decimation) {
+        return Err(TelemetryConfigError::Decimation(config.decimation));
+    }
+    if !(1..=MAX_TELEMETRY_BATCH_LEN).contains(&(config.batch_len as usize)) {
+        return Err(TelemetryConfigError::BatchLen(config.batch_len));
+    }
+
+    Ok(())
+}
+
+/// Picks the samples of one motor from the control loop and numbers them. It runs in the
+/// control loop, the samples are buffered until the publisher sends them
+pub struct TelemetryRecorder {
+    decimation: u16,
+    cycle: u16,
+    next_seq: u32,
+}
+
+impl TelemetryRecorder {
+    pub fn new(config: &TelemetryConfig) -> Self {
+        Self {
+            decimation: config.decimation.max(1),
+            cycle: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// The sequence keeps counting, so the host sees no gap when the rate changes
+    pub fn set_config(&mut self, config: &TelemetryConfig) {
+        self.decimation = config.decimation.max(1);
+        self.cycle = 0;
+    }
+
+    /// Called once per control cycle, returns the sample of every `decimation`-th cycle
+    pub fn record(&mut self, data: &MotorProcessData) -> Option<TelemetrySample> {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        if self.cycle >= self.decimation {
+            self.cycle = 0;
+        }
+        if cycle != 0 {
+            return None;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        Some(TelemetrySample { seq, data: *data })
+    }
+}
+
+/// Collects the samples of one motor into `MotorDataBatchTopic` messages. A batch is sent when
+/// it is full, or when a sample arrives and the first sample of the batch is older than
+/// `MAX_BATCH_AGE_US`. The age is taken from the sample timestamps, so it doesn't need a timer
+pub struct TelemetryBatcher {
+    id: MotorId,
+    batch_len: usize,
+    max_age_ticks: u64,
+    samples: Vec<TelemetrySample, MAX_TELEMETRY_BATCH_LEN>,
+}
+
+impl TelemetryBatcher {
+    /// `tick_hz` is the rate of `MotorProcessData::timestamp`
+    pub fn new(id: MotorId, config: &TelemetryConfig, tick_hz: u32) -> Self {
+        Self {
+            id,
+            batch_len: Self::batch_len(config),
+            max_age_ticks: MAX_BATCH_AGE_US * tick_hz as u64 / 1_000_000,
+            samples: Vec::new(),
+        }
+    }
+
+    /// A shorter batch is applied on the next sample
+    pub fn set_config(&mut self, config: &TelemetryConfig) {
+        self.batch_len = Self::batch_len(config);
+    }
+
+    /// Returns the batch that is ready to send
+    pub fn push(&mut self, sample: TelemetrySample) -> Option<MotorDataBatch> {
+        // The batch is never kept full, so there is room
+        let _ = self.samples.push(sample);
+
+        let age_ticks = self.samples.first().map_or(0, |x| {
+            sample.data.timestamp.saturating_sub(x.data.timestamp)
+        });
+        if self.samples.len() >= self.batch_len || age_ticks >= self.max_age_ticks {
+            return Some(MotorDataBatch {
+                id: self.id,
+                samples: core::mem::take(&mut self.samples),
+            });
+        }
+
+        None
+    }
+
+    fn batch_len(config: &TelemetryConfig) -> usize {
+        (config.batch_len as usize).clamp(1, MAX_TELEMETRY_BATCH_LEN)
+    }
+}
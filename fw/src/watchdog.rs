@@ -0,0 +1,107 @@
+use protocol::WatchdogState;
+
+/// Communication watchdog, it is fed by host heartbeats and expires when the heartbeats stop
+/// for longer than the timeout given in the last heartbeat. It only tracks the state, the
+/// caller decides what to do when it expires.
+pub struct CommWatchdog {
+    state: WatchdogState,
+    timeout_us: u64,
+    last_feed_us: u64,
+}
+
+impl Default for CommWatchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommWatchdog {
+    pub fn new() -> Self {
+        Self {
+            state: WatchdogState::Disarmed,
+            timeout_us: 0,
+            last_feed_us: 0,
+        }
+    }
+
+    pub fn get_state(&self) -> WatchdogState {
+        self.state
+    }
+
+    pub fn feed(&mut self, now_us: u64, timeout_ms: u32) {
+        if timeout_ms == 0 {
+            self.state = WatchdogState::Disarmed;
+            return;
+        }
+
+        self.state = WatchdogState::Armed;
+        self.timeout_us = timeout_ms as u64 * 1000;
+        self.last_feed_us = now_us;
+    }
+
+    /// Returns true only in the cycle that the watchdog expires
+    pub fn check(&mut self, now_us: u64) -> bool {
+        if self.state == WatchdogState::Armed
+            && now_us.wrapping_sub(self.last_feed_us) > self.timeout_us
+        {
+            self.state = WatchdogState::Expired;
+            return true;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT_MS: u32 = 100;
+
+    #[test]
+    fn expires_once_after_timeout_of_last_heartbeat() {
+        let mut watchdog = CommWatchdog::new();
+        watchdog.feed(1_000, TIMEOUT_MS);
+        assert_eq!(watchdog.get_state(), WatchdogState::Armed);
+
+        assert!(!watchdog.check(1_000 + 100_000));
+        assert!(watchdog.check(1_000 + 100_001));
+        assert_eq!(watchdog.get_state(), WatchdogState::Expired);
+        // The motors are only halted in the cycle that it expires
+        assert!(!watchdog.check(1_000 + 200_000));
+    }
+
+    #[test]
+    fn heartbeat_restarts_timeout_and_rearms_expired_watchdog() {
+        let mut watchdog = CommWatchdog::new();
+        watchdog.feed(0, TIMEOUT_MS);
+        watchdog.feed(80_000, TIMEOUT_MS);
+        assert!(!watchdog.check(150_000));
+
+        assert!(watchdog.check(180_001));
+        watchdog.feed(200_000, TIMEOUT_MS);
+        assert_eq!(watchdog.get_state(), WatchdogState::Armed);
+        assert!(watchdog.check(300_001));
+    }
+
+    #[test]
+    fn zero_timeout_disarms_watchdog() {
+        let mut watchdog = CommWatchdog::new();
+        assert!(!watchdog.check(u64::MAX / 2));
+
+        watchdog.feed(0, TIMEOUT_MS);
+        watchdog.feed(10_000, 0);
+        assert_eq!(watchdog.get_state(), WatchdogState::Disarmed);
+        assert!(!watchdog.check(1_000_000));
+    }
+
+    #[test]
+    fn timeout_is_measured_across_clock_wrap_around() {
+        let mut watchdog = CommWatchdog::new();
+        let fed_at = u64::MAX - 50_000;
+        watchdog.feed(fed_at, TIMEOUT_MS);
+
+        assert!(!watchdog.check(fed_at.wrapping_add(100_000)));
+        assert!(watchdog.check(fed_at.wrapping_add(100_001)));
+    }
+}
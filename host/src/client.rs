@@ -1,15 +1,95 @@
 use std::convert::Infallible;
+use std::fmt::Display;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
 use postcard_rpc::{
     header::VarSeqKind,
-    host_client::{HostClient, HostErr},
+    host_client::{HostClient, HostErr, MultiSubRxError, test_channels},
     standard_icd::{ERROR_PATH, PingEndpoint, WireError},
 };
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
 use protocol::*;
 
+use crate::tcp;
+use crate::telemetry::TelemetrySubscription;
+
+// Usb ids of the firmware, see `usb_config` in `fw/src/main.rs`
+pub const DEVICE_VENDOR_ID: u16 = 0x16c0;
+pub const DEVICE_PRODUCT_ID: u16 = 0x27dd;
+
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+// Time to wait for `GetDeviceInfo` when connecting
+const DEVICE_INFO_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The device halts the motors if heartbeats stop for this time, several heartbeats are sent
+// within the timeout, so one late heartbeat doesn't halt the motors
+pub const DEFAULT_HEARTBEAT_TIMEOUT_MS: u32 = 500;
+const HEARTBEATS_PER_TIMEOUT: u32 = 5;
+// Heartbeat period when the watchdog is disabled
+const IDLE_HEARTBEAT_PERIOD: Duration = Duration::from_millis(100);
+// A halt aborts the whole command queue of the device at once, the subscription should hold
+// the events of all the queued commands
+const MOTION_EVENT_SUB_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    // Raw usb device, the serial number is used to pick one board when several boards are
+    // connected, `None` matches any serial number
+    Usb {
+        vendor_id: u16,
+        product_id: u16,
+        serial_number: Option<String>,
+    },
+    // Tcp socket address, Ex: `sim_server`
+    Tcp(String),
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Usb {
+            vendor_id: DEVICE_VENDOR_ID,
+            product_id: DEVICE_PRODUCT_ID,
+            serial_number: None,
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Usb {
+                vendor_id,
+                product_id,
+                serial_number,
+            } => write!(
+                f,
+                "usb {:#06x}:{:#06x} {}",
+                vendor_id,
+                product_id,
+                serial_number.as_deref().unwrap_or("*")
+            ),
+            Transport::Tcp(addr) => write!(f, "tcp {addr}"),
+        }
+    }
+}
+
 pub struct Client {
     pub client: HostClient<WireError>,
+    // Sequence id of the next motor command, `INTERNAL_SEQ_ID` is reserved for the device
+    next_seq_id: AtomicU32,
+    heartbeat_timeout_ms: Arc<AtomicU32>,
+    heartbeat_task: JoinHandle<()>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.heartbeat_task.abort();
+    }
 }
 
 #[derive(Debug)]
@@ -18,6 +98,56 @@ pub enum ClientError<E> {
     Endpoint(E),
 }
 
+#[derive(Debug)]
+pub enum ConnectError {
+    // The transport can't be opened (Ex: the usb device is not found, tcp connection refused)
+    Transport(String),
+    Comms(HostErr<WireError>),
+    // The device doesn't answer `GetDeviceInfo` in time, or it doesn't know the endpoint
+    // (Ex: the firmware is built before the handshake is added)
+    UnknownDevice,
+    // The device is built with a different `protocol` crate
+    Incompatible {
+        expected_hash: u64,
+        device: Box<DeviceInfo>,
+    },
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Transport(msg) => write!(f, "{msg}"),
+            ConnectError::Comms(e) => write!(f, "Communication error: {e:?}"),
+            ConnectError::UnknownDevice => {
+                write!(f, "The device doesn't report its info, update the firmware")
+            }
+            ConnectError::Incompatible {
+                expected_hash,
+                device,
+            } => write!(
+                f,
+                "The device is not compatible, protocol hash: {:#018x}, expected: {:#018x}, \
+                 device: {} {} ({}), update the firmware or the host",
+                device.schema_hash,
+                expected_hash,
+                device.board_name,
+                device.fw_version,
+                device.git_hash
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+#[derive(Debug)]
+pub enum MotionError {
+    // The device doesn't accept the command
+    Rejected(CommandError),
+    // The command is dropped or interrupted by the device (Ex: halt or fault)
+    Aborted(u32),
+}
+
 impl<E> From<HostErr<WireError>> for ClientError<E> {
     fn from(err: HostErr<WireError>) -> Self {
         ClientError::Comms(err)
@@ -41,15 +171,127 @@ impl<T, E> FlattenErr for Result<T, E> {
 
 // ---
 
+// The constructors spawn the io tasks of the client, so they must be called in a tokio
+// runtime. The typed api below is the same for all the transports.
 impl Client {
-    pub fn new(product_name: &str) -> Result<Self, String> {
+    /// Connects to the device and checks that it is built with the same `protocol`, the
+    /// other constructors don't check the device
+    pub async fn connect(transport: &Transport) -> Result<Self, ConnectError> {
+        let client = Self::open(transport).map_err(ConnectError::Transport)?;
+        client.check_compatibility().await?;
+        Ok(client)
+    }
+
+    /// Opens the transport without checking the device
+    pub fn open(transport: &Transport) -> Result<Self, String> {
+        match transport {
+            Transport::Usb {
+                vendor_id,
+                product_id,
+                serial_number,
+            } => Self::new_usb(*vendor_id, *product_id, serial_number.as_deref()),
+            Transport::Tcp(addr) => Self::new_tcp(addr),
+        }
+    }
+
+    pub fn new_usb(
+        vendor_id: u16,
+        product_id: u16,
+        serial_number: Option<&str>,
+    ) -> Result<Self, String> {
         let client = HostClient::try_new_raw_nusb(
-            |d| d.product_string() == Some(product_name),
+            |d| {
+                d.vendor_id() == vendor_id
+                    && d.product_id() == product_id
+                    && serial_number.is_none_or(|x| d.serial_number() == Some(x))
+            },
             ERROR_PATH,
             8,
             VarSeqKind::Seq2,
         )?;
-        Ok(Self { client })
+        Ok(Self::from_host_client(client))
+    }
+
+    pub fn new_tcp(addr: &str) -> Result<Self, String> {
+        let addr = addr
+            .to_socket_addrs()
+            .map_err(|e| format!("Invalid address {addr}, msg: {e}"))?
+            .next()
+            .ok_or(format!("Invalid address {addr}"))?;
+
+        // Connect with std socket, so the constructor doesn't need to be async like the usb one
+        let stream = TcpStream::connect_timeout(&addr, TCP_CONNECT_TIMEOUT)
+            .and_then(|x| x.set_nonblocking(true).map(|_| x))
+            .and_then(tokio::net::TcpStream::from_std)
+            .map_err(|e| format!("Failed to connect to {addr}, msg: {e}"))?;
+
+        let (tx, rx) = tcp::bridge(stream);
+        Ok(Self::new_in_memory(tx, rx))
+    }
+
+    // Each message in the channels is one postcard-rpc frame, Ex: the channels returned by
+    // `sim_server::start_in_process`
+    pub fn new_in_memory(tx: mpsc::Sender<Vec<u8>>, rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        let client = test_channels::new_from_channels(tx, rx, VarSeqKind::Seq2);
+        Self::from_host_client(client)
+    }
+
+    fn from_host_client(client: HostClient<WireError>) -> Self {
+        let heartbeat_timeout_ms = Arc::new(AtomicU32::new(DEFAULT_HEARTBEAT_TIMEOUT_MS));
+        let heartbeat_task =
+            tokio::spawn(heartbeat_task(client.clone(), heartbeat_timeout_ms.clone()));
+
+        Self {
+            client,
+            next_seq_id: AtomicU32::new(INTERNAL_SEQ_ID + 1),
+            heartbeat_timeout_ms,
+            heartbeat_task,
+        }
+    }
+
+    // Returns the first id of `count` consecutive ids. The id wraps around after `u32::MAX`
+    // commands, the ranges that contain the reserved id are skipped
+    fn alloc_seq_ids(&self, count: u32) -> u32 {
+        loop {
+            let first = self.next_seq_id.fetch_add(count, Ordering::Relaxed);
+            if first != INTERNAL_SEQ_ID && first.checked_add(count).is_some() {
+                break first;
+            }
+        }
+    }
+
+    /// Sets the timeout of device watchdog, 0 disables the watchdog. The new timeout is sent
+    /// with the next heartbeat
+    pub fn set_heartbeat_timeout(&self, timeout_ms: u32) {
+        self.heartbeat_timeout_ms
+            .store(timeout_ms, Ordering::Relaxed);
+    }
+
+    /// Reads the device info and compares its schema hash with the `protocol` of this build
+    pub async fn check_compatibility(&self) -> Result<DeviceInfo, ConnectError> {
+        let device = tokio::time::timeout(DEVICE_INFO_TIMEOUT, self.get_device_info())
+            .await
+            .map_err(|_e| ConnectError::UnknownDevice)?
+            .map_err(|e| match e {
+                // The device doesn't know the path of the endpoint
+                ClientError::Comms(HostErr::Wire(_)) => ConnectError::UnknownDevice,
+                ClientError::Comms(x) => ConnectError::Comms(x),
+                ClientError::Endpoint(x) => match x {},
+            })?;
+
+        if device.schema_hash != SCHEMA_HASH {
+            return Err(ConnectError::Incompatible {
+                expected_hash: SCHEMA_HASH,
+                device: Box::new(device),
+            });
+        }
+
+        Ok(device)
+    }
+
+    pub async fn get_device_info(&self) -> Result<DeviceInfo, ClientError<Infallible>> {
+        let info = self.client.send_resp::<GetDeviceInfoEndPoint>(&()).await?;
+        Ok(info)
     }
 
     pub async fn wait_closed(&self) {
@@ -61,14 +303,229 @@ impl Client {
         Ok(val)
     }
 
+    /// Sends the command with a new sequence id and returns the id, the progress of the
+    /// command is reported with the id by `MotionEventTopic`
     pub async fn set_motor_cmd(
         &self,
         id: MotorId,
         cmd: MotorCommand,
-    ) -> Result<(), ClientError<CommandError>> {
+    ) -> Result<u32, ClientError<CommandError>> {
+        let seq_cmd = SequencedCommand {
+            seq_id: self.alloc_seq_ids(1),
+            command: cmd,
+        };
+        self.client
+            .send_resp::<SetMotorCommandEndPoint>(&(id, seq_cmd))
+            .await?
+            .flatten()?;
+        Ok(seq_cmd.seq_id)
+    }
+
+    /// Queues the position commands at once, all or nothing. Returns the sequence id of the
+    /// first command, the following commands take the next ids, and the number of the commands
+    /// that the device has queued
+    pub async fn set_position_batch(
+        &self,
+        id: MotorId,
+        commands: &[PositionCommand],
+    ) -> Result<(u32, u16), ClientError<CommandError>> {
+        let commands = heapless::Vec::from_slice(commands).map_err(|_e| {
+            ClientError::Endpoint(CommandError::BatchTooLarge {
+                id,
+                len: commands.len() as u16,
+                capacity: MAX_POSITION_BATCH_SIZE as u16,
+            })
+        })?;
+        let first_seq_id = self.alloc_seq_ids(commands.len() as u32);
+        let batch = PositionBatch {
+            first_seq_id,
+            commands,
+        };
+        let accepted = self
+            .client
+            .send_resp::<SetPositionBatchEndPoint>(&(id, batch))
+            .await?
+            .flatten()?;
+        Ok((first_seq_id, accepted))
+    }
+
+    /// Sends the position command and waits until the motion is completed. Returns
+    /// `MotionError::Aborted` if the device drops or interrupts the command
+    pub async fn move_and_wait(
+        &self,
+        id: MotorId,
+        cmd: PositionCommand,
+    ) -> Result<(), ClientError<MotionError>> {
+        // Subscribe before the command is sent, so the events of a short motion are not missed
+        let mut sub = self
+            .client
+            .subscribe_multi::<MotionEventTopic>(MOTION_EVENT_SUB_DEPTH)
+            .await
+            .map_err(|_x| ClientError::Comms(HostErr::Closed))?;
+
+        let seq_id = self
+            .set_motor_cmd(id, MotorCommand::PositionCommand(cmd))
+            .await
+            .map_err(|e| match e {
+                ClientError::Comms(x) => ClientError::Comms(x),
+                ClientError::Endpoint(x) => ClientError::Endpoint(MotionError::Rejected(x)),
+            })?;
+
+        loop {
+            let (motor_id, event) = match sub.recv().await {
+                Ok(x) => x,
+                Err(MultiSubRxError::IoClosed) => return Err(ClientError::Comms(HostErr::Closed)),
+                // The events of the other commands are dropped, keep waiting for our command
+                Err(MultiSubRxError::Lagged(_)) => continue,
+            };
+
+            if motor_id != id || event.seq_id != seq_id {
+                continue;
+            }
+
+            match event.kind {
+                MotionEventKind::Completed => return Ok(()),
+                MotionEventKind::Aborted => {
+                    return Err(ClientError::Endpoint(MotionError::Aborted(seq_id)));
+                }
+                MotionEventKind::Accepted | MotionEventKind::Started => (),
+            }
+        }
+    }
+
+    /// Returns the queue and interpolator state of the motor, it is taken in the next control
+    /// loop cycle, so the commands that are sent before are counted
+    pub async fn get_motion_status(
+        &self,
+        id: MotorId,
+    ) -> Result<MotionStatus, ClientError<Infallible>> {
+        let status = self
+            .client
+            .send_resp::<GetMotionStatusEndPoint>(&id)
+            .await?;
+        Ok(status)
+    }
+
+    /// Reads the descriptions of all the parameters in the registry of the device
+    pub async fn list_params(&self) -> Result<Vec<ParamInfo>, ClientError<Infallible>> {
+        let mut params = Vec::new();
+        let mut index = 0_u16;
+        while let Some(info) = self.client.send_resp::<ListParamsEndPoint>(&index).await? {
+            params.push(info);
+            index += 1;
+        }
+        Ok(params)
+    }
+
+    pub async fn get_param(&self, id: ParamId) -> Result<ParamValue, ClientError<ParamError>> {
+        self.client
+            .send_resp::<GetParamEndPoint>(&id)
+            .await?
+            .flatten()
+    }
+
+    /// Sets the parameter, check `ParamInfo::requires_reboot` to know when it takes effect
+    pub async fn set_param(
+        &self,
+        id: ParamId,
+        value: ParamValue,
+    ) -> Result<(), ClientError<ParamError>> {
+        self.client
+            .send_resp::<SetParamEndPoint>(&(id, value))
+            .await?
+            .flatten()
+    }
+
+    /// Subscribes to the samples of both motors, `depth` is the number of batches that can wait
+    /// in the subscription
+    pub async fn subscribe_telemetry(
+        &self,
+        depth: usize,
+    ) -> Result<TelemetrySubscription, ClientError<Infallible>> {
+        // The clock rate of the timestamps
+        let device = self.get_device_info().await?;
+        let sub = self
+            .client
+            .subscribe_multi::<MotorDataBatchTopic>(depth)
+            .await
+            .map_err(|_x| ClientError::Comms(HostErr::Closed))?;
+        Ok(TelemetrySubscription::new(sub, device.tick_hz))
+    }
+
+    /// Sets the sample rate (a decimation of the control loop rate) and the samples per message
+    pub async fn set_telemetry_config(
+        &self,
+        config: TelemetryConfig,
+    ) -> Result<(), ClientError<TelemetryConfigError>> {
         self.client
-            .send_resp::<SetMotorCommandEndPoint>(&(id, cmd))
+            .send_resp::<SetTelemetryConfigEndPoint>(&config)
             .await?
             .flatten()
     }
+
+    /// Saves the current values of the parameters in the flash of the device, the motors have
+    /// to be stopped
+    pub async fn save_params(&self) -> Result<(), ClientError<ParamStoreError>> {
+        self.client
+            .send_resp::<SaveParamsEndPoint>(&())
+            .await?
+            .flatten()
+    }
+
+    /// Replaces the current values with the saved ones
+    pub async fn load_params(&self) -> Result<(), ClientError<ParamStoreError>> {
+        self.client
+            .send_resp::<LoadParamsEndPoint>(&())
+            .await?
+            .flatten()
+    }
+
+    /// Erases the saved values and restores the defaults, the motors have to be stopped
+    pub async fn factory_reset(&self) -> Result<(), ClientError<ParamStoreError>> {
+        self.client
+            .send_resp::<FactoryResetEndPoint>(&())
+            .await?
+            .flatten()
+    }
+
+    pub async fn get_pid_gains(&self, id: MotorId) -> Result<PidGains, ClientError<Infallible>> {
+        let gains = self.client.send_resp::<GetPidGainsEndPoint>(&id).await?;
+        Ok(gains)
+    }
+
+    pub async fn set_pid_gains(
+        &self,
+        id: MotorId,
+        gains: PidGains,
+    ) -> Result<(), ClientError<PidGainsError>> {
+        self.client
+            .send_resp::<SetPidGainsEndPoint>(&(id, gains))
+            .await?
+            .flatten()
+    }
+}
+
+// Heartbeats are sent in background as long as the client is alive, so the device can tell a
+// hanging host app from a working one
+async fn heartbeat_task(client: HostClient<WireError>, timeout_ms: Arc<AtomicU32>) {
+    let mut seq = 0_u8;
+
+    loop {
+        let timeout_ms = timeout_ms.load(Ordering::Relaxed);
+        if client
+            .publish::<HeartbeatTopic>(seq.into(), &Heartbeat { timeout_ms })
+            .await
+            .is_err()
+        {
+            break;
+        }
+        seq = seq.wrapping_add(1);
+
+        let period = if timeout_ms == 0 {
+            IDLE_HEARTBEAT_PERIOD
+        } else {
+            Duration::from_millis((timeout_ms / HEARTBEATS_PER_TIMEOUT).max(1) as u64)
+        };
+        tokio::time::sleep(period).await;
+    }
 }
@@ -1 +1,3 @@
 pub mod client;
+pub mod tcp;
+pub mod telemetry;
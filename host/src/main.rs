@@ -1,13 +1,26 @@
 use std::{sync::Arc, time::Duration};
 
-use host::client::Client;
+use host::client::{Client, Transport};
 use protocol::PositionCommand;
 use tokio::time::interval;
 
+// Connects to the board through usb by default, a tcp address can be given to connect to
+// `sim_server`, Ex: `cargo run -- 127.0.0.1:7878`
 #[tokio::main]
 pub async fn main() {
-    let client = Client::new("stm32-discovery");
-    let client = Arc::new(client.unwrap());
+    let transport = match std::env::args().nth(1) {
+        Some(addr) => Transport::Tcp(addr),
+        None => Transport::default(),
+    };
+    let client = match Client::connect(&transport).await {
+        Ok(client) => Arc::new(client),
+        Err(e) => {
+            println!("Failed to connect to {transport}: {e}");
+            return;
+        }
+    };
+
+    list_params(client.clone()).await;
 
     tokio::join!(
         ping(client.clone()),
@@ -15,10 +28,44 @@ pub async fn main() {
         send_vel_cmd(client.clone()),
         send_pos_cmd(client.clone())
     );
+    move_and_wait(client.clone()).await;
 
     println!("Finished");
 }
 
+async fn list_params(client: Arc<Client>) {
+    println!("Check params");
+
+    let params = match client.list_params().await {
+        Ok(x) => x,
+        Err(e) => {
+            println!("list_params got {e:?}!");
+            return;
+        }
+    };
+
+    for info in params {
+        let value = match client.get_param(info.id).await {
+            Ok(x) => x.to_string(),
+            Err(e) => format!("{e:?}"),
+        };
+        println!(
+            "[{}] {}: {value} {} (default: {}, range: {} ~ {}{})",
+            info.id,
+            info.name,
+            info.unit,
+            info.default,
+            info.min,
+            info.max,
+            if info.requires_reboot {
+                ", requires reboot"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
 async fn ping(client: Arc<Client>) {
     println!("Check ping");
 
@@ -36,19 +83,21 @@ async fn subscribe(client: Arc<Client>) {
     println!("Check topic");
 
     let mut counter = 0_u32;
-    let mut sub = client
-        .client
-        .subscribe_multi::<protocol::MotorProcessDataTopic>(8)
-        .await
-        .unwrap();
+    let mut sub = client.subscribe_telemetry(8).await.unwrap();
 
     loop {
-        let msg = sub.recv().await.unwrap();
+        let (id, sample) = sub.recv().await.unwrap();
         counter += 1;
-        
+
         if counter % 100 == 0 {
             counter = 0;
-            println!("Got message: {msg:?}");
+            let stream = sub.stream(id);
+            println!(
+                "Got sample: {id:?} {sample:?} (received: {}, dropped: {}, clock offset: {:?} s)",
+                stream.received(),
+                stream.dropped(),
+                sub.clock().offset_s()
+            );
         }
     }
 }
@@ -88,9 +137,27 @@ async fn send_pos_cmd(client: Arc<Client>) {
                     displacement: dummy_val / i,
                     vel_max: dummy_val / i,
                     vel_end: dummy_val / i,
+                    ..Default::default()
                 }),
             )
             .await;
         println!("send_pos_cmd got {res:?}!");
     }
 }
+
+async fn move_and_wait(client: Arc<Client>) {
+    println!("Check move and wait");
+
+    let res = client
+        .move_and_wait(
+            protocol::MotorId::Right,
+            PositionCommand {
+                displacement: 100.0,
+                vel_max: 1000.0,
+                vel_end: 0.0,
+                ..Default::default()
+            },
+        )
+        .await;
+    println!("move_and_wait got {res:?}!");
+}
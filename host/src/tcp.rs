@@ -0,0 +1,55 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+// Frames are sent as `u32` little endian length followed by the postcard-rpc frame, TCP is a
+// stream so the frame boundary has to be restored on the other side
+pub const MAX_FRAME_SIZE: usize = 1024;
+const CHANNEL_DEPTH: usize = 64;
+
+/// Bridges a tcp stream to a pair of channels, each channel message is one postcard-rpc frame.
+/// The channels can be used with the `test_channels` implementation of postcard-rpc on both
+/// sides (`HostClient` and `Server`). The channels are closed when the connection is closed.
+pub fn bridge(stream: TcpStream) -> (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer) = stream.into_split();
+    let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(CHANNEL_DEPTH);
+    let (in_tx, in_rx) = mpsc::channel::<Vec<u8>>(CHANNEL_DEPTH);
+
+    tokio::spawn(async move {
+        while let Some(frame) = out_rx.recv().await {
+            let len = (frame.len() as u32).to_le_bytes();
+            if writer.write_all(&len).await.is_err() || writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let mut len = [0_u8; 4];
+            if reader.read_exact(&mut len).await.is_err() {
+                break;
+            }
+
+            let len = u32::from_le_bytes(len) as usize;
+            if len > MAX_FRAME_SIZE {
+                // The peer doesn't talk our framing, drop the connection
+                break;
+            }
+
+            let mut frame = vec![0_u8; len];
+            if reader.read_exact(&mut frame).await.is_err() {
+                break;
+            }
+
+            if in_tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    (out_tx, in_rx)
+}
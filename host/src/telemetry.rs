@@ -0,0 +1,287 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use postcard_rpc::host_client::{MultiSubRxError, MultiSubscription};
+use protocol::*;
+
+// The minimum is taken over windows of this length, so the estimate follows the drift between
+// the clocks, unit: s
+const CLOCK_OFFSET_WINDOW_S: f64 = 10.0;
+
+/// Keeps the samples of one motor in order and counts the samples that are lost on the way,
+/// Ex: the buffer of the device is full or the subscription lags. The samples are not
+/// reordered, a sample that arrives after a newer one is dropped and counted as lost
+#[derive(Debug, Default, Clone)]
+pub struct TelemetryStream {
+    next_seq: Option<u32>,
+    received: u64,
+    dropped: u64,
+}
+
+impl TelemetryStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the sample if it is the next one of the stream, the samples that are older than
+    /// the last returned one are duplicates and skipped
+    pub fn push(&mut self, sample: TelemetrySample) -> Option<TelemetrySample> {
+        if let Some(next_seq) = self.next_seq {
+            let gap = sample.seq.wrapping_sub(next_seq);
+            // The counter wraps, a gap of more than half of the range is an old sample
+            if gap > u32::MAX / 2 {
+                return None;
+            }
+            self.dropped += gap as u64;
+        }
+
+        self.next_seq = Some(sample.seq.wrapping_add(1));
+        self.received += 1;
+        Some(sample)
+    }
+
+    /// Number of samples in the stream
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Number of samples that are missing between the received ones
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Estimates the offset between the device clock and the host clock from the arrival times of
+/// the samples. A sample can't arrive before it is taken, so the smallest difference between the
+/// arrival time and the sample time is the offset plus the shortest latency of the link
+#[derive(Debug, Clone)]
+pub struct ClockOffsetEstimator {
+    tick_hz: u32,
+    // Host time is counted from here
+    origin: Instant,
+    window_start_s: f64,
+    window_min_s: Option<f64>,
+    prev_window_min_s: Option<f64>,
+}
+
+impl ClockOffsetEstimator {
+    /// `tick_hz` is `DeviceInfo::tick_hz`
+    pub fn new(tick_hz: u32) -> Self {
+        Self {
+            tick_hz,
+            origin: Instant::now(),
+            window_start_s: 0.0,
+            window_min_s: None,
+            prev_window_min_s: None,
+        }
+    }
+
+    /// Device time of a timestamp, unit: s
+    pub fn device_time_s(&self, timestamp: u64) -> f64 {
+        timestamp as f64 / self.tick_hz as f64
+    }
+
+    pub fn update(&mut self, timestamp: u64, received_at: Instant) {
+        let host_s = received_at
+            .saturating_duration_since(self.origin)
+            .as_secs_f64();
+        let diff_s = host_s - self.device_time_s(timestamp);
+
+        if host_s - self.window_start_s >= CLOCK_OFFSET_WINDOW_S {
+            self.prev_window_min_s = self.window_min_s;
+            self.window_min_s = None;
+            self.window_start_s = host_s;
+        }
+        self.window_min_s = Some(self.window_min_s.map_or(diff_s, |x| x.min(diff_s)));
+    }
+
+    /// Host time minus device time, the host time is counted from the creation of the
+    /// estimator. `None` until the first sample, unit: s
+    pub fn offset_s(&self) -> Option<f64> {
+        match (self.window_min_s, self.prev_window_min_s) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        }
+    }
+
+    /// Host time when the device clock shows the timestamp
+    pub fn to_host_time(&self, timestamp: u64) -> Option<Instant> {
+        let host_s = self.device_time_s(timestamp) + self.offset_s()?;
+        Some(self.origin + Duration::try_from_secs_f64(host_s).ok()?)
+    }
+}
+
+/// Subscription of `MotorDataBatchTopic` that returns the samples one by one, see
+/// `Client::subscribe_telemetry`
+pub struct TelemetrySubscription {
+    sub: MultiSubscription<MotorDataBatch>,
+    left: TelemetryStream,
+    right: TelemetryStream,
+    clock: ClockOffsetEstimator,
+    pending: VecDeque<(MotorId, TelemetrySample)>,
+}
+
+impl TelemetrySubscription {
+    pub(crate) fn new(sub: MultiSubscription<MotorDataBatch>, tick_hz: u32) -> Self {
+        Self {
+            sub,
+            left: TelemetryStream::new(),
+            right: TelemetryStream::new(),
+            clock: ClockOffsetEstimator::new(tick_hz),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns the next sample of either motor, the samples of each motor are in order and
+    /// without duplicates. The error is only returned when the connection is closed
+    pub async fn recv(&mut self) -> Result<(MotorId, TelemetrySample), MultiSubRxError> {
+        loop {
+            if let Some(x) = self.pending.pop_front() {
+                return Ok(x);
+            }
+
+            let batch = match self.sub.recv().await {
+                Ok(x) => x,
+                // The samples of the lost batches show up as the gap in the sequence
+                Err(MultiSubRxError::Lagged(_)) => continue,
+                Err(MultiSubRxError::IoClosed) => return Err(MultiSubRxError::IoClosed),
+            };
+            let received_at = Instant::now();
+            for sample in batch.samples.iter() {
+                self.clock.update(sample.data.timestamp, received_at);
+            }
+
+            let id = batch.id;
+            let stream = self.stream_mut(id);
+            let samples: Vec<_> = batch
+                .samples
+                .into_iter()
+                .filter_map(|x| stream.push(x))
+                .map(|x| (id, x))
+                .collect();
+            self.pending.extend(samples);
+        }
+    }
+
+    /// Offset between the clocks, it is updated by the received samples
+    pub fn clock(&self) -> &ClockOffsetEstimator {
+        &self.clock
+    }
+
+    pub fn stream(&self, id: MotorId) -> &TelemetryStream {
+        match id {
+            MotorId::Left => &self.left,
+            MotorId::Right => &self.right,
+        }
+    }
+
+    fn stream_mut(&mut self, id: MotorId) -> &mut TelemetryStream {
+        match id {
+            MotorId::Left => &mut self.left,
+            MotorId::Right => &mut self.right,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_HZ: u32 = 1_000;
+
+    fn sample(seq: u32) -> TelemetrySample {
+        TelemetrySample {
+            seq,
+            ..Default::default()
+        }
+    }
+
+    fn seqs(stream: &mut TelemetryStream, seqs: &[u32]) -> Vec<u32> {
+        seqs.iter()
+            .filter_map(|&x| stream.push(sample(x)))
+            .map(|x| x.seq)
+            .collect()
+    }
+
+    #[test]
+    fn stream_counts_the_gaps() {
+        let mut stream = TelemetryStream::new();
+        // The stream starts at any sequence number
+        assert_eq!(
+            seqs(&mut stream, &[10, 11, 14, 15, 20]),
+            [10, 11, 14, 15, 20]
+        );
+        assert_eq!(stream.received(), 5);
+        assert_eq!(stream.dropped(), 2 + 4);
+    }
+
+    #[test]
+    fn stream_skips_duplicates_and_late_samples() {
+        let mut stream = TelemetryStream::new();
+        assert_eq!(seqs(&mut stream, &[1, 2, 2, 4, 3, 1, 5]), [1, 2, 4, 5]);
+        assert_eq!(stream.received(), 4);
+        // The late sample is counted in the gap that it leaves
+        assert_eq!(stream.dropped(), 1);
+    }
+
+    #[test]
+    fn stream_follows_the_wrap_around_of_the_counter() {
+        let mut stream = TelemetryStream::new();
+        let received = seqs(&mut stream, &[u32::MAX - 1, u32::MAX, 0, 2, u32::MAX]);
+        assert_eq!(received, [u32::MAX - 1, u32::MAX, 0, 2]);
+        assert_eq!(stream.received(), 4);
+        assert_eq!(stream.dropped(), 1);
+    }
+
+    // Host time when the sample taken at `device_s` arrives
+    fn arrival(clock: &ClockOffsetEstimator, device_s: f64, offset_s: f64) -> Instant {
+        clock.origin + Duration::from_secs_f64(device_s + offset_s)
+    }
+
+    fn timestamp(device_s: f64) -> u64 {
+        (device_s * TICK_HZ as f64) as u64
+    }
+
+    #[test]
+    fn clock_offset_converges_to_the_shortest_latency() {
+        let mut clock = ClockOffsetEstimator::new(TICK_HZ);
+        assert_eq!(clock.offset_s(), None);
+        assert_eq!(clock.to_host_time(0), None);
+
+        let offset_s = 5.0;
+        for (i, latency_s) in [0.030, 0.004, 0.050, 0.002, 0.010].into_iter().enumerate() {
+            let device_s = i as f64 * 0.1;
+            clock.update(
+                timestamp(device_s),
+                arrival(&clock, device_s, offset_s + latency_s),
+            );
+        }
+        assert!((clock.offset_s().unwrap() - (offset_s + 0.002)).abs() < 1e-6);
+
+        let host_time = clock.to_host_time(timestamp(1.0)).unwrap();
+        let expected = arrival(&clock, 1.0, offset_s + 0.002);
+        assert!(host_time.max(expected) - host_time.min(expected) < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn clock_offset_follows_the_drift() {
+        let mut clock = ClockOffsetEstimator::new(TICK_HZ);
+        let mut device_s = 0.0;
+        let mut run = |clock: &mut ClockOffsetEstimator, offset_s: f64, duration_s: f64| {
+            let end_s = device_s + duration_s;
+            while device_s < end_s {
+                clock.update(timestamp(device_s), arrival(clock, device_s, offset_s));
+                device_s += 0.1;
+            }
+        };
+
+        run(&mut clock, 1.0, 15.0);
+        assert!((clock.offset_s().unwrap() - 1.0).abs() < 1e-6);
+        // The minimum of the previous window is kept until the window is over, then the offset
+        // of the drifted clock is taken
+        run(&mut clock, 1.5, 1.0);
+        assert!((clock.offset_s().unwrap() - 1.0).abs() < 1e-6);
+        run(&mut clock, 1.5, 2.0 * CLOCK_OFFSET_WINDOW_S);
+        assert!((clock.offset_s().unwrap() - 1.5).abs() < 1e-6);
+    }
+}
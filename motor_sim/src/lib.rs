@@ -0,0 +1,186 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use fw::hal::{Clock, FlashError, FlashPage, MotorDriver, PositionSensor};
+
+pub mod params;
+pub mod plant;
+
+use params::MotorParams;
+use plant::{MotorPlant, PlantState};
+
+// Simulated motor, the plant is shared between the hardware handles (sensor, driver, clock) that
+// are passed to the motion stack and the simulation loop that advances the plant
+#[derive(Clone)]
+pub struct SimMotor {
+    plant: Arc<Mutex<MotorPlant>>,
+    time_us: Arc<AtomicU64>,
+}
+
+impl SimMotor {
+    pub fn new(params: MotorParams) -> Self {
+        Self {
+            plant: Arc::new(Mutex::new(MotorPlant::new(params))),
+            time_us: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn sensor(&self) -> SimPositionSensor {
+        SimPositionSensor {
+            plant: self.plant.clone(),
+        }
+    }
+
+    pub fn driver(&self) -> SimMotorDriver {
+        SimMotorDriver {
+            plant: self.plant.clone(),
+        }
+    }
+
+    pub fn clock(&self) -> SimClock {
+        SimClock {
+            time_us: self.time_us.clone(),
+        }
+    }
+
+    pub fn get_state(&self) -> PlantState {
+        self.plant.lock().unwrap().get_state()
+    }
+
+    pub fn set_load_torque(&self, load_torque: f32) {
+        self.plant.lock().unwrap().set_load_torque(load_torque);
+    }
+
+    pub fn set_rotor_locked(&self, locked: bool) {
+        self.plant.lock().unwrap().set_rotor_locked(locked);
+    }
+
+    pub fn set_encoder_disconnected(&self, disconnected: bool) {
+        self.plant
+            .lock()
+            .unwrap()
+            .set_encoder_disconnected(disconnected);
+    }
+
+    /// Advances the plant and the simulated clock by `dt` seconds
+    pub fn step(&self, dt: f32) {
+        self.plant.lock().unwrap().step(dt);
+        self.time_us
+            .fetch_add((dt * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct SimPositionSensor {
+    plant: Arc<Mutex<MotorPlant>>,
+}
+
+impl PositionSensor for SimPositionSensor {
+    fn raw_count(&mut self) -> u16 {
+        self.plant.lock().unwrap().raw_count()
+    }
+}
+
+pub struct SimMotorDriver {
+    plant: Arc<Mutex<MotorPlant>>,
+}
+
+impl MotorDriver for SimMotorDriver {
+    fn set_duty_cycle_percent(&mut self, duty_cycle_percent: u8) {
+        self.plant
+            .lock()
+            .unwrap()
+            .set_duty_cycle_percent(duty_cycle_percent);
+    }
+
+    fn set_dir_pin(&mut self, high: bool) {
+        self.plant.lock().unwrap().set_dir_pin(high);
+    }
+
+    fn set_break_pin(&mut self, high: bool) {
+        self.plant.lock().unwrap().set_break_pin(high);
+    }
+}
+
+#[derive(Clone)]
+pub struct SimClock {
+    time_us: Arc<AtomicU64>,
+}
+
+impl Clock for SimClock {
+    // The simulated time is counted in us
+    const TICK_HZ: u32 = 1_000_000;
+
+    fn now_us(&self) -> u64 {
+        self.time_us.load(Ordering::Relaxed)
+    }
+
+    fn now_ticks(&self) -> u64 {
+        self.now_us()
+    }
+
+    fn delay_us(&mut self, _us: u32) {
+        // The simulated time is only advanced by `SimMotor::step`, busy waiting is not needed
+    }
+}
+
+// Same size as a page of the stm32f303 flash
+const SIM_FLASH_PAGE_SIZE: usize = 2048;
+
+// In-memory flash page, it behaves like NOR flash: the erase sets the bytes to 0xff and a write
+// only clears bits, so a write to a page that is not erased corrupts the data like on target.
+// The data is lost when the process exits, like a board with an erased page
+pub struct SimFlashPage {
+    bytes: Vec<u8>,
+}
+
+impl SimFlashPage {
+    pub fn new() -> Self {
+        Self {
+            bytes: vec![0xff; SIM_FLASH_PAGE_SIZE],
+        }
+    }
+
+    fn range(offset: u32, len: usize) -> Result<std::ops::Range<usize>, FlashError> {
+        let start = offset as usize;
+        let end = start.checked_add(len).ok_or(FlashError)?;
+        if end > SIM_FLASH_PAGE_SIZE {
+            return Err(FlashError);
+        }
+        Ok(start..end)
+    }
+}
+
+impl Default for SimFlashPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlashPage for SimFlashPage {
+    const SIZE: usize = SIM_FLASH_PAGE_SIZE;
+    const WRITE_SIZE: usize = 2;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        let range = Self::range(offset, bytes.len())?;
+        bytes.copy_from_slice(&self.bytes[range]);
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), FlashError> {
+        self.bytes.fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        if !(offset as usize).is_multiple_of(Self::WRITE_SIZE)
+            || !bytes.len().is_multiple_of(Self::WRITE_SIZE)
+        {
+            return Err(FlashError);
+        }
+        let range = Self::range(offset, bytes.len())?;
+        for (x, byte) in self.bytes[range].iter_mut().zip(bytes) {
+            *x &= byte;
+        }
+        Ok(())
+    }
+}
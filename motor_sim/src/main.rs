@@ -0,0 +1,129 @@
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, pubsub::PubSubChannel};
+
+use fw::encoder::Encoder;
+use fw::fault::{FaultLimits, FaultSupervisor};
+use fw::motion::Motion;
+use fw::motor::BldcMotor24H;
+use fw::params::DeviceParams;
+use fw::rpm_to_rad_s;
+use motor_sim::SimMotor;
+use motor_sim::params::MotorParams;
+use protocol::{MotorCommand, MotorId, PositionCommand, SequencedCommand};
+use s_curve::SCurveInterpolator;
+
+const PERIOD_S: f32 = 0.005;
+const VEL_LIMIT_RPM: f32 = 4000.0;
+const CHANNEL_SIZE: usize = 8;
+const MOTION_CMD_QUEUE_SIZE: usize = 8;
+const MOTION_EVENT_QUEUE_SIZE: usize = 16;
+
+// Runs the motion stack against the simulated motor: a velocity command, then a halt and a
+// position command, then a velocity command with a locked rotor that triggers stall fault, and
+// the fault is cleared after the rotor is released. The motor params can be given as the first argument (toml file), Ex:
+// `cargo run -- params.toml > record.txt`
+// The motion events of the commands are printed to stderr, so they don't mix with the record.
+fn main() {
+    let params = match std::env::args().nth(1) {
+        Some(path) => MotorParams::from_file(path).unwrap_or_else(|e| panic!("{e}")),
+        None => MotorParams::default(),
+    };
+    let device_params = DeviceParams::new(PERIOD_S, params.counts_per_rev, VEL_LIMIT_RPM);
+    let sim_motor = SimMotor::new(params);
+
+    let motor = BldcMotor24H::new(
+        Encoder::new(sim_motor.sensor(), device_params.counts_per_rev),
+        sim_motor.driver(),
+        sim_motor.clock(),
+        device_params.velocity_pid(MotorId::Left),
+        PERIOD_S,
+    );
+
+    let s_curve_intper = SCurveInterpolator::new(
+        rpm_to_rad_s(device_params.vel_limit_rpm),
+        device_params.acc_limit,
+        device_params.jerk_limit,
+        PERIOD_S,
+    );
+    let pos_controller = device_params.position_controller();
+    let fault_supervisor = FaultSupervisor::new(FaultLimits {
+        stall_effort: 0.5,
+        stall_time_s: 0.5,
+        following_error_rad: 2.0 * std::f32::consts::PI,
+        overspeed_rpm: VEL_LIMIT_RPM * 1.1,
+        max_cycle_time_us: (2.0 * PERIOD_S * 1_000_000.0) as u64,
+    });
+
+    let cmd_channel = PubSubChannel::<NoopRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>::new();
+    let cmd_pub = cmd_channel.publisher().unwrap();
+    let mut seq_id = 0_u32;
+    let mut send = |command: MotorCommand| {
+        seq_id += 1;
+        cmd_pub.publish_immediate(SequencedCommand { seq_id, command });
+    };
+    let mut motion: Motion<
+        _,
+        _,
+        _,
+        _,
+        CHANNEL_SIZE,
+        MOTION_CMD_QUEUE_SIZE,
+        MOTION_EVENT_QUEUE_SIZE,
+    > = Motion::new(
+        s_curve_intper,
+        motor,
+        pos_controller,
+        fault_supervisor,
+        cmd_channel.subscriber().unwrap(),
+    );
+    motion.set_params(&device_params);
+
+    send(MotorCommand::VelocityCommand(1000.0));
+
+    println!("time\tmode\tact_pos\tact_vel\tintp_pos\tintp_vel\tfollowing_error\tfault");
+    let total_steps = (6.0 / PERIOD_S) as usize;
+    for step in 0..total_steps {
+        if step == (1.0 / PERIOD_S) as usize {
+            send(MotorCommand::Halt);
+            send(MotorCommand::PositionCommand(PositionCommand {
+                displacement: 100.0,
+                vel_max: 2000.0,
+                vel_end: 0.0,
+                ..Default::default()
+            }));
+        } else if step == (3.0 / PERIOD_S) as usize {
+            sim_motor.set_rotor_locked(true);
+            send(MotorCommand::VelocityCommand(1000.0));
+        } else if step == (4.5 / PERIOD_S) as usize {
+            sim_motor.set_rotor_locked(false);
+            send(MotorCommand::ClearFault);
+        } else if step == (5.0 / PERIOD_S) as usize {
+            send(MotorCommand::VelocityCommand(1000.0));
+        }
+
+        motion.read_cmd_from_queue();
+        motion.run();
+        sim_motor.step(PERIOD_S);
+
+        while let Some(event) = motion.take_motion_event() {
+            eprintln!(
+                "{:.3}\tseq {}\t{:?}",
+                step as f32 * PERIOD_S,
+                event.seq_id,
+                event.kind
+            );
+        }
+
+        let data = motion.get_motor_process_data();
+        println!(
+            "{:.3}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            step as f32 * PERIOD_S,
+            data.control_mode_display,
+            data.actual_pos,
+            data.actual_vel,
+            data.intp_pos,
+            data.intp_vel,
+            data.following_error,
+            data.fault_code
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt::Display;
+use std::path::Path;
+
+use serde::Deserialize;
+
+// Parameters of DC motor model, all values are in SI unit. Fields that are not given in the
+// file use the default value.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MotorParams {
+    // unit: V
+    pub supply_voltage: f32,
+    // unit: ohm
+    pub resistance: f32,
+    // unit: H
+    pub inductance: f32,
+    // unit: Nm/A
+    pub torque_constant: f32,
+    // unit: V/(rad/s)
+    pub back_emf_constant: f32,
+    // unit: kg*m^2
+    pub inertia: f32,
+    // unit: Nm/(rad/s)
+    pub viscous_friction: f32,
+    // unit: Nm
+    pub coulomb_friction: f32,
+    // unit: Nm
+    pub load_torque: f32,
+    pub counts_per_rev: u16,
+}
+
+impl Default for MotorParams {
+    fn default() -> Self {
+        // Rough values of Nidec 24H, the no-load speed is about 4000 rpm at 24V
+        Self {
+            supply_voltage: 24.0,
+            resistance: 2.0,
+            inductance: 0.0005,
+            torque_constant: 0.0573,
+            back_emf_constant: 0.0573,
+            inertia: 0.00005,
+            viscous_friction: 0.00001,
+            coulomb_friction: 0.004,
+            load_torque: 0.0,
+            counts_per_rev: 400,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParamsError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl Display for ParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamsError::Io(e) => write!(f, "failed to read motor params, {e}"),
+            ParamsError::Parse(e) => write!(f, "failed to parse motor params, {e}"),
+        }
+    }
+}
+
+impl MotorParams {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ParamsError> {
+        let content = std::fs::read_to_string(path).map_err(ParamsError::Io)?;
+        Self::from_toml(&content)
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, ParamsError> {
+        toml::from_str(content).map_err(ParamsError::Parse)
+    }
+}
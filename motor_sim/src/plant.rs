@@ -0,0 +1,149 @@
+use std::f32::consts::PI;
+
+use crate::params::MotorParams;
+
+// Electrical time constant of the motor is much smaller than control period, so the plant is
+// integrated with a smaller step to keep the explicit integration stable
+const MAX_INTEGRATION_STEP_S: f32 = 0.00002;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlantState {
+    // unit: A
+    pub current: f32,
+    // unit: rad/s
+    pub velocity: f32,
+    // unit: rad
+    pub position: f32,
+    // unit: V
+    pub voltage: f32,
+}
+
+pub struct MotorPlant {
+    params: MotorParams,
+    state: PlantState,
+    duty_cycle_percent: u8,
+    dir_pin_high: bool,
+    break_pin_high: bool,
+    // Fault injection, a blocked wheel and a broken encoder cable
+    rotor_locked: bool,
+    encoder_disconnected: bool,
+    frozen_count: u16,
+}
+
+impl MotorPlant {
+    pub fn new(params: MotorParams) -> Self {
+        Self {
+            params,
+            state: PlantState::default(),
+            duty_cycle_percent: 0,
+            dir_pin_high: false,
+            break_pin_high: true,
+            rotor_locked: false,
+            encoder_disconnected: false,
+            frozen_count: 0,
+        }
+    }
+
+    pub fn get_params(&self) -> &MotorParams {
+        &self.params
+    }
+
+    pub fn get_state(&self) -> PlantState {
+        self.state
+    }
+
+    pub fn set_load_torque(&mut self, load_torque: f32) {
+        self.params.load_torque = load_torque;
+    }
+
+    pub fn set_rotor_locked(&mut self, locked: bool) {
+        self.rotor_locked = locked;
+        if locked {
+            self.state.velocity = 0.0;
+        }
+    }
+
+    pub fn set_encoder_disconnected(&mut self, disconnected: bool) {
+        if disconnected && !self.encoder_disconnected {
+            self.frozen_count = self.raw_count();
+        }
+        self.encoder_disconnected = disconnected;
+    }
+
+    pub fn set_duty_cycle_percent(&mut self, duty_cycle_percent: u8) {
+        self.duty_cycle_percent = duty_cycle_percent.min(100);
+    }
+
+    pub fn set_dir_pin(&mut self, high: bool) {
+        self.dir_pin_high = high;
+    }
+
+    pub fn set_break_pin(&mut self, high: bool) {
+        self.break_pin_high = high;
+    }
+
+    /// Encoder count quantized with `counts_per_rev`, the value is truncated to 16 bits like
+    /// the timer in QEI mode
+    pub fn raw_count(&self) -> u16 {
+        if self.encoder_disconnected {
+            return self.frozen_count;
+        }
+
+        let count = self.state.position / (2.0 * PI) * self.params.counts_per_rev as f32;
+        (count.floor() as i64) as u16
+    }
+
+    pub fn step(&mut self, dt: f32) {
+        let steps = (dt / MAX_INTEGRATION_STEP_S).ceil().max(1.0) as usize;
+        let h = dt / steps as f32;
+        for _ in 0..steps {
+            self.integrate(h);
+        }
+    }
+
+    fn integrate(&mut self, h: f32) {
+        let p = &self.params;
+        let s = &mut self.state;
+
+        // When the brake is applied, the windings are shorted and no voltage is applied
+        let dir = if self.dir_pin_high { -1.0 } else { 1.0 };
+        s.voltage = if self.break_pin_high {
+            dir * p.supply_voltage * self.duty_cycle_percent as f32 / 100.0
+        } else {
+            0.0
+        };
+
+        // Armature: L * di/dt = V - R * i - Ke * w
+        let di = (s.voltage - p.resistance * s.current - p.back_emf_constant * s.velocity)
+            / p.inductance;
+        s.current += di * h;
+
+        if self.rotor_locked {
+            return;
+        }
+
+        // Mechanics: J * dw/dt = Kt * i - b * w - Tc * sign(w) - T_load
+        let motor_torque = p.torque_constant * s.current;
+        let driving_torque = motor_torque - p.viscous_friction * s.velocity - p.load_torque;
+        if s.velocity == 0.0 && driving_torque.abs() <= p.coulomb_friction {
+            // Static friction holds the rotor
+            return;
+        }
+
+        let friction_dir = if s.velocity != 0.0 {
+            s.velocity.signum()
+        } else {
+            driving_torque.signum()
+        };
+        let acc = (driving_torque - p.coulomb_friction * friction_dir) / p.inertia;
+        let velocity = s.velocity + acc * h;
+
+        // Coulomb friction can't reverse the rotation, stop the rotor when velocity crosses 0
+        s.velocity = if s.velocity != 0.0 && velocity.signum() != s.velocity.signum() {
+            0.0
+        } else {
+            velocity
+        };
+        s.position += s.velocity * h;
+    }
+}
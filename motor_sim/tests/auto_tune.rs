@@ -0,0 +1,113 @@
+mod common;
+
+use std::f64::consts::PI;
+
+use common::{PERIOD_S, SimAxis};
+use motor_sim::params::MotorParams;
+use protocol::{AutoTuneConfig, AutoTuneStatus, MotorCommand, TuningRule};
+
+// Relative tolerance of the measured ultimate gain and period, the relay switches on the
+// samples of the encoder velocity, which is quantized to 30 rpm at 400 counts per revolution
+const TOLERANCE: f64 = 0.1;
+
+// Phase and magnitude of the loop that the relay closes at the frequency `w` (rad/s). The
+// electrical time constant is much smaller than the period, so the plant from the duty cycle to
+// the velocity (rpm) is first order. The duty cycle is held over a period, the encoder velocity
+// is the average over the previous period and the relay uses the velocity of the previous
+// cycle, so the sampled loop is:
+//   L(z) = z^-2 * K * (c * (1 - a) / (z - a) + 1 - c), a = e^(-T / tau), c = tau * (1 - a) / T
+fn loop_response(params: &MotorParams, w: f64) -> (f64, f64) {
+    let p = params;
+    let damping =
+        (p.resistance * p.viscous_friction + p.torque_constant * p.back_emf_constant) as f64;
+    let gain = (p.supply_voltage * p.torque_constant) as f64 / damping * 60.0 / (2.0 * PI);
+    let tau = (p.inertia * p.resistance) as f64 / damping;
+
+    let period_s = PERIOD_S as f64;
+    let a = (-period_s / tau).exp();
+    let c = tau * (1.0 - a) / period_s;
+
+    // c * (1 - a) / (z - a) + 1 - c, with z = e^(j * w * T)
+    let (re, im) = ((w * period_s).cos() - a, (w * period_s).sin());
+    let scale = c * (1.0 - a) / (re * re + im * im);
+    let (re, im) = (scale * re + 1.0 - c, -scale * im);
+
+    let phase = im.atan2(re) - 2.0 * w * period_s;
+    (phase, gain * (re * re + im * im).sqrt())
+}
+
+// The relay oscillates at the frequency where the phase of the loop is -180 degrees, the
+// ultimate gain is the inverse of the magnitude there
+fn analytic_ultimate_point(params: &MotorParams) -> (f64, f64) {
+    let (mut low, mut high) = (0.0, PI / PERIOD_S as f64);
+    for _ in 0..100 {
+        let w = (low + high) / 2.0;
+        if loop_response(params, w).0 > -PI {
+            low = w;
+        } else {
+            high = w;
+        }
+    }
+
+    let (_, magnitude) = loop_response(params, low);
+    (1.0 / magnitude, 2.0 * PI / low)
+}
+
+#[test]
+fn relay_finds_ultimate_point_of_plant() {
+    let mut axis = SimAxis::new();
+    axis.send(MotorCommand::AutoTune(AutoTuneConfig {
+        target_vel: 2000.0,
+        relay_amplitude: 0.3,
+        hysteresis: 0.0,
+        cycles: 10,
+        timeout_s: 5.0,
+        rule: TuningRule::ZieglerNichols,
+    }));
+    assert!(axis.run_until(6.0, |x| !matches!(
+        x.auto_tune_status(),
+        None | Some(AutoTuneStatus::Running(_))
+    )));
+
+    let Some(AutoTuneStatus::Finished(result)) = axis.auto_tune_status() else {
+        panic!("auto tuning failed, {:?}", axis.auto_tune_status());
+    };
+    let (ultimate_gain, ultimate_period) = analytic_ultimate_point(&MotorParams::default());
+    let gain_error = (result.ultimate_gain as f64 - ultimate_gain).abs() / ultimate_gain;
+    let period_error = (result.ultimate_period as f64 - ultimate_period).abs() / ultimate_period;
+
+    assert!(
+        gain_error < TOLERANCE,
+        "{result:?}, analytic: {ultimate_gain}"
+    );
+    assert!(
+        period_error < TOLERANCE,
+        "{result:?}, analytic: {ultimate_period}"
+    );
+}
+
+#[test]
+fn tuned_gains_follow_ziegler_nichols_rule() {
+    let mut axis = SimAxis::new();
+    axis.send(MotorCommand::AutoTune(AutoTuneConfig {
+        target_vel: 2000.0,
+        relay_amplitude: 0.3,
+        hysteresis: 0.0,
+        cycles: 4,
+        timeout_s: 5.0,
+        rule: TuningRule::ZieglerNichols,
+    }));
+    assert!(axis.run_until(6.0, |x| matches!(
+        x.auto_tune_status(),
+        Some(AutoTuneStatus::Finished(_))
+    )));
+
+    let Some(AutoTuneStatus::Finished(result)) = axis.auto_tune_status() else {
+        unreachable!();
+    };
+    let (ku, tu) = (result.ultimate_gain, result.ultimate_period);
+    let gains = result.gains;
+    assert!((gains.kp - 0.6 * ku).abs() < 1e-6 * ku);
+    assert!((gains.ki - 1.2 * ku / tu).abs() < 1e-4 * gains.ki);
+    assert!((gains.kd - 0.075 * ku * tu).abs() < 1e-4 * gains.kd);
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::{PARAMS, PERIOD_S, SimAxis};
+use fw::{rad_s_to_rpm, rpm_to_rad_s};
+use protocol::{ControlMode, FaultCode, MotionEventKind, MotorCommand, PositionCommand};
+
+// The encoder velocity is quantized to 30 rpm at 400 counts per revolution and 5 ms, and the
+// duty cycle to 1 % (about 40 rpm), so the velocity dithers around the target. The average
+// velocity of the plant is checked instead
+const VEL_TOLERANCE_RPM: f32 = 10.0;
+const LOAD_TORQUE: f32 = 0.02;
+
+fn mean_plant_vel_rpm(axis: &mut SimAxis, time_s: f32) -> f32 {
+    let cycles = (time_s / PERIOD_S) as usize;
+    let mut sum = 0.0;
+    for _ in 0..cycles {
+        axis.step();
+        sum += rad_s_to_rpm(axis.sim_motor.get_state().velocity);
+    }
+    sum / cycles as f32
+}
+
+#[test]
+fn velocity_command_reaches_target_velocity() {
+    let mut axis = SimAxis::new();
+    let seq_id = axis.send(MotorCommand::VelocityCommand(1500.0));
+
+    assert!(axis.run_until(1.0, |x| x.has_event(seq_id, MotionEventKind::Completed)));
+    // The command is completed within the velocity-ready threshold, the integral term removes
+    // the overshoot of the step afterwards
+    axis.run_for(1.0);
+    let vel_rpm = mean_plant_vel_rpm(&mut axis, 0.5);
+
+    assert_eq!(
+        axis.process_data().control_mode_display,
+        ControlMode::Velocity
+    );
+    assert!((vel_rpm - 1500.0).abs() < VEL_TOLERANCE_RPM);
+}
+
+#[test]
+fn velocity_is_held_against_load() {
+    let mut axis = SimAxis::new();
+    axis.send(MotorCommand::VelocityCommand(-1000.0));
+    axis.run_for(1.0);
+
+    // The load brakes the motor in negative direction, the integral term recovers the velocity
+    axis.sim_motor.set_load_torque(-LOAD_TORQUE);
+    axis.run_for(1.0);
+
+    assert!((mean_plant_vel_rpm(&mut axis, 0.5) + 1000.0).abs() < VEL_TOLERANCE_RPM);
+}
+
+#[test]
+fn position_move_follows_s_curve_profile() {
+    let vel_max_rpm = 2000.0;
+    let mut axis = SimAxis::new();
+    let seq_id = axis.send(MotorCommand::PositionCommand(PositionCommand {
+        displacement: 50.0,
+        vel_max: vel_max_rpm,
+        ..Default::default()
+    }));
+
+    // The profile stays in the limits of the device, and the velocity loop follows it
+    // within the correction of the position controller
+    let mut max_following_error: f32 = 0.0;
+    let completed = axis.run_until(5.0, |x| {
+        let data = x.process_data();
+        assert!(data.intp_vel.abs() <= rpm_to_rad_s(vel_max_rpm) * 1.001);
+        assert!(data.intp_acc.abs() <= PARAMS.acc_limit * 1.001);
+        assert!(data.intp_jerk.abs() <= PARAMS.jerk_limit * 1.001);
+        max_following_error = max_following_error.max((data.intp_pos - data.actual_pos).abs());
+        x.has_event(seq_id, MotionEventKind::Completed)
+    });
+    assert!(completed);
+
+    // About 60 ms of the maximum velocity
+    assert!(max_following_error < 0.06 * rpm_to_rad_s(vel_max_rpm));
+    assert_eq!(axis.process_data().fault_code, FaultCode::None);
+}
@@ -0,0 +1,154 @@
+// Runs the motion stack of the firmware against the simulated motor, one control cycle per step
+#![allow(dead_code)]
+
+use embassy_sync::{
+    blocking_mutex::raw::NoopRawMutex,
+    pubsub::{PubSubChannel, Publisher},
+};
+
+use fw::encoder::Encoder;
+use fw::fault::{FaultLimits, FaultSupervisor};
+use fw::motion::Motion;
+use fw::motor::BldcMotor24H;
+use fw::params::DeviceParams;
+use fw::rpm_to_rad_s;
+use motor_sim::params::MotorParams;
+use motor_sim::{SimClock, SimMotor, SimMotorDriver, SimPositionSensor};
+use protocol::{
+    AutoTuneStatus, MotionEvent, MotionEventKind, MotorCommand, MotorId, MotorProcessData,
+    SequencedCommand,
+};
+use s_curve::SCurveInterpolator;
+
+// The control loop settings of the firmware
+pub const PERIOD_S: f32 = fw::device::PERIOD_S;
+pub const VEL_LIMIT_RPM: f32 = fw::device::VEL_LIMIT_RPM;
+pub const PARAMS: DeviceParams = fw::device::DEFAULT_PARAMS;
+
+const CHANNEL_SIZE: usize = 8;
+const MOTION_CMD_QUEUE_SIZE: usize = 8;
+const MOTION_EVENT_QUEUE_SIZE: usize = 16;
+
+type CmdChannel = PubSubChannel<NoopRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>;
+pub type SimMotion = Motion<
+    'static,
+    NoopRawMutex,
+    SimPositionSensor,
+    SimMotorDriver,
+    SimClock,
+    CHANNEL_SIZE,
+    MOTION_CMD_QUEUE_SIZE,
+    MOTION_EVENT_QUEUE_SIZE,
+>;
+
+pub fn fault_limits() -> FaultLimits {
+    fw::device::fault_supervisor(&PARAMS).get_limits()
+}
+
+pub struct SimAxis {
+    pub sim_motor: SimMotor,
+    pub motion: SimMotion,
+    cmd_pub: Publisher<'static, NoopRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>,
+    seq_id: u32,
+    events: Vec<MotionEvent>,
+    auto_tune_status: Option<AutoTuneStatus>,
+}
+
+impl SimAxis {
+    pub fn new() -> Self {
+        Self::with_params(&PARAMS)
+    }
+
+    pub fn with_params(params: &DeviceParams) -> Self {
+        let sim_motor = SimMotor::new(MotorParams::default());
+
+        let motor = BldcMotor24H::new(
+            Encoder::new(sim_motor.sensor(), params.counts_per_rev),
+            sim_motor.driver(),
+            sim_motor.clock(),
+            params.velocity_pid(MotorId::Left),
+            params.period_s,
+        );
+        let s_curve_intper = SCurveInterpolator::new(
+            rpm_to_rad_s(params.vel_limit_rpm),
+            params.acc_limit,
+            params.jerk_limit,
+            params.period_s,
+        );
+
+        // The subscriber of the motion controller borrows the channel for the whole test
+        let cmd_channel: &'static CmdChannel = Box::leak(Box::new(CmdChannel::new()));
+        let mut motion = Motion::new(
+            s_curve_intper,
+            motor,
+            params.position_controller(),
+            FaultSupervisor::new(fault_limits()),
+            cmd_channel.subscriber().unwrap(),
+        );
+        motion.set_params(params);
+
+        Self {
+            sim_motor,
+            motion,
+            cmd_pub: cmd_channel.publisher().unwrap(),
+            seq_id: 0,
+            events: Vec::new(),
+            auto_tune_status: None,
+        }
+    }
+
+    /// Sends the command to the motion controller and returns its sequence id
+    pub fn send(&mut self, command: MotorCommand) -> u32 {
+        self.seq_id += 1;
+        self.cmd_pub.publish_immediate(SequencedCommand {
+            seq_id: self.seq_id,
+            command,
+        });
+        self.seq_id
+    }
+
+    pub fn step(&mut self) {
+        self.motion.read_cmd_from_queue();
+        self.motion.run();
+        self.sim_motor.step(PERIOD_S);
+
+        while let Some(event) = self.motion.take_motion_event() {
+            self.events.push(event);
+        }
+        if let Some(status) = self.motion.take_auto_tune_status() {
+            self.auto_tune_status = Some(status);
+        }
+    }
+
+    pub fn run_for(&mut self, time_s: f32) {
+        for _ in 0..(time_s / PERIOD_S) as usize {
+            self.step();
+        }
+    }
+
+    /// Runs the axis until the condition is met, returns false if it isn't met within `timeout_s`
+    pub fn run_until(&mut self, timeout_s: f32, mut cond: impl FnMut(&Self) -> bool) -> bool {
+        for _ in 0..(timeout_s / PERIOD_S) as usize {
+            self.step();
+            if cond(self) {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn has_event(&self, seq_id: u32, kind: MotionEventKind) -> bool {
+        self.events
+            .iter()
+            .any(|x| x.seq_id == seq_id && x.kind == kind)
+    }
+
+    /// The latest status of auto tuning
+    pub fn auto_tune_status(&self) -> Option<AutoTuneStatus> {
+        self.auto_tune_status
+    }
+
+    pub fn process_data(&self) -> MotorProcessData {
+        self.motion.get_motor_process_data()
+    }
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use common::SimAxis;
+use protocol::{ControlMode, FaultCode, MotionEventKind, MotorCommand, PositionCommand};
+
+fn fault_code(axis: &SimAxis) -> FaultCode {
+    axis.process_data().fault_code
+}
+
+// Runs the motor at a constant velocity, then locks the rotor until the stall is detected
+fn stall(axis: &mut SimAxis) {
+    axis.send(MotorCommand::VelocityCommand(1000.0));
+    axis.run_for(0.5);
+    axis.sim_motor.set_rotor_locked(true);
+    // The stall time of the supervisor is 0.5 s
+    assert!(axis.run_until(1.0, |x| fault_code(x) != FaultCode::None));
+}
+
+#[test]
+fn locked_rotor_is_reported_as_stall() {
+    let mut axis = SimAxis::new();
+    stall(&mut axis);
+
+    let data = axis.process_data();
+    assert_eq!(data.fault_code, FaultCode::Stall);
+    assert_eq!(data.control_mode_display, ControlMode::Fault);
+    // The motor is braked
+    axis.step();
+    assert_eq!(axis.sim_motor.get_state().voltage, 0.0);
+}
+
+#[test]
+fn disconnected_encoder_is_reported_as_stall() {
+    let mut axis = SimAxis::new();
+    axis.send(MotorCommand::VelocityCommand(1000.0));
+    axis.run_for(0.5);
+    axis.sim_motor.set_encoder_disconnected(true);
+
+    assert!(axis.run_until(2.0, |x| fault_code(x) != FaultCode::None));
+    assert_eq!(fault_code(&axis), FaultCode::Stall);
+}
+
+#[test]
+fn locked_rotor_in_position_move_is_reported_as_following_error() {
+    let mut axis = SimAxis::new();
+    axis.send(MotorCommand::PositionCommand(PositionCommand {
+        displacement: 100.0,
+        vel_max: 2000.0,
+        ..Default::default()
+    }));
+    axis.run_for(0.3);
+    axis.sim_motor.set_rotor_locked(true);
+
+    // The interpolated position runs away from the rotor before the stall time is over
+    assert!(axis.run_until(0.4, |x| fault_code(x) != FaultCode::None));
+    assert_eq!(fault_code(&axis), FaultCode::FollowingError);
+    assert_eq!(axis.process_data().intp_vel, 0.0);
+}
+
+#[test]
+fn fault_is_latched_until_cleared() {
+    let mut axis = SimAxis::new();
+    stall(&mut axis);
+
+    // The rotor is free again, but the fault stays and the commands are dropped
+    axis.sim_motor.set_rotor_locked(false);
+    axis.run_for(1.0);
+    let seq_id = axis.send(MotorCommand::VelocityCommand(500.0));
+    axis.run_for(0.5);
+
+    assert_eq!(fault_code(&axis), FaultCode::Stall);
+    assert_eq!(axis.process_data().control_mode_display, ControlMode::Fault);
+    assert!(axis.has_event(seq_id, MotionEventKind::Aborted));
+    assert_eq!(axis.sim_motor.get_state().velocity, 0.0);
+}
+
+#[test]
+fn clear_fault_returns_to_standstill() {
+    let mut axis = SimAxis::new();
+    stall(&mut axis);
+    axis.sim_motor.set_rotor_locked(false);
+
+    let seq_id = axis.send(MotorCommand::ClearFault);
+    assert!(axis.run_until(0.1, |x| x.has_event(seq_id, MotionEventKind::Completed)));
+    let data = axis.process_data();
+    assert_eq!(data.fault_code, FaultCode::None);
+    assert_eq!(data.control_mode_display, ControlMode::StandStill);
+
+    // The motor can be driven again, and the stall detection starts over
+    let seq_id = axis.send(MotorCommand::VelocityCommand(500.0));
+    assert!(axis.run_until(1.0, |x| x.has_event(seq_id, MotionEventKind::Completed)));
+    axis.run_for(1.0);
+    assert_eq!(fault_code(&axis), FaultCode::None);
+    assert!(axis.sim_motor.get_state().velocity > 0.0);
+}
@@ -0,0 +1,180 @@
+mod common;
+
+use common::{PARAMS, PERIOD_S, SimAxis, VEL_LIMIT_RPM};
+use fw::device::command_limits;
+use fw::motion::{CommandLimits, validate_motor_command};
+use fw::params::DeviceParams;
+use fw::rpm_to_rad_s;
+use protocol::{
+    CommandError, ControlMode, MotionEventKind, MotorCommand, MotorId, PositionCommand,
+};
+use s_curve::{MoveOptions, SCurveInterpolator};
+
+const VEL_MAX_RPM: f32 = 1000.0;
+const LOAD_TORQUE: f32 = 0.01;
+
+// One encoder count is 2 * pi / 400 rad, the axis is expected to stop within a few counts of the
+// interpolated position
+const POS_TOLERANCE_RAD: f32 = 0.05;
+
+// The interpolator ends the move on the displacement, up to the rounding of f32
+const INTP_END_TOLERANCE_RAD: f32 = 1e-3;
+
+fn move_cmd(displacement: f32) -> MotorCommand {
+    MotorCommand::PositionCommand(PositionCommand {
+        displacement,
+        vel_max: VEL_MAX_RPM,
+        ..Default::default()
+    })
+}
+
+// Moves the axis and waits until it settles at the end position
+fn move_and_settle(axis: &mut SimAxis, displacement: f32) {
+    let seq_id = axis.send(move_cmd(displacement));
+    assert!(axis.run_until(5.0, |x| x.has_event(seq_id, MotionEventKind::Completed)));
+    axis.run_for(2.0);
+}
+
+#[test]
+fn move_stops_at_interpolated_position() {
+    let mut axis = SimAxis::new();
+
+    move_and_settle(&mut axis, 20.0);
+
+    let data = axis.process_data();
+    assert_eq!(data.control_mode_display, ControlMode::Position);
+    assert!((data.intp_pos - 20.0).abs() < INTP_END_TOLERANCE_RAD);
+    assert!((data.actual_pos - data.intp_pos).abs() < POS_TOLERANCE_RAD);
+    assert_eq!(data.actual_vel, 0.0);
+}
+
+#[test]
+fn move_in_negative_direction_stops_at_interpolated_position() {
+    let mut axis = SimAxis::new();
+
+    move_and_settle(&mut axis, -15.0);
+
+    let data = axis.process_data();
+    assert!((data.intp_pos + 15.0).abs() < INTP_END_TOLERANCE_RAD);
+    assert!((data.actual_pos - data.intp_pos).abs() < POS_TOLERANCE_RAD);
+}
+
+#[test]
+fn position_is_held_against_load() {
+    let mut axis = SimAxis::new();
+
+    move_and_settle(&mut axis, 5.0);
+    let hold_pos = axis.process_data().intp_pos;
+    axis.sim_motor.set_load_torque(LOAD_TORQUE);
+    axis.run_for(2.0);
+
+    assert!((axis.process_data().actual_pos - hold_pos).abs() < POS_TOLERANCE_RAD);
+}
+
+// Turns off the position controller while the load pushes the axis away from the end of a move,
+// then applies `params` and returns the position error before and after `recovery_s`. The axis
+// is pulled back before the following error fault
+fn recover_from_load(params: &DeviceParams, recovery_s: f32) -> (f32, f32) {
+    let mut axis = SimAxis::new();
+
+    move_and_settle(&mut axis, 5.0);
+    let hold_pos = axis.process_data().intp_pos;
+
+    axis.motion.set_params(&DeviceParams {
+        pos_kp: 0.0,
+        pos_ki: 0.0,
+        ..PARAMS
+    });
+    axis.sim_motor.set_load_torque(LOAD_TORQUE);
+    axis.run_for(0.5);
+    let drift = (axis.process_data().actual_pos - hold_pos).abs();
+
+    axis.motion.set_params(params);
+    axis.run_for(recovery_s);
+    let error = (axis.process_data().actual_pos - hold_pos).abs();
+
+    (drift, error)
+}
+
+#[test]
+fn position_gains_are_applied_at_runtime() {
+    // Without the position controller, the velocity loop only holds the velocity
+    let (drift, error) = recover_from_load(&PARAMS, 1.0);
+
+    assert!(drift > 10.0 * POS_TOLERANCE_RAD);
+    assert!(error < drift / 4.0);
+}
+
+#[test]
+fn position_correction_limit_is_applied_at_runtime() {
+    let limited_params = DeviceParams {
+        pos_correction_limit_rpm: 30.0,
+        ..PARAMS
+    };
+    let (_, limited_error) = recover_from_load(&limited_params, 0.3);
+    let (_, error) = recover_from_load(&PARAMS, 0.3);
+
+    assert!(limited_error > 2.0 * error);
+}
+
+#[test]
+fn motion_status_reports_remaining_time_of_move() {
+    let mut axis = SimAxis::new();
+    let plan = SCurveInterpolator::new(
+        rpm_to_rad_s(VEL_LIMIT_RPM),
+        PARAMS.acc_limit,
+        PARAMS.jerk_limit,
+        PERIOD_S,
+    )
+    .plan(
+        20.0,
+        0.0,
+        0.0,
+        rpm_to_rad_s(VEL_MAX_RPM),
+        &MoveOptions::default(),
+    )
+    .unwrap();
+
+    let seq_id = axis.send(move_cmd(20.0));
+    axis.step();
+    let remaining_time = axis.motion.get_motion_status().remaining_time_s;
+    assert!((remaining_time - plan.total_time()).abs() <= PERIOD_S * 1.01);
+
+    // The move is completed once the interpolator reaches the end of the plan
+    let mut elapsed = PERIOD_S;
+    while !axis.has_event(seq_id, MotionEventKind::Completed) {
+        assert!(elapsed < 5.0);
+        axis.step();
+        elapsed += PERIOD_S;
+    }
+    assert!(elapsed >= plan.total_time());
+    assert_eq!(axis.motion.get_motion_status().remaining_time_s, 0.0);
+}
+
+#[test]
+fn command_that_runs_on_past_travel_limits_is_rejected() {
+    let axis = SimAxis::new();
+    let status = axis.motion.get_motor_status(MotorId::Left);
+    let limits = CommandLimits {
+        travel_limits_rad: Some((-10.0, 10.0)),
+        ..command_limits(&PARAMS)
+    };
+    let validate = |vel_end: f32, blend: bool| {
+        let cmd = MotorCommand::PositionCommand(PositionCommand {
+            displacement: 9.0,
+            vel_max: VEL_MAX_RPM,
+            vel_end,
+            blend,
+            ..Default::default()
+        });
+        validate_motor_command(MotorId::Left, &cmd, &status, &limits)
+    };
+
+    assert_eq!(validate(0.0, false), Ok(()));
+    assert_eq!(validate(VEL_MAX_RPM, true), Ok(()));
+    // The end is within the limits, but the stop from the end velocity is not
+    assert_eq!(
+        validate(VEL_MAX_RPM, false),
+        Err(CommandError::OutOfTravelLimits(MotorId::Left))
+    );
+}
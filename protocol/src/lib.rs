@@ -1,25 +1,48 @@
 #![cfg_attr(not(feature = "use-std"), no_std)]
 
+use postcard::experimental::max_size::MaxSize;
 use postcard_rpc::{endpoints, topics, TopicDirection};
 use postcard_schema::Schema;
 use serde::{Deserialize, Serialize};
 
 pub type CommandSetResult = Result<(), CommandError>;
+// Number of commands that are accepted
+pub type BatchSetResult = Result<u16, CommandError>;
+pub type PidGainsSetResult = Result<(), PidGainsError>;
+// `None` when the index is past the last parameter
+pub type ParamListResult = Option<ParamInfo>;
+pub type ParamGetResult = Result<ParamValue, ParamError>;
+pub type ParamSetResult = Result<(), ParamError>;
+pub type ParamStoreResult = Result<(), ParamStoreError>;
+pub type TelemetrySetResult = Result<(), TelemetryConfigError>;
 
 endpoints! {
     list = ENDPOINT_LIST;
     omit_std = true;
     | EndpointTy                  | RequestTy                     | ResponseTy          | Path               |
     | ----------                  | ----------                    | ----------          | ----------         |
-    | SetMotorCommandEndPoint     | (MotorId, MotorCommand)       | CommandSetResult    | "motor_cmd/set"    |
+    | SetMotorCommandEndPoint     | (MotorId, SequencedCommand)   | CommandSetResult    | "motor_cmd/set"    |
+    | GetPidGainsEndPoint         | MotorId                       | PidGains            | "pid_gains/get"    |
+    | SetPidGainsEndPoint         | (MotorId, PidGains)           | PidGainsSetResult   | "pid_gains/set"    |
+    | GetMotionStatusEndPoint     | MotorId                       | MotionStatus        | "motion_status/get"|
+    | SetPositionBatchEndPoint    | (MotorId, PositionBatch)      | BatchSetResult      | "motor_cmd/batch"  |
+    | GetDeviceInfoEndPoint       | ()                            | DeviceInfo          | "device/info"      |
+    | ListParamsEndPoint          | u16                           | ParamListResult     | "param/list"       |
+    | GetParamEndPoint            | ParamId                       | ParamGetResult      | "param/get"        |
+    | SetParamEndPoint            | (ParamId, ParamValue)         | ParamSetResult      | "param/set"        |
+    | SaveParamsEndPoint          | ()                            | ParamStoreResult    | "param/save"       |
+    | LoadParamsEndPoint          | ()                            | ParamStoreResult    | "param/load"       |
+    | FactoryResetEndPoint        | ()                            | ParamStoreResult    | "param/reset"      |
+    | SetTelemetryConfigEndPoint  | TelemetryConfig               | TelemetrySetResult  | "telemetry/config" |
 }
 
 topics! {
     list = TOPICS_IN_LIST;
     direction = TopicDirection::ToServer;
     omit_std = true;
-    | TopicTy                     | MessageTy                     | Path          |
-    | ----------                  | ----------                    | ----------    |
+    | TopicTy                     | MessageTy                     | Path               |
+    | ----------                  | ----------                    | ----------         |
+    | HeartbeatTopic              | Heartbeat                     | "host/heartbeat"   |
 }
 
 
@@ -27,47 +50,447 @@ topics! {
     list = TOPICS_OUT_LIST;
     direction = TopicDirection::ToClient;
     omit_std = true;
-    | TopicTy                     | MessageTy                     | Path          | Cfg                |
-    | ----------                  | ----------                    | ----------    | ----------         |
-    | MotorProcessDataTopic       | (MotorId, MotorProcessData)   | "motor/data"  |                    |
+    | TopicTy                     | MessageTy                     | Path               | Cfg                |
+    | ----------                  | ----------                    | ----------         | ----------         |
+    | MotorDataBatchTopic         | MotorDataBatch                | "motor/data"       |                    |
+    | AutoTuneTopic               | (MotorId, AutoTuneStatus)     | "motor/autotune"   |                    |
+    | MotionEventTopic            | (MotorId, MotionEvent)        | "motor/event"      |                    |
 }
 
 
+// Hash of the keys in `ENDPOINT_LIST`, `TOPICS_IN_LIST` and `TOPICS_OUT_LIST`, a key is
+// derived from the path and the schema of the message, so the hash changes when any message
+// is changed. The host compares it with `DeviceInfo::schema_hash` when it connects.
+pub const SCHEMA_HASH: u64 = schema_hash();
+
+const fn schema_hash() -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+
+    let mut i = 0;
+    while i < ENDPOINT_LIST.endpoints.len() {
+        let (_, req_key, resp_key) = &ENDPOINT_LIST.endpoints[i];
+        hash = fnv1a(hash, &req_key.to_bytes());
+        hash = fnv1a(hash, &resp_key.to_bytes());
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < TOPICS_IN_LIST.topics.len() {
+        hash = fnv1a(hash, &TOPICS_IN_LIST.topics[i].1.to_bytes());
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < TOPICS_OUT_LIST.topics.len() {
+        hash = fnv1a(hash, &TOPICS_OUT_LIST.topics[i].1.to_bytes());
+        i += 1;
+    }
+
+    hash
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+const fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct DeviceInfo {
+    pub fw_version: heapless::String<16>,
+    // Short git hash of the firmware build, "unknown" if it is built outside of git
+    pub git_hash: heapless::String<16>,
+    pub board_name: heapless::String<32>,
+    pub motor_count: u8,
+    pub control_period_us: u32,
+    // Rate of the device clock, `MotorProcessData::timestamp` is counted in these ticks
+    pub tick_hz: u32,
+    // `SCHEMA_HASH` of the protocol that the firmware is built with
+    pub schema_hash: u64,
+}
+
+// Id of a parameter in the registry of the device, the ids are stable across firmware
+// versions, so the host can refer to a parameter without listing the registry first
+pub type ParamId = u16;
+
+pub const MAX_PARAM_NAME_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum ParamValue {
+    F32(f32),
+    U32(u32),
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum ParamUnit {
+    None,
+    Second,
+    Rpm,
+    RadPerSec2,
+    RadPerSec3,
+    CountsPerRev,
+}
+
+// Description of one parameter, the host lists them by index with `ListParamsEndPoint`
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct ParamInfo {
+    pub id: ParamId,
+    pub name: heapless::String<MAX_PARAM_NAME_LEN>,
+    pub unit: ParamUnit,
+    // The value has the same variant as `default`, the range is inclusive
+    pub min: ParamValue,
+    pub max: ParamValue,
+    pub default: ParamValue,
+    // The new value is stored, but it is only used after the device is restarted (Ex: control
+    // loop period)
+    pub requires_reboot: bool,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum ParamError {
+    UnknownParam(ParamId),
+    // The value is not the same variant as the default value
+    TypeMismatch(ParamId),
+    OutOfRange(ParamId),
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum ParamStoreError {
+    // The flash page can't be read, erased or written
+    Flash,
+    // The page is erased, the parameters are not saved yet
+    Empty,
+    // Wrong magic number, length or crc, Ex: the power is lost while the page is written
+    Corrupted,
+    // The parameters are saved by a newer firmware
+    UnsupportedVersion(u16),
+    // The parameters don't fit into the flash page
+    Encode,
+    // Writing the flash stalls the control loop, the motors have to be stopped first
+    MotorsRunning,
+}
+
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
 pub enum ControlMode {
     Position,
     #[default]
     Velocity,
     StandStill,
+    AutoTune,
+    // Latched when a fault is detected, the motor is braked until `ClearFault` is received
+    Fault,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum FaultCode {
+    #[default]
+    None,
+    // High duty cycle without encoder counts, Ex: blocked wheel or disconnected encoder
+    Stall,
+    // Position error between interpolated and actual position exceeds the limit
+    FollowingError,
+    // Actual velocity exceeds the velocity limit
+    Overspeed,
+    // Control loop cycle takes longer than the limit
+    ControlLoopOverrun,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum WatchdogState {
+    // No heartbeat is received yet, or the host disables the watchdog
+    #[default]
+    Disarmed,
+    Armed,
+    // Heartbeats stop and both motors are halted, the next heartbeat arms the watchdog again
+    Expired,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Heartbeat {
+    // The device halts the motors if the next heartbeat doesn't come within this time, 0
+    // disables the watchdog, unit: ms
+    pub timeout_ms: u32,
 }
 
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq)]
 pub enum CommandError {
     BufferFull(MotorId),
+    // Only `ClearFault` is accepted when the motor is in fault mode
+    Faulted(MotorId),
+    // The batch is larger than the command queue of motion controller, it can't be accepted
+    // even when the queue is empty
+    BatchTooLarge {
+        id: MotorId,
+        len: u16,
+        capacity: u16,
+    },
+    // The value is NaN, infinite or out of range (Ex: velocity above the velocity limit)
+    InvalidValue(MotorId, CommandField),
+    // The motor has to finish the current motion first (Ex: auto tuning starts from rest)
+    NotReady(MotorId),
+    // The position command moves the motor beyond the travel limits
+    OutOfTravelLimits(MotorId),
+    // The command can't be used in current control mode
+    UnsupportedInMode(MotorId, ControlMode),
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum CommandField {
+    Velocity,
+    Displacement,
+    VelMax,
+    VelEnd,
+    AccMax,
+    DecMax,
+    JerkMax,
+    JerkOutMax,
+    AccEnd,
+    AutoTuneTargetVel,
+    AutoTuneRelayAmplitude,
+    AutoTuneHysteresis,
+    AutoTuneCycles,
+    AutoTuneTimeout,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq)]
+pub enum PidGainsError {
+    NonFiniteGain(MotorId),
+    NegativeGain(MotorId),
 }
 
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct PidGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub kff_vel: f32,
+    pub kff_acc: f32,
+}
+
+// The size of the batches depends on the encoded size, see `MAX_POSITION_BATCH_SIZE`
+#[derive(Serialize, Deserialize, Schema, MaxSize, Debug, PartialEq, Clone, Copy, Default)]
 pub struct PositionCommand {
     pub displacement: f32,
     pub vel_max: f32,
     pub vel_end: f32,
+    // Acceleration at the end of the move in the direction of the move, `None` ends without
+    // acceleration. It is clamped to the limits of the move and only used with a non-zero
+    // `vel_end`, unit: rpm/s
+    pub acc_end: Option<f32>,
+    // Limits of this move, `None` uses the defaults derived from `vel_max`, unit: rpm/s, rpm/s^2.
+    // The device converts them to rad/s^2, rad/s^3 (the unit of the `acc_limit` and `jerk_limit`
+    // parameters) and clamps them to these limits
+    pub acc_max: Option<f32>,
+    pub dec_max: Option<f32>,
+    // Jerk while the acceleration builds up, and while it is released (`jerk_max` if `None`)
+    pub jerk_max: Option<f32>,
+    pub jerk_out_max: Option<f32>,
+    // The end velocity is planned by the look-ahead over the queued position commands instead
+    // of `vel_end`, the command runs into the next one as fast as the limits allow
+    pub blend: bool,
 }
 
-#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+#[derive(Serialize, Deserialize, Schema, MaxSize, Debug, PartialEq, Clone, Copy)]
 pub enum MotorId {
     Left,
     Right,
 }
 
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum TuningRule {
+    #[default]
+    ZieglerNichols,
+    TyreusLuyben,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct AutoTuneConfig {
+    // Velocity that the relay oscillates around, unit: rpm
+    pub target_vel: f32,
+    // Relay amplitude added to/subtracted from bias output, unit: duty (0.0 ~ 1.0)
+    pub relay_amplitude: f32,
+    // Relay hysteresis on velocity error, unit: rpm
+    pub hysteresis: f32,
+    // Number of oscillation cycles that are measured
+    pub cycles: u8,
+    // Maximum duration of the test, unit: s
+    pub timeout_s: f32,
+    pub rule: TuningRule,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum AutoTuneError {
+    Timeout,
+    NoOscillation,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct AutoTuneResult {
+    pub ultimate_gain: f32,
+    pub ultimate_period: f32,
+    pub gains: PidGains,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum AutoTuneStatus {
+    // Number of measured oscillation cycles
+    Running(u8),
+    Finished(AutoTuneResult),
+    Failed(AutoTuneError),
+    Aborted,
+}
+
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
 pub enum MotorCommand {
     Halt,
     VelocityCommand(f32),
-    PositionCommand(PositionCommand)
+    PositionCommand(PositionCommand),
+    AutoTune(AutoTuneConfig),
+    ClearFault,
+}
+
+// Sequence id of the commands that are created by the device itself (Ex: halt when the
+// connection is lost), the host assigns ids from 1
+pub const INTERNAL_SEQ_ID: u32 = 0;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct SequencedCommand {
+    pub seq_id: u32,
+    pub command: MotorCommand,
+}
+
+// Size of the buffer that receives one frame in the firmware
+pub const DEVICE_RX_BUF_SIZE: usize = 1024;
+// Worst case size of the frame without the commands: the header (discriminant, 8 byte key, 4 byte
+// sequence number), `MotorId`, `first_seq_id` and the length of the commands as varints
+const POSITION_BATCH_OVERHEAD: usize = 13 + MotorId::POSTCARD_MAX_SIZE + u32::POSTCARD_MAX_SIZE + 2;
+// Maximum number of commands in one batch, the frame fits into the receive buffer of the firmware.
+// It follows the fields of `PositionCommand`
+pub const MAX_POSITION_BATCH_SIZE: usize =
+    (DEVICE_RX_BUF_SIZE - POSITION_BATCH_OVERHEAD) / PositionCommand::POSTCARD_MAX_SIZE;
+
+// Position commands that are queued at once, all or nothing. The commands take the sequence
+// ids from `first_seq_id` in order
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct PositionBatch {
+    pub first_seq_id: u32,
+    pub commands: heapless::Vec<PositionCommand, MAX_POSITION_BATCH_SIZE>,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum MotionEventKind {
+    // The command is put in the command queue of motion controller
+    Accepted,
+    // The command is taken from the queue and applied
+    Started,
+    // Position: interpolation is done, Velocity: target velocity is reached, Halt: the motor
+    // is in standstill, AutoTune: tuning is finished (see `AutoTuneTopic` for the result)
+    Completed,
+    // The command is dropped or interrupted before it is completed (Ex: halt, fault or it is
+    // replaced by the next command)
+    Aborted,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct MotionEvent {
+    pub seq_id: u32,
+    pub kind: MotionEventKind,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum InterpolationState {
+    #[default]
+    Done,
+    Busy,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum HaltState {
+    #[default]
+    Idle,
+    // The motor is stopping, the queued commands wait until it is in standstill
+    Running,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct MotionStatus {
+    // Free slots in the command queue of motion controller
+    pub queue_free: u16,
+    // Free slots in the channel between the endpoint handler and motion controller
+    pub channel_free: u16,
+    // Commands in the channel that are not moved to the command queue yet
+    pub channel_pending: u16,
+    pub intp_state: InterpolationState,
+    pub halt_state: HaltState,
+    // The command that is set and not completed yet
+    pub active_cmd: Option<SequencedCommand>,
+    // Estimated time until the interpolator finishes the running move, from the plan of the
+    // move, unit: s. It is 0 if no move is running, the queued commands are not counted
+    pub remaining_time_s: f32,
+}
+
+impl MotionStatus {
+    // Number of commands that can be sent without `BufferFull`, the pending commands in the
+    // channel will take the free slots in the command queue
+    pub fn credits(&self) -> u16 {
+        self.queue_free
+            .saturating_sub(self.channel_pending)
+            .min(self.channel_free)
+    }
+}
+
+// Maximum number of samples in one `MotorDataBatchTopic` message, the frame should fit into
+// the transmit buffer of the firmware
+pub const MAX_TELEMETRY_BATCH_LEN: usize = 16;
+pub const MAX_TELEMETRY_DECIMATION: u16 = 1000;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct TelemetryConfig {
+    // Every `decimation`-th control cycle is recorded, 1 records every cycle, so the sample
+    // rate is `1 / (DeviceInfo::control_period_s * decimation)`
+    pub decimation: u16,
+    // Samples per message, a batch that is not full is also sent once it gets old, so a low
+    // sample rate still reaches the host in time
+    pub batch_len: u8,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum TelemetryConfigError {
+    // Out of 1 ~ `MAX_TELEMETRY_DECIMATION`
+    Decimation(u16),
+    // Out of 1 ~ `MAX_TELEMETRY_BATCH_LEN`
+    BatchLen(u8),
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct TelemetrySample {
+    // Counter of the recorded samples of the motor, it increases by one per sample, so a gap
+    // means the samples are dropped (Ex: the buffer of the device is full)
+    pub seq: u32,
+    pub data: MotorProcessData,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct MotorDataBatch {
+    pub id: MotorId,
+    // In the order of `TelemetrySample::seq`
+    pub samples: heapless::Vec<TelemetrySample, MAX_TELEMETRY_BATCH_LEN>,
 }
 
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
 pub struct MotorProcessData {
+    // Device time of the control cycle, in ticks of `DeviceInfo::tick_hz`
+    pub timestamp: u64,
+    // Control cycles of the motor since boot, it increases by one per cycle
+    pub sample_counter: u32,
     pub control_mode_display: ControlMode,
     pub actual_pos: f32,
     pub actual_vel: f32,
@@ -75,11 +498,18 @@ pub struct MotorProcessData {
     pub intp_vel: f32,
     pub intp_acc: f32,
     pub intp_jerk: f32,
+    pub following_error: f32,
+    pub fault_code: FaultCode,
+    pub watchdog_state: WatchdogState,
 }
 
 #[cfg(feature = "use-std")]
 mod display_impl {
-    use super::ControlMode;
+    use super::{
+        CommandError, CommandField, ControlMode, FaultCode, ParamError, ParamStoreError,
+        ParamUnit, ParamValue, TelemetryConfigError, MAX_TELEMETRY_BATCH_LEN,
+        MAX_TELEMETRY_DECIMATION,
+    };
     use std::fmt::Display;
 
     impl Display for ControlMode {
@@ -88,7 +518,174 @@ mod display_impl {
                 ControlMode::Position => write!(f, "Position"),
                 ControlMode::Velocity => write!(f, "Velocity"),
                 ControlMode::StandStill => write!(f, "StandStill"),
+                ControlMode::AutoTune => write!(f, "AutoTune"),
+                ControlMode::Fault => write!(f, "Fault"),
+            }
+        }
+    }
+
+    impl Display for FaultCode {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                FaultCode::None => write!(f, "None"),
+                FaultCode::Stall => write!(f, "Stall"),
+                FaultCode::FollowingError => write!(f, "FollowingError"),
+                FaultCode::Overspeed => write!(f, "Overspeed"),
+                FaultCode::ControlLoopOverrun => write!(f, "ControlLoopOverrun"),
+            }
+        }
+    }
+
+    impl Display for CommandField {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                CommandField::Velocity => write!(f, "velocity"),
+                CommandField::Displacement => write!(f, "displacement"),
+                CommandField::VelMax => write!(f, "max velocity"),
+                CommandField::VelEnd => write!(f, "end velocity"),
+                CommandField::AccMax => write!(f, "max acceleration"),
+                CommandField::DecMax => write!(f, "max deceleration"),
+                CommandField::JerkMax => write!(f, "max jerk"),
+                CommandField::JerkOutMax => write!(f, "max jerk-out"),
+                CommandField::AccEnd => write!(f, "end acceleration"),
+                CommandField::AutoTuneTargetVel => write!(f, "auto tune target velocity"),
+                CommandField::AutoTuneRelayAmplitude => write!(f, "auto tune relay amplitude"),
+                CommandField::AutoTuneHysteresis => write!(f, "auto tune hysteresis"),
+                CommandField::AutoTuneCycles => write!(f, "auto tune cycles"),
+                CommandField::AutoTuneTimeout => write!(f, "auto tune timeout"),
             }
         }
     }
-}
\ No newline at end of file
+
+    impl Display for CommandError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                CommandError::BufferFull(id) => write!(f, "{id:?} motor: command buffer is full"),
+                CommandError::Faulted(id) => {
+                    write!(f, "{id:?} motor: motor is faulted, clear the fault first")
+                }
+                CommandError::BatchTooLarge { id, len, capacity } => write!(
+                    f,
+                    "{id:?} motor: batch of {len} commands doesn't fit into the queue of {capacity}"
+                ),
+                CommandError::InvalidValue(id, field) => {
+                    write!(f, "{id:?} motor: {field} is invalid or out of range")
+                }
+                CommandError::NotReady(id) => {
+                    write!(
+                        f,
+                        "{id:?} motor: motor is busy, wait until the motion is finished"
+                    )
+                }
+                CommandError::OutOfTravelLimits(id) => {
+                    write!(f, "{id:?} motor: target position is out of travel limits")
+                }
+                CommandError::UnsupportedInMode(id, mode) => {
+                    write!(f, "{id:?} motor: command is not supported in {mode} mode")
+                }
+            }
+        }
+    }
+
+    impl Display for ParamValue {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ParamValue::F32(x) => write!(f, "{x}"),
+                ParamValue::U32(x) => write!(f, "{x}"),
+            }
+        }
+    }
+
+    impl Display for ParamUnit {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ParamUnit::None => write!(f, ""),
+                ParamUnit::Second => write!(f, "s"),
+                ParamUnit::Rpm => write!(f, "rpm"),
+                ParamUnit::RadPerSec2 => write!(f, "rad/s^2"),
+                ParamUnit::RadPerSec3 => write!(f, "rad/s^3"),
+                ParamUnit::CountsPerRev => write!(f, "counts/rev"),
+            }
+        }
+    }
+
+    impl Display for ParamError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ParamError::UnknownParam(id) => write!(f, "parameter {id} doesn't exist"),
+                ParamError::TypeMismatch(id) => write!(f, "parameter {id}: wrong value type"),
+                ParamError::OutOfRange(id) => write!(f, "parameter {id}: value is out of range"),
+            }
+        }
+    }
+
+    impl Display for ParamStoreError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ParamStoreError::Flash => write!(f, "flash access failed"),
+                ParamStoreError::Empty => write!(f, "no parameters are saved"),
+                ParamStoreError::Corrupted => write!(f, "saved parameters are corrupted"),
+                ParamStoreError::UnsupportedVersion(x) => {
+                    write!(f, "saved parameters have unsupported layout version {x}")
+                }
+                ParamStoreError::Encode => write!(f, "parameters don't fit into the flash page"),
+                ParamStoreError::MotorsRunning => write!(f, "stop the motors first"),
+            }
+        }
+    }
+
+    impl Display for TelemetryConfigError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                TelemetryConfigError::Decimation(x) => {
+                    write!(f, "decimation {x} is out of 1 ~ {MAX_TELEMETRY_DECIMATION}")
+                }
+                TelemetryConfigError::BatchLen(x) => {
+                    write!(f, "batch length {x} is out of 1 ~ {MAX_TELEMETRY_BATCH_LEN}")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postcard_rpc::header::{VarHeader, VarKey, VarSeq};
+    use postcard_rpc::Endpoint;
+
+    #[test]
+    fn max_position_batch_fits_into_rx_buffer() {
+        // Every field takes its longest encoding
+        let cmd = PositionCommand {
+            displacement: f32::MAX,
+            vel_max: f32::MAX,
+            vel_end: f32::MAX,
+            acc_end: Some(f32::MAX),
+            acc_max: Some(f32::MAX),
+            dec_max: Some(f32::MAX),
+            jerk_max: Some(f32::MAX),
+            jerk_out_max: Some(f32::MAX),
+            blend: true,
+        };
+        let mut commands = heapless::Vec::new();
+        while commands.push(cmd).is_ok() {}
+        let batch = PositionBatch {
+            first_seq_id: u32::MAX,
+            commands,
+        };
+        let header = VarHeader {
+            key: VarKey::Key8(SetPositionBatchEndPoint::REQ_KEY),
+            seq_no: VarSeq::Seq4(u32::MAX),
+        };
+
+        let mut buf = [0u8; DEVICE_RX_BUF_SIZE];
+        let (used, remain) = header.write_to_slice(&mut buf).unwrap();
+        let header_len = used.len();
+        let body = postcard::to_slice(&(MotorId::Left, &batch), remain)
+            .expect("the batch doesn't fit into the receive buffer");
+        let frame_len = header_len + body.len();
+        // One more command would overflow the buffer
+        assert!(frame_len + PositionCommand::POSTCARD_MAX_SIZE > DEVICE_RX_BUF_SIZE);
+    }
+}
@@ -3,8 +3,10 @@
 #[cfg(feature = "std")]
 use std::io::Write;
 
-#[cfg(not(feature = "std"))]
-use num_traits::Float;
+pub mod planner;
+mod profile;
+
+use planner::{PlanError, PlanLimits, SCurvePlan};
 
 #[repr(u8)]
 #[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
@@ -21,15 +23,23 @@ pub struct TargetData {
     pub vel_start: f32,
     pub vel_end: f32,
     pub vel_max: f32,
-    pos_offset: f32,
-    vel_min: f32,
-    acc_start: f32,
-    acc_end: f32,
-    acc_max: f32,
-    acc_min: f32,
-    jerk_max: f32,
-    jerk_min: f32,
-    dir: f32,
+    // Position where the segment starts, the plan is relative to it
+    pos_start: f32,
+}
+
+// Optional settings of one move. The limits are magnitudes in the units of the interpolator
+// (Ex: rad/s^2, rad/s^3 in the firmware) and clamped to its limits, `None` uses the limits
+// derived from the max velocity
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct MoveOptions {
+    pub acc_max: Option<f32>,
+    pub dec_max: Option<f32>,
+    // Jerk while the acceleration builds up (jerk-in), it is used for jerk-out, while the
+    // acceleration is released, as well if `jerk_out_max` is not given
+    pub jerk_max: Option<f32>,
+    pub jerk_out_max: Option<f32>,
+    // Acceleration at the end of the move, it is meant for a move that is followed by another one
+    pub acc_end: f32,
 }
 
 #[derive(Default, Clone)]
@@ -43,19 +53,11 @@ pub struct InterpolationDataOutput {
 #[derive(Default, Clone)]
 struct InterpolationData {
     pos: f32,
-    dist: f32,
     vel: f32,
     acc: f32,
     jerk: f32,
-
-    ta: [f32; 2],
-    tb: [f32; 2],
-    td: [f32; 2],
-    h: f32,
+    // Samples since the start of the segment
     steps: usize,
-    dec_start_period: usize,
-    dec_right_away: bool,
-    pos_end: f32,
 }
 
 #[derive(Default, Clone)]
@@ -72,6 +74,8 @@ pub struct SCurveInterpolator {
     intp_status: InterpolationStatus,
     target_data: TargetData,
     motion_constraint: SCurveConstraint,
+    // Plan of the running segment, it is sampled once per period
+    plan: SCurvePlan,
 }
 
 impl SCurveInterpolator {
@@ -79,26 +83,31 @@ impl SCurveInterpolator {
         Self {
             intp_data: InterpolationData::default(),
             intp_status: InterpolationStatus::default(),
-            target_data: TargetData {
-                dir: 1.0,
-                ..Default::default()
-            },
+            target_data: TargetData::default(),
             motion_constraint: SCurveConstraint {
                 vel_limit,
                 acc_limit,
                 jerk_limit,
                 sampling_time,
             },
+            plan: SCurvePlan::default(),
         }
     }
 
+    // The limits are used from the next `set_target`, the running segment keeps the limits that
+    // it is planned with
+    pub fn set_limits(&mut self, vel_limit: f32, acc_limit: f32, jerk_limit: f32) {
+        self.motion_constraint.vel_limit = vel_limit;
+        self.motion_constraint.acc_limit = acc_limit;
+        self.motion_constraint.jerk_limit = jerk_limit;
+    }
+
     pub fn get_intp_data(&self) -> InterpolationDataOutput {
-        let dir = self.target_data.dir;
         InterpolationDataOutput {
-            pos: self.intp_data.pos * dir,
-            vel: self.intp_data.vel * dir,
-            acc: self.intp_data.acc * dir,
-            jerk: self.intp_data.jerk * dir,
+            pos: self.intp_data.pos,
+            vel: self.intp_data.vel,
+            acc: self.intp_data.acc,
+            jerk: self.intp_data.jerk,
         }
     }
 
@@ -113,388 +122,474 @@ impl SCurveInterpolator {
         vel_start: f32,
         vel_end: f32,
         vel_max_magnitude: f32,
+        options: &MoveOptions,
     ) {
-        let t = self.motion_constraint.sampling_time;
-
-        // The interpolation is not needed if distance == 0 or v_max == 0. The `dec_right_away` is a special case for
-        // aborting interpolation and 0 displacement is allowed in this case
-        if (displacement == 0.0 && !self.intp_data.dec_right_away) || vel_max_magnitude == 0.0 {
+        // The interpolation is not needed if distance == 0 or v_max == 0
+        if displacement == 0.0 || vel_max_magnitude == 0.0 {
             return;
         }
 
-        // Simple protection for v_max, the value should be greater than 0
-        let vel_max = vel_max_magnitude.abs();
-        let vel_max = if vel_max <= 1e-6 || vel_max > self.motion_constraint.vel_limit {
-            self.motion_constraint.vel_limit
-        } else {
-            vel_max
-        };
-
-        // Calculate a_max and j_max from v_max using simple equation
-        let acc_max = vel_max / t / 100.0;
-        let acc_max = if acc_max <= 1e-6 || acc_max > self.motion_constraint.acc_limit {
-            self.motion_constraint.acc_limit
-        } else {
-            acc_max
-        };
-
-        let jerk_max = acc_max / t / 10.0;
-        let jerk_max = if jerk_max <= 1e-6 || jerk_max > self.motion_constraint.jerk_limit {
-            self.motion_constraint.jerk_limit
-        } else {
-            jerk_max
-        };
-
-        // Calculate dir coefficient
-        let dir_prev = self.target_data.dir;
-        let dir = if displacement >= 0.0 { 1.0 } else { -1.0 };
-        self.target_data.dir = dir;
-
-        // Special case, override displacement if `dec_right_away` is true
-        let mut displacement = displacement;
-        if self.intp_data.dec_right_away {
-            displacement = 0.0;
-        }
+        let limits = self.resolve_limits(vel_max_magnitude, options);
+        self.plan = SCurvePlan::new_move(
+            displacement,
+            self.vel_start(vel_start),
+            vel_end,
+            self.intp_data.acc,
+            options.acc_end,
+            &limits,
+        );
+        self.start_segment(pos_offset, limits.vel_max);
+    }
 
-        // According to the equations on book, the s-curve will always treat the segment as positive which means
-        // `q_end > q_start`. If `q_end < q_start` we need to flip velocity and position
-        //
-        // Override `vel_start` if previous intp vel != 0 to make sure current moves after previous segmenet
-        // 1. If previous direction is negative, the sign of previous intp velocity is flipped.
-        //    Example:
-        //      Test input A:
-        //        * q_start: 0
-        //        * q_end: -10
-        //        * vel_start: 0
-        //        * vel_end: -2
-        // 
-        //      This settings will be processed as positive segment:
-        //        * q_start: 0
-        //        * q_end: 10
-        //        * vel_start: 0
-        //        * vel_end: 2
-        //      with a negative direction settings (-1.0). The direction settings will be used to flip the 
-        //      interpolated value during the output stage.
-        //      
-        //      After test input A is finished, the intp vel is 2, and this value need to be fliped to prevent
-        //      velocity jump errors. 
-        //
-        //      a. New input that moves axis in negative direction:
-        //         vel_start = -2 (flipped)
-        //         target_data.vel = -1 * vel_start = 2 (flipped)
-        //         output vel = -1 * intp vel = -1 * 2 (flipped)
-        //         => The first output vel is consistent with previous end vel (-2)
-        //
-        //      b. New input that moves axis in positive direction:
-        //         vel_start = -2 (flipped)
-        //         target_data.vel = 1 * vel_start = -2 (no flipped)
-        //         output vel = 1 * intp vel = 1 * (-2) (no flipped)
-        //         => The first output vel is consistent with previous end vel (-2)
-        // 
-        // 2. If previous direction is positive, the sign of previous intp vel is not flipped.
-        //    Example:
-        //      Test input A:
-        //        * q_start: 0
-        //        * q_end: 10
-        //        * vel_start: 0
-        //        * vel_end: 2
-        //
-        //    Because it is already a positive segment, the ending intp vel is 2
-        //
-        //    a. New input that moves axis in negative direction
-        //       vel start = 2 (no flipped)
-        //       target_data.vel = -1 * vel_start = -2 (flipped)
-        //       output vel = -1 * intp vel = 2 (flipped)
-        //       => The first output vel is consistent with previous end vel (2)
-        //
-        //    b. New input that moves axis in positive direction again
-        //       vel start = 2 (no flipped)
-        //       target_data.vel = 1 * vel_start = 2 (no flipped)
-        //       output vel = 1 * intp vel = 2 (no flipped)
-        //       => The first output vel is consistent with previous end vel (2)
-        // 
-        let mut vel_start = vel_start;
-        if self.intp_data.vel != 0.0 {
-            if dir_prev < 0.0 {
-                vel_start = -self.intp_data.vel;
-            } else {
-                vel_start = self.intp_data.vel;
-            }
-        }
+    // Plans the move that `set_target` would run with the same arguments, from the current
+    // velocity and acceleration and with the same limits, Ex: to estimate the duration before the
+    // move is started. Unlike `set_target`, a move that can't reach `vel_end` within the
+    // displacement is an error
+    pub fn plan(
+        &self,
+        displacement: f32,
+        vel_start: f32,
+        vel_end: f32,
+        vel_max_magnitude: f32,
+        options: &MoveOptions,
+    ) -> Result<SCurvePlan, PlanError> {
+        let limits = self.plan_limits(vel_max_magnitude, options);
+        SCurvePlan::new(
+            displacement,
+            self.vel_start(vel_start),
+            vel_end,
+            self.intp_data.acc,
+            options.acc_end,
+            &limits,
+        )
+    }
 
-        // Override intp pos end if the direction is revered
-        // The decision is similar as above comments, here is the example that explain the decision
-        // 
-        // a. Previous: axis moves in negative direction, Current: axis moves in negative direction
-        //    * Previous pos_end is negative
-        //    * Current direction is negative, flip previous pos_end, it becomes positive
-        //    * Doing interpolation in current direction, the output value is flipped again
-        //    => This makes the positive is consistent with previous segment (same for pos_offset)
-        let mut pos_offset = pos_offset;
-        if dir < 0.0 {
-            self.intp_data.pos_end = -self.intp_data.pos_end;
-            pos_offset = -pos_offset;
-        }
+    // Limits of the move for the offline planner, see `plan`
+    pub fn plan_limits(&self, vel_max_magnitude: f32, options: &MoveOptions) -> PlanLimits {
+        self.resolve_limits(vel_max_magnitude, options)
+    }
 
-        // Use symmetric settings for min value and update target data struct
-        self.target_data.pos_offset = pos_offset;
-        self.target_data.dist = dir * displacement;
-        self.target_data.vel_start = dir * vel_start;
-        self.target_data.vel_end = dir * vel_end;
-        self.target_data.vel_max = vel_max * (dir + 1.0) / 2.0 - vel_max * (dir - 1.0) / 2.0;
-        self.target_data.vel_min = -self.target_data.vel_max;
-        self.target_data.acc_start = 0.0;
-        self.target_data.acc_end = 0.0;
-        self.target_data.acc_max = acc_max * (dir + 1.0) / 2.0 - acc_max * (dir - 1.0) / 2.0;
-        self.target_data.acc_min = -self.target_data.acc_max;
-        self.target_data.jerk_max = jerk_max * (dir + 1.0) / 2.0 - jerk_max * (dir - 1.0) / 2.0;
-        self.target_data.jerk_min = -self.target_data.jerk_max;
-
-        // Update current interpolation data based on target start condition
-        self.intp_data.vel = self.target_data.vel_start;
-        self.intp_data.acc = self.target_data.acc_start;
-
-        // Special case, update intp dist according to intp status.
-        //
-        // 1. If previous intp is finished, the intp dist is set to 0, this makes sure intp can generate correct 
-        //    distance when running new segment. (combined with `pos_end`, we can get expected position value)
-        // 2. If previous intp is not finished, the intp dist is not set to 0. Currently, this is caused by stopping
-        //    the interplation in the middle by calling `stop`. Because we need to make sure axis is stopping from
-        //    current position, the distance is not reset to 0. (If it is reset to 0, then position will jump during
-        //    calculation)
-        if self.intp_status == InterpolationStatus::Done {
-            self.intp_data.dist = 0.0;
+    // Time until the running segment is done, unit: s. It is 0 if no segment is running
+    pub fn remaining_time(&self) -> f32 {
+        if self.intp_status != InterpolationStatus::Busy {
+            return 0.0;
         }
-
-        // Update status
-        self.intp_status = InterpolationStatus::Busy;
-        self.intp_data.dec_start_period = usize::MIN;
+        let t = self.intp_data.steps as f32 * self.motion_constraint.sampling_time;
+        (self.plan.total_time() - t).max(0.0)
     }
 
+    // Samples the plan at the next period. The segment is done at the first sample at or after
+    // the end of the plan, the sample is up to one period past the end, where a move
+    // that ends with velocity has run on with it
     pub fn interpolate(&mut self) {
         if self.intp_status == InterpolationStatus::Done {
             return;
         }
 
-        self.calculate_dec_distance();
-        self.generate_jerk_acc_vel_segment();
-        self.generate_jerk_dec_segment();
-        self.integrate();
+        self.intp_data.steps += 1;
+        let t = self.intp_data.steps as f32 * self.motion_constraint.sampling_time;
+        if t >= self.plan.total_time() {
+            self.intp_status = InterpolationStatus::Done;
+        }
+
+        let data = self.plan.evaluate(t);
+        self.intp_data.pos = self.target_data.pos_start + data.pos;
+        self.intp_data.vel = data.vel;
+        self.intp_data.acc = data.acc;
+        self.intp_data.jerk = data.jerk;
     }
 
     #[cfg(feature = "std")]
     pub fn save_intp_data(&self, file: &mut std::fs::File) {
-        let dir = self.target_data.dir;
+        let phases = self.plan.phases();
         let _ = write!(
             file,
             "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
-            self.intp_data.pos * dir,
-            self.intp_data.vel * dir,
-            self.intp_data.acc * dir,
-            self.intp_data.jerk * dir,
-            self.intp_data.ta[0],
-            self.intp_data.tb[0],
-            self.intp_data.td[0],
-            self.intp_data.h * dir
+            self.intp_data.pos,
+            self.intp_data.vel,
+            self.intp_data.acc,
+            self.intp_data.jerk,
+            phases.ta,
+            phases.tv,
+            phases.td,
+            self.target_data.dist
         );
     }
 
+    // Decelerates to standstill right away from the current velocity and acceleration, with the
+    // limits that are derived from the velocity limit. The axis stops wherever the deceleration
+    // ends
     pub fn stop(&mut self) {
-        // 1. Set `dec_right_away` to true:
-        //    * Stop generating acc/vel data
-        //    * Run deceleration segment right away
-        //
-        // 2. Use `set_target` to force deceleration 
-        //    * Special displacement: 0.0
-        //      In general case, 0 displacement will be rejected, but it is allowed when running `Stop`. The 0
-        //      displacement makes sure deceleration distance is larger(or equal) to remaing distance which forces
-        //      intp to run deceleration calculation (in `generate_jerk_dec_segment`)
-        //    * Vel start: use current intp vel times direction.
-        //      Because all the calculation is based on positive segment, the intp vel needs to be flipped to get real
-        //      value)
-        //    * End velocity: 0
-        //      Make axis stop at the end and also make sure `calculate_dec_distance` is activated
-        self.intp_data.dec_right_away = true;
-        self.set_target(
-            0.0,
-            0.0,
-            self.intp_data.vel * self.target_data.dir,
-            0.0,
-            self.motion_constraint.vel_limit,
-        );
+        let limits = self.stop_limits();
+        self.plan = SCurvePlan::stop(self.intp_data.vel, self.intp_data.acc, &limits);
+        self.start_segment(0.0, limits.vel_max);
     }
 
-    fn calculate_dec_distance(&mut self) {
-        if self.intp_data.vel < self.target_data.vel_end {
-            // In deceleration segment, we expect the intp vel is greater than or
-            // equal to target end velocity
-            return;
-        }
+    // Limits of the stop, Ex: to plan where the axis stops before the stop is started
+    pub fn stop_limits(&self) -> PlanLimits {
+        self.resolve_limits(self.motion_constraint.vel_limit, &MoveOptions::default())
+    }
 
-        // Calculate the time in deceleration segment: T_a, T_b, T_d
-        let vel_end = self.target_data.vel_end;
-        let acc_end = self.target_data.acc_end;
-        let acc_min = self.target_data.acc_min;
-        let jerk_max = self.target_data.jerk_max;
-        let jerk_min = self.target_data.jerk_min;
-        let vel_cur = self.intp_data.vel;
-        let acc_cur = self.intp_data.acc;
-
-        let mut ta = (acc_min - acc_cur) / jerk_min;
-        let mut tb = (acc_end - acc_min) / jerk_max;
-        let mut td = ((vel_end - vel_cur) / acc_min)
-            + (ta * (acc_min - acc_cur) / (2.0 * acc_min))
-            + (tb * (acc_min - acc_end) / (2.0 * acc_min));
-
-        if td < (ta + tb) {
-            let acc_cur_square = acc_cur * acc_cur;
-            let acc_end_squre = acc_end * acc_end;
-            let term1 = acc_cur_square * jerk_max
-                - jerk_min * (acc_end_squre + 2.0 * jerk_max * (vel_cur - vel_end));
-            let term2 = jerk_max - jerk_min;
-
-            ta = -acc_cur / jerk_min + (term2 * term1).sqrt() / (-term2 * jerk_min);
-            tb = acc_end / jerk_max + (term2 * term1).sqrt() / (term2 * jerk_max);
-            td = ta + tb;
-        }
+    pub fn abort(&mut self) {
+        // Unlike `stop`, the interpolation is dropped without deceleration (Ex: the axis is braked
+        // by a fault). The output holds current position with 0 velocity, so the next segment
+        // starts from here
+        self.intp_data = InterpolationData {
+            pos: self.intp_data.pos,
+            ..Default::default()
+        };
+        self.intp_status = InterpolationStatus::Done;
+    }
 
-        // Calculate deceleration distance
-        let td_square = td * td;
-        let ta_square = ta * ta;
-        let tb_cubic = tb * tb * tb;
-        let hk = 0.5 * acc_cur * td_square
-            + (1.0 / 6.0)
-                * (jerk_min * ta * (3.0 * td_square - 3.0 * td * ta + ta_square)
-                    + jerk_max * tb_cubic)
-            + td * vel_cur;
-
-        // Basic protection of numerical error to prevent negative time
-        if ta < 0.0 {
-            ta = 0.0;
+    // The segment continues from the current intp vel and acc, so they don't jump at the
+    // boundary of the segments. A finished segment ends with its `vel_end` and `acc_end`. The
+    // start velocity is opposite to the move if the direction is reversed while moving
+    fn vel_start(&self, vel_start: f32) -> f32 {
+        if self.intp_data.vel != 0.0 {
+            self.intp_data.vel
+        } else {
+            vel_start
         }
+    }
 
-        if tb < 0.0 {
-            tb = 0.0;
-        }
+    // The plan is set, the segment starts from the current position
+    fn start_segment(&mut self, pos_offset: f32, vel_max: f32) {
+        let end = self.plan.evaluate(self.plan.total_time());
+        self.target_data = TargetData {
+            dist: self.plan.displacement(),
+            vel_start: self.plan.evaluate(0.0).vel,
+            vel_end: end.vel,
+            vel_max,
+            pos_start: self.intp_data.pos + pos_offset,
+        };
+        self.intp_data.steps = 0;
+        self.intp_status = InterpolationStatus::Busy;
+    }
+
+    fn resolve_limits(&self, vel_max_magnitude: f32, options: &MoveOptions) -> PlanLimits {
+        let t = self.motion_constraint.sampling_time;
 
-        if td < 0.0 {
-            td = 0.0;
+        // Simple protection for v_max, the value should be greater than 0
+        let vel_max = vel_max_magnitude.abs();
+        let vel_max = if vel_max <= 1e-6 || vel_max > self.motion_constraint.vel_limit {
+            self.motion_constraint.vel_limit
+        } else {
+            vel_max
+        };
+
+        // Use the limits of the move, or calculate a_max and j_max from v_max using simple
+        // equation if they are not given
+        let acc_limit = self.motion_constraint.acc_limit;
+        let acc_max = Self::clamp_limit(options.acc_max, vel_max / t / 100.0, acc_limit);
+        let dec_max = Self::clamp_limit(options.dec_max, acc_max, acc_limit);
+
+        let jerk_limit = self.motion_constraint.jerk_limit;
+        let jerk_in = Self::clamp_limit(options.jerk_max, acc_max / t / 10.0, jerk_limit);
+        let jerk_out = Self::clamp_limit(options.jerk_out_max, jerk_in, jerk_limit);
+
+        PlanLimits {
+            vel_max,
+            acc_max,
+            dec_max,
+            jerk_in,
+            jerk_out,
         }
+    }
 
-        self.intp_data.ta[0] = ta;
-        self.intp_data.tb[0] = tb;
-        self.intp_data.td[0] = td;
-        self.intp_data.h = hk;
+    // The limit of the move if it is given, otherwise the fallback value, and neither is above
+    // the limit of the interpolator. A value that can't be a limit (not positive or not finite)
+    // falls back as well, it never turns into the highest limit
+    fn clamp_limit(value: Option<f32>, fallback: f32, limit: f32) -> f32 {
+        let value = match value {
+            Some(x) if x.is_finite() && x > 1e-6 => x,
+            _ => fallback,
+        };
+        value.min(limit)
     }
+}
 
-    fn generate_jerk_acc_vel_segment(&mut self) {
-        if self.intp_data.h >= (self.target_data.dist - self.intp_data.dist)
-            || self.intp_data.dec_right_away
-        {
-            // Check decelerate distance, do acceleration only when decelerate
-            // distance is less than remaining distance
-            return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VEL_LIMIT: f32 = 400.0;
+    const ACC_LIMIT: f32 = 4000.0;
+    const JERK_LIMIT: f32 = 40000.0;
+    const PERIOD_S: f32 = 0.005;
+
+    fn interpolator() -> SCurveInterpolator {
+        SCurveInterpolator::new(VEL_LIMIT, ACC_LIMIT, JERK_LIMIT, PERIOD_S)
+    }
+
+    #[test]
+    fn limits_of_move_are_clamped_to_interpolator_limits() {
+        let options = MoveOptions {
+            acc_max: Some(2.0 * ACC_LIMIT),
+            dec_max: Some(50.0),
+            jerk_max: Some(2.0 * JERK_LIMIT),
+            jerk_out_max: Some(500.0),
+            ..Default::default()
+        };
+        let limits = interpolator().resolve_limits(100.0, &options);
+
+        assert_eq!(limits.acc_max, ACC_LIMIT);
+        assert_eq!(limits.dec_max, 50.0);
+        assert_eq!(limits.jerk_in, JERK_LIMIT);
+        assert_eq!(limits.jerk_out, 500.0);
+    }
+
+    #[test]
+    fn invalid_limits_of_move_fall_back_to_defaults() {
+        let intper = interpolator();
+        let defaults = intper.resolve_limits(100.0, &MoveOptions::default());
+        for value in [0.0, 1e-9, -10.0, f32::NAN, f32::INFINITY] {
+            let options = MoveOptions {
+                acc_max: Some(value),
+                dec_max: Some(value),
+                jerk_max: Some(value),
+                jerk_out_max: Some(value),
+                ..Default::default()
+            };
+            let limits = intper.resolve_limits(100.0, &options);
+
+            assert_eq!(limits.acc_max, defaults.acc_max);
+            assert_eq!(limits.dec_max, defaults.dec_max);
+            assert_eq!(limits.jerk_in, defaults.jerk_in);
+            assert_eq!(limits.jerk_out, defaults.jerk_out);
+            assert!(limits.acc_max < ACC_LIMIT && limits.jerk_in < JERK_LIMIT);
         }
+    }
 
-        let t = self.motion_constraint.sampling_time;
+    // The acceleration and jerk limits of the sweep, they are far apart to tell them apart
+    const ACC_PAIRS: [(f32, f32); 3] = [(100.0, 1000.0), (1000.0, 100.0), (400.0, 400.0)];
+    const JERK_PAIRS: [(f32, f32); 3] = [(1000.0, 20000.0), (20000.0, 1000.0), (5000.0, 5000.0)];
+    const DISPLACEMENTS: [f32; 6] = [0.3, 4.0, 60.0, 500.0, -4.0, -500.0];
+    const VEL_MAXS: [f32; 2] = [40.0, 200.0];
+
+    // Rounding of the integration and of f32
+    const LIMIT_TOLERANCE: f32 = 1e-3;
+    const POS_TOLERANCE: f32 = 1e-3;
 
-        let vel_max = self.target_data.vel_max;
-        let acc_max = self.target_data.acc_max;
-        let jerk_min = self.target_data.jerk_min;
-        let jerk_max = self.target_data.jerk_max;
-
-        let vel_cur = self.intp_data.vel;
-        let acc_cur = self.intp_data.acc;
-
-        // Check if we can continue using jMax to accelerate
-        let end_vel_cur = vel_cur - (acc_cur * acc_cur / (2.0 * jerk_min));
-        if end_vel_cur < vel_max && acc_cur < acc_max {
-            let jerk_temp = (acc_max - acc_cur) / t;
-            self.intp_data.jerk = jerk_max.min(jerk_temp);
-        } else if end_vel_cur < vel_max && acc_cur >= acc_max {
-            self.intp_data.acc = acc_max;
-            self.intp_data.jerk = 0.0;
-        } else if end_vel_cur >= vel_max && acc_cur > 0.0 {
-            let jerk_temp = (0.0 - acc_cur) / t;
-            self.intp_data.jerk = jerk_min.max(jerk_temp);
-        } else if end_vel_cur >= vel_max && acc_cur <= 0.0 {
-            self.intp_data.acc = 0.0;
-            self.intp_data.jerk = 0.0;
+    // Runs the move to the end and returns the samples
+    fn run_move(
+        intper: &mut SCurveInterpolator,
+        displacement: f32,
+        vel_max: f32,
+        options: &MoveOptions,
+    ) -> Vec<InterpolationDataOutput> {
+        intper.set_target(0.0, displacement, 0.0, 0.0, vel_max, options);
+        let mut samples = Vec::new();
+        while intper.get_intp_status() == InterpolationStatus::Busy {
+            assert!(samples.len() < 100_000, "move doesn't finish");
+            intper.interpolate();
+            samples.push(intper.get_intp_data());
         }
+        samples
     }
 
-    fn generate_jerk_dec_segment(&mut self) {
-        if self.intp_data.h < (self.target_data.dist - self.intp_data.dist)
-            && !self.intp_data.dec_right_away
-        {
-            return;
+    #[test]
+    fn asymmetric_limits_are_respected_and_target_is_reached() {
+        for (acc_max, dec_max) in ACC_PAIRS {
+            for (jerk_in, jerk_out) in JERK_PAIRS {
+                for displacement in DISPLACEMENTS {
+                    for vel_max in VEL_MAXS {
+                        let case = format!(
+                            "acc {acc_max}/{dec_max}, jerk {jerk_in}/{jerk_out}, \
+                             displacement {displacement}, vel {vel_max}"
+                        );
+                        let options = MoveOptions {
+                            acc_max: Some(acc_max),
+                            dec_max: Some(dec_max),
+                            jerk_max: Some(jerk_in),
+                            jerk_out_max: Some(jerk_out),
+                            ..Default::default()
+                        };
+                        let mut intper = interpolator();
+                        let samples = run_move(&mut intper, displacement, vel_max, &options);
+
+                        // The limits are in the direction of the move
+                        let dir = displacement.signum();
+                        for x in samples.iter() {
+                            let (vel, acc, jerk) = (dir * x.vel, dir * x.acc, dir * x.jerk);
+                            assert!(vel <= vel_max * (1.0 + LIMIT_TOLERANCE), "{case}: v {vel}");
+                            assert!(acc <= acc_max * (1.0 + LIMIT_TOLERANCE), "{case}: a {acc}");
+                            assert!(-acc <= dec_max * (1.0 + LIMIT_TOLERANCE), "{case}: a {acc}");
+
+                            // Jerk-in builds the acceleration up, jerk-out releases it. The jerk
+                            // is the one of the piece at the sample, so it builds up the
+                            // acceleration of the sample if their signs match
+                            let jerk_limit = if acc * jerk >= 0.0 { jerk_in } else { jerk_out };
+                            assert!(
+                                jerk.abs() <= jerk_limit * (1.0 + LIMIT_TOLERANCE),
+                                "{case}: j {jerk}, a {acc}"
+                            );
+                        }
+
+                        let end = samples.last().unwrap();
+                        let pos_error = end.pos - displacement;
+                        assert!(
+                            pos_error.abs() <= POS_TOLERANCE,
+                            "{case}: error {pos_error}"
+                        );
+                        assert_eq!(end.vel, 0.0, "{case}");
+                        assert_eq!(end.acc, 0.0, "{case}");
+                    }
+                }
+            }
         }
+    }
 
-        // Need to decelerate, record the period when decelerating phase takes control
-        if self.intp_data.dec_start_period == usize::MIN {
-            self.intp_data.dec_start_period = self.intp_data.steps;
-            self.intp_data.ta[1] = self.intp_data.ta[0];
-            self.intp_data.tb[1] = self.intp_data.tb[0];
-            self.intp_data.td[1] = self.intp_data.td[0];
+    // Asymmetric limits of the plan tests, in the units of the interpolator
+    const PLAN_OPTIONS: MoveOptions = MoveOptions {
+        acc_max: Some(400.0),
+        dec_max: Some(1000.0),
+        jerk_max: Some(5000.0),
+        jerk_out_max: Some(20000.0),
+        acc_end: 0.0,
+    };
+
+    // Runs the segment that is set and checks every sample against the plan, the segment is done
+    // at the first sample at or after the end of the plan
+    fn assert_runs_as_planned(intper: &mut SCurveInterpolator, plan: &SCurvePlan, case: &str) {
+        let pos_start = intper.get_intp_data().pos;
+        let mut steps = 0;
+        while intper.get_intp_status() == InterpolationStatus::Busy {
+            assert!(steps < 100_000, "{case}: move doesn't finish");
+            intper.interpolate();
+            steps += 1;
+
+            let t = steps as f32 * PERIOD_S;
+            let x = intper.get_intp_data();
+            let y = plan.evaluate(t);
+            assert!(
+                (x.pos - pos_start - y.pos).abs() <= POS_TOLERANCE,
+                "{case}: t {t}"
+            );
+            assert_eq!(x.vel, y.vel, "{case}: t {t}");
+            assert_eq!(x.acc, y.acc, "{case}: t {t}");
+            assert_eq!(x.jerk, y.jerk, "{case}: t {t}");
         }
 
-        let t = self.motion_constraint.sampling_time;
+        let total_time = plan.total_time();
+        assert!((steps - 1) as f32 * PERIOD_S < total_time, "{case}");
+        assert!(total_time <= steps as f32 * PERIOD_S, "{case}");
+    }
 
-        let first_stage_start_period = 0_usize;
-        let first_stage_end_period = (self.intp_data.ta[1] / t) as usize;
-
-        let second_stage_start_period = first_stage_end_period;
-        let second_stage_end_period = ((self.intp_data.td[1] - self.intp_data.tb[1]) / t) as usize;
-
-        let third_stage_start_period = second_stage_end_period;
-        let third_stage_end_period = (self.intp_data.td[1] / t) as usize;
-
-        let elapsed_period = self.intp_data.steps - self.intp_data.dec_start_period;
-        if first_stage_start_period <= elapsed_period && elapsed_period <= first_stage_end_period {
-            let jerk_temp = (self.target_data.acc_min - self.intp_data.acc) / t;
-            self.intp_data.jerk = self.target_data.jerk_min.max(jerk_temp);
-        } else if second_stage_start_period <= elapsed_period
-            && elapsed_period <= second_stage_end_period
-        {
-            self.intp_data.jerk = 0.0;
-            self.intp_data.acc = self.target_data.acc_min;
-        } else if third_stage_start_period <= elapsed_period
-            && elapsed_period <= third_stage_end_period
-        {
-            let jerk_temp = (self.target_data.acc_end - self.intp_data.acc) / t;
-            self.intp_data.jerk = self.target_data.jerk_max.min(jerk_temp);
-        } else {
-            self.intp_data.vel = self.target_data.vel_end;
-            self.intp_data.acc = 0.0;
-            self.intp_data.jerk = 0.0;
+    #[test]
+    fn plan_matches_sampled_move() {
+        let cases = [
+            (60.0, 0.0, 0.0, 0.0),
+            (-4.0, 0.0, 0.0, 0.0),
+            (0.3, 0.0, 0.0, 0.0),
+            (500.0, 0.0, 50.0, 0.0),
+            (-30.0, 0.0, -40.0, 100.0),
+        ];
+        for (displacement, vel_start, vel_end, acc_end) in cases {
+            let case = format!("displacement {displacement}, vel_end {vel_end}, acc {acc_end}");
+            let options = MoveOptions {
+                acc_end,
+                ..PLAN_OPTIONS
+            };
+            let mut intper = interpolator();
+            let plan = intper
+                .plan(displacement, vel_start, vel_end, 200.0, &options)
+                .unwrap();
+
+            intper.set_target(0.0, displacement, vel_start, vel_end, 200.0, &options);
+            assert_runs_as_planned(&mut intper, &plan, &case);
+            assert!(
+                (plan.displacement() - displacement).abs() <= POS_TOLERANCE,
+                "{case}"
+            );
+            assert_eq!(plan.evaluate(plan.total_time()).vel, vel_end, "{case}");
+        }
+    }
 
-            // set finished status
-            self.intp_status = InterpolationStatus::Done;
-            self.intp_data.dec_start_period = usize::MIN;
-            self.intp_data.dec_right_away = false;
+    #[test]
+    fn plan_continues_velocity_and_acceleration_of_running_segment() {
+        let mut intper = interpolator();
+        intper.set_target(0.0, 60.0, 0.0, 0.0, 200.0, &PLAN_OPTIONS);
+        for _ in 0..40 {
+            intper.interpolate();
+        }
+        let data = intper.get_intp_data();
+        assert!(data.vel > 0.0 && data.acc > 0.0);
+
+        // Reversed while accelerating, and a shorter move in the same direction
+        for displacement in [-20.0, 10.0] {
+            let case = format!("displacement {displacement}");
+            let mut intper = intper.clone();
+            let plan = intper
+                .plan(displacement, 0.0, 0.0, 200.0, &PLAN_OPTIONS)
+                .unwrap();
+            assert_eq!(plan.evaluate(0.0).vel, data.vel, "{case}");
+            assert_eq!(plan.evaluate(0.0).acc, data.acc, "{case}");
+
+            intper.set_target(0.0, displacement, 0.0, 0.0, 200.0, &PLAN_OPTIONS);
+            assert_runs_as_planned(&mut intper, &plan, &case);
         }
     }
 
-    fn integrate(&mut self) {
-        let jerk = self.intp_data.jerk;
-        let acc = self.intp_data.acc;
-        let vel = self.intp_data.vel;
-        let dist = self.intp_data.dist;
-        let t = self.motion_constraint.sampling_time;
+    #[test]
+    fn move_that_starts_with_acceleration_continues_without_jerk_spike() {
+        let mut running = interpolator();
+        running.set_target(0.0, 60.0, 0.0, 0.0, 200.0, &PLAN_OPTIONS);
+        for _ in 0..10 {
+            running.interpolate();
+        }
+        assert!(running.get_intp_data().acc > 0.0);
+
+        // The move is replaced while the acceleration builds up, by a longer move, a move that
+        // has to brake right away and a reversal. The jerk between the samples stays within the
+        // jerk limits of the move, jerk-out is the higher one
+        let jerk_max = PLAN_OPTIONS.jerk_out_max.unwrap();
+        for displacement in [100.0, 10.0, -20.0] {
+            let mut intper = running.clone();
+            let mut prev = intper.get_intp_data();
+            intper.set_target(0.0, displacement, 0.0, 0.0, 200.0, &PLAN_OPTIONS);
+            while intper.get_intp_status() == InterpolationStatus::Busy {
+                intper.interpolate();
+                let x = intper.get_intp_data();
+                let jerk = (x.acc - prev.acc) / PERIOD_S;
+                assert!(
+                    jerk.abs() <= jerk_max * (1.0 + LIMIT_TOLERANCE),
+                    "displacement {displacement}: jerk {jerk}, a {} -> {}",
+                    prev.acc,
+                    x.acc
+                );
+                prev = x;
+            }
+        }
+    }
 
-        let acc_next = acc + t * jerk;
-        let vel_next = vel + (t / 2.0) * (acc + acc_next);
-        let dist_next = dist + (t / 2.0) * (vel + vel_next);
+    #[test]
+    fn plan_reports_phases_and_peaks_of_asymmetric_limits() {
+        let plan = interpolator()
+            .plan(500.0, 0.0, 0.0, 200.0, &PLAN_OPTIONS)
+            .unwrap();
+        let phases = plan.phases();
+
+        // The move is long enough to reach all limits, the peak is built up with jerk-in
+        assert_eq!(plan.vel_peak(), 200.0);
+        assert_eq!(plan.acc_peak(), 400.0);
+        assert_eq!(plan.dec_peak(), -1000.0);
+        assert!((phases.tj1 - 400.0 / 5000.0).abs() < 1e-6);
+        assert!((phases.tj2 - 1000.0 / 5000.0).abs() < 1e-6);
+        let total_time = phases.ta + phases.tv + phases.td;
+        assert!((plan.total_time() - total_time).abs() < 1e-6);
+    }
 
-        self.intp_data.acc = acc_next;
-        self.intp_data.vel = vel_next;
-        self.intp_data.dist = dist_next;
-        self.intp_data.pos = self.target_data.pos_offset + self.intp_data.pos_end + dist_next;
-        self.intp_data.steps += 1;
+    #[test]
+    fn plan_rejects_end_velocity_that_cannot_be_reached() {
+        let intper = interpolator();
 
-        // Store intp pos if the state is Done
-        if self.intp_status == InterpolationStatus::Done {
-            self.intp_data.pos_end = self.target_data.dir * self.intp_data.pos;
-        }
+        assert_eq!(
+            intper.plan(0.3, 0.0, 150.0, 200.0, &PLAN_OPTIONS).err(),
+            Some(PlanError::DistanceTooShort)
+        );
+        assert_eq!(
+            intper.plan(f32::NAN, 0.0, 0.0, 200.0, &PLAN_OPTIONS).err(),
+            Some(PlanError::InvalidInput)
+        );
     }
 }
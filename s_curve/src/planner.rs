@@ -0,0 +1,403 @@
+// Offline planning of a double S move. The move is the jerk limited profile of `profile.rs`, the
+// phases are solved in closed form and the peak velocity of a short move is solved numerically.
+// The whole move is planned at once, so the duration and the peaks are known before the move
+// starts and the profile can be evaluated at any time. `SCurveInterpolator` samples the same plan,
+// so a move runs as it is planned
+
+use crate::profile::{phase_vel_diff, Profile, State};
+use crate::InterpolationDataOutput;
+
+// Limits of one move, the acceleration and jerk limits are magnitudes. The acceleration builds up
+// with `jerk_in` and is released with `jerk_out`
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct PlanLimits {
+    pub vel_max: f32,
+    pub acc_max: f32,
+    pub dec_max: f32,
+    pub jerk_in: f32,
+    pub jerk_out: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlanError {
+    // Non-finite value or a limit that is not positive
+    InvalidInput,
+    // The end velocity is faster than `vel_max`
+    VelocityAboveLimit,
+    // The end velocity can't be reached within the displacement
+    DistanceTooShort,
+}
+
+// Durations of the phases, unit: s
+//
+// * ta: acceleration phase, the acceleration ramps to its peak in `tj1` with jerk-in, holds it if
+//   it is at the limit and ramps to 0 with jerk-out
+// * tv: constant velocity phase
+// * td: deceleration phase, the deceleration ramps to its peak in `tj2` and back the same way
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct PhaseDurations {
+    pub ta: f32,
+    pub tv: f32,
+    pub td: f32,
+    pub tj1: f32,
+    pub tj2: f32,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct SCurvePlan {
+    // The profile is planned as a positive move, which means `q_end > q_start`. A negative move
+    // is flipped into the direction of the move and flipped back at the output
+    dir: f32,
+    profile: Profile,
+}
+
+impl SCurvePlan {
+    // Plans the move from the start velocity and acceleration to the end velocity and
+    // acceleration. A start that is faster than `vel_max` slows down to it first
+    pub fn new(
+        displacement: f32,
+        vel_start: f32,
+        vel_end: f32,
+        acc_start: f32,
+        acc_end: f32,
+        limits: &PlanLimits,
+    ) -> Result<Self, PlanError> {
+        let is_limit = |x: f32| x.is_finite() && x > 0.0;
+        if ![displacement, vel_start, vel_end, acc_start, acc_end]
+            .iter()
+            .all(|x| x.is_finite())
+            || ![
+                limits.vel_max,
+                limits.acc_max,
+                limits.dec_max,
+                limits.jerk_in,
+                limits.jerk_out,
+            ]
+            .into_iter()
+            .all(is_limit)
+        {
+            return Err(PlanError::InvalidInput);
+        }
+        if vel_end.abs() > limits.vel_max {
+            return Err(PlanError::VelocityAboveLimit);
+        }
+
+        let plan = Self::new_move(displacement, vel_start, vel_end, acc_start, acc_end, limits);
+        if plan.profile.end().pos > plan.dir * displacement {
+            return Err(PlanError::DistanceTooShort);
+        }
+        Ok(plan)
+    }
+
+    // Same as `new` without the checks, a move that can't reach the end velocity within the
+    // displacement decelerates right away and runs past the end
+    pub(crate) fn new_move(
+        displacement: f32,
+        vel_start: f32,
+        vel_end: f32,
+        acc_start: f32,
+        acc_end: f32,
+        limits: &PlanLimits,
+    ) -> Self {
+        let dir = if displacement >= 0.0 { 1.0 } else { -1.0 };
+        let start = State {
+            pos: 0.0,
+            vel: dir * vel_start,
+            acc: dir * acc_start,
+        };
+        // The move can't end with an acceleration that is beyond the limits
+        let acc_end = (dir * acc_end).clamp(-limits.dec_max, limits.acc_max);
+
+        Self {
+            dir,
+            profile: Profile::new_move(dir * displacement, start, dir * vel_end, acc_end, limits),
+        }
+    }
+
+    // Plans the stop from the velocity and acceleration right away
+    pub fn stop(vel: f32, acc: f32, limits: &PlanLimits) -> Self {
+        let dir = if vel != 0.0 {
+            vel.signum()
+        } else if acc != 0.0 {
+            acc.signum()
+        } else {
+            1.0
+        };
+        let start = State {
+            pos: 0.0,
+            vel: dir * vel,
+            acc: dir * acc,
+        };
+
+        Self {
+            dir,
+            profile: Profile::new_stop(start, limits),
+        }
+    }
+
+    pub fn phases(&self) -> PhaseDurations {
+        let profile = &self.profile;
+        PhaseDurations {
+            ta: profile.acc_phase().duration(),
+            tv: profile.cruise(),
+            td: profile.dec_phase().duration(),
+            tj1: profile.acc_phase().rise(),
+            tj2: profile.dec_phase().rise(),
+        }
+    }
+
+    pub fn total_time(&self) -> f32 {
+        self.profile.total_time()
+    }
+
+    // Position at the end of the move relative to the start, it is the displacement unless the
+    // plan is a stop
+    pub fn displacement(&self) -> f32 {
+        self.dir * self.profile.end().pos
+    }
+
+    // Velocity of the constant velocity phase, it is lower than `vel_max` if the move is too short
+    pub fn vel_peak(&self) -> f32 {
+        self.dir * self.profile.vel_peak()
+    }
+
+    // Acceleration of the acceleration phase, it is lower than `acc_max` if the velocity change
+    // is too small to reach it
+    pub fn acc_peak(&self) -> f32 {
+        self.dir * self.profile.acc_phase().peak()
+    }
+
+    // Acceleration of the deceleration phase, the sign is opposite to the move
+    pub fn dec_peak(&self) -> f32 {
+        self.dir * self.profile.dec_phase().peak()
+    }
+
+    // Position, velocity, acceleration and jerk at time `t` after the start. The position is
+    // relative to the start, and the move continues with the end velocity after `total_time`
+    pub fn evaluate(&self, t: f32) -> InterpolationDataOutput {
+        let (state, jerk) = self.profile.evaluate(t);
+        let dir = self.dir;
+        InterpolationDataOutput {
+            pos: dir * state.pos,
+            vel: dir * state.vel,
+            acc: dir * state.acc,
+            jerk: dir * jerk,
+        }
+    }
+}
+
+// One move of a chain, see `plan_junctions`
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct ChainMove {
+    pub displacement: f32,
+    // Magnitude of the velocity at the end of the move, in the direction of the move. It is the
+    // highest allowed velocity before planning and the planned velocity after it
+    pub vel_end: f32,
+    pub limits: PlanLimits,
+}
+
+// Plans the highest velocity at the end of each move of a chain, so the moves run into each
+// other without stopping. The velocity is 0 where the direction is reversed, and a junction is
+// not faster than `vel_max` of the moves on both sides. A backward pass limits each junction to
+// the velocity that the next move can still decelerate from to its own end velocity, and a
+// forward pass to the velocity that the move can reach from its start, both with the jerk
+// limited phases of `SCurvePlan`. `vel_start` is the velocity at the start of the first move
+pub fn plan_junctions(vel_start: f32, moves: &mut [ChainMove]) {
+    let dir = |x: &ChainMove| if x.displacement >= 0.0 { 1.0 } else { -1.0 };
+
+    for i in 0..moves.len() {
+        let vel_end = match moves.get(i + 1) {
+            Some(next)
+                if next.displacement != 0.0
+                    && moves[i].displacement != 0.0
+                    && dir(next) == dir(&moves[i]) =>
+            {
+                moves[i].vel_end.min(next.limits.vel_max)
+            }
+            Some(_) => 0.0,
+            None => moves[i].vel_end,
+        };
+        moves[i].vel_end = vel_end.clamp(0.0, moves[i].limits.vel_max);
+    }
+
+    for i in (1..moves.len()).rev() {
+        let x = moves[i];
+        let vel_entry = reachable_velocity(x.displacement.abs(), x.vel_end, &x.limits, true);
+        moves[i - 1].vel_end = moves[i - 1].vel_end.min(vel_entry);
+    }
+
+    let mut vel = moves.first().map_or(0.0, |x| (dir(x) * vel_start).max(0.0));
+    for x in moves.iter_mut() {
+        let vel_exit = reachable_velocity(x.displacement.abs(), vel, &x.limits, false);
+        x.vel_end = x.vel_end.min(vel_exit);
+        vel = x.vel_end;
+    }
+}
+
+// Share of the distance that is kept free of the phase in `reachable_velocity`, so the rounding of
+// f32 doesn't make the planned move longer than its displacement
+const DIST_MARGIN: f32 = 1e-4;
+
+// Highest velocity that can be reached from `vel` within `dist`, or that can be decelerated from
+// to `vel` if `is_dec` is true. The phase is the same as the one of the move, with the jerk-in and
+// jerk-out of its limits
+fn reachable_velocity(dist: f32, vel: f32, limits: &PlanLimits, is_dec: bool) -> f32 {
+    let vel_max = limits.vel_max.max(vel);
+    let dist = dist * (1.0 - DIST_MARGIN);
+    (vel + phase_vel_diff(dist, vel, limits, is_dec)).min(vel_max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    // Asymmetric limits, so a phase with one jerk for both ramps would be off
+    const LIMITS: PlanLimits = PlanLimits {
+        vel_max: 200.0,
+        acc_max: 400.0,
+        dec_max: 1000.0,
+        jerk_in: 5000.0,
+        jerk_out: 20000.0,
+    };
+
+    // Rounding of f32
+    const DIST_TOLERANCE: f32 = 1e-3;
+
+    fn chain(displacements: &[f32], limits: &[PlanLimits]) -> Vec<ChainMove> {
+        displacements
+            .iter()
+            .zip(limits.iter().cycle())
+            .map(|(&displacement, &limits)| ChainMove {
+                displacement,
+                vel_end: limits.vel_max,
+                limits,
+            })
+            .collect()
+    }
+
+    const SLOW_LIMITS: PlanLimits = PlanLimits {
+        vel_max: 80.0,
+        acc_max: 400.0,
+        dec_max: 150.0,
+        jerk_in: 5000.0,
+        jerk_out: 1000.0,
+    };
+
+    // Chains with the same limits for every move, the last move of a chain ends at rest
+    fn chains(limits: PlanLimits) -> Vec<Vec<ChainMove>> {
+        let mut chains = vec![
+            chain(&[50.0, 0.5, 0.5, 0.5, 50.0, 0.2], &[limits]),
+            chain(&[3.0, 3.0, 3.0, -3.0, -3.0, 60.0, 1.0], &[limits]),
+            chain(&[0.1; 8], &[limits]),
+            chain(&[20.0, 20.0, 1.0, 20.0, 0.3, 0.3], &[limits]),
+            chain(&[-40.0, -2.0, -40.0, -2.0, -0.5], &[limits]),
+        ];
+        for x in chains.iter_mut() {
+            x.last_mut().unwrap().vel_end = 0.0;
+        }
+        chains
+    }
+
+    #[test]
+    fn junction_velocity_can_stop_within_remaining_queue() {
+        for limits in [LIMITS, SLOW_LIMITS] {
+            for (i, moves) in chains(limits).iter_mut().enumerate() {
+                plan_junctions(0.0, moves);
+
+                for j in 0..moves.len() {
+                    // The queue up to the next standstill, Ex: a reversal or the end of the queue
+                    let rest = &moves[j + 1..];
+                    let len = rest
+                        .iter()
+                        .position(|x| x.vel_end == 0.0)
+                        .map_or(0, |k| k + 1);
+                    let dist: f32 = rest[..len].iter().map(|x| x.displacement.abs()).sum();
+
+                    let vel = moves[j].vel_end;
+                    let stop = SCurvePlan::stop(vel, 0.0, &limits).displacement();
+                    assert!(
+                        stop <= dist + DIST_TOLERANCE,
+                        "chain {i}, junction {j}: vel {vel}, stop {stop}, dist {dist}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_move_of_planned_chain_is_feasible() {
+        // The moves of a chain change the limits as well
+        let mut mixed = chain(&[20.0, 20.0, 1.0, 20.0, 0.3, 0.3], &[LIMITS, SLOW_LIMITS]);
+        mixed.extend(chain(
+            &[-40.0, -2.0, -40.0, -2.0, -0.5],
+            &[SLOW_LIMITS, LIMITS],
+        ));
+        mixed.last_mut().unwrap().vel_end = 0.0;
+
+        let mut all = chains(LIMITS);
+        all.extend(chains(SLOW_LIMITS));
+        all.push(mixed);
+        for (i, moves) in all.iter_mut().enumerate() {
+            plan_junctions(0.0, moves);
+
+            let mut vel = 0.0;
+            for (j, x) in moves.iter().enumerate() {
+                let dir = x.displacement.signum();
+                // The end velocity is reached without running past the end of the move
+                let vel_end = dir * x.vel_end;
+                let plan = SCurvePlan::new(x.displacement, vel, vel_end, 0.0, 0.0, &x.limits);
+                assert!(plan.is_ok(), "chain {i}, move {j}: {plan:?}");
+                assert!(x.vel_end <= x.limits.vel_max, "chain {i}, move {j}");
+                vel = vel_end;
+            }
+        }
+    }
+
+    #[test]
+    fn planning_full_queue_takes_small_share_of_control_cycle() {
+        // The look-ahead runs over the whole command queue of the firmware in each 5 ms cycle,
+        // the bound leaves room for a target that is slower than the host
+        const QUEUE_SIZE: usize = 32;
+        const RUNS: usize = 20;
+        const BOUND: Duration = Duration::from_micros(50);
+
+        let mut displacements = [0.0; QUEUE_SIZE];
+        for (i, x) in displacements.iter_mut().enumerate() {
+            *x = [0.05, 2.0, 40.0, 0.5][i % 4];
+        }
+        let moves = chain(&displacements, &[LIMITS, SLOW_LIMITS]);
+
+        let elapsed = (0..RUNS)
+            .map(|_| {
+                let mut x = moves.clone();
+                let start = Instant::now();
+                plan_junctions(0.0, &mut x);
+                start.elapsed()
+            })
+            .min()
+            .unwrap();
+        assert!(elapsed < BOUND, "{elapsed:?}");
+    }
+
+    #[test]
+    fn junction_is_at_rest_where_direction_is_reversed() {
+        let mut moves = chain(&[30.0, -30.0, 30.0], &[LIMITS]);
+        plan_junctions(0.0, &mut moves);
+
+        assert_eq!(moves[0].vel_end, 0.0);
+        assert_eq!(moves[1].vel_end, 0.0);
+        assert!(moves[2].vel_end > 0.0);
+    }
+
+    #[test]
+    fn long_moves_run_into_each_other_at_max_velocity() {
+        let mut moves = chain(&[100.0, 100.0, 100.0], &[LIMITS]);
+        moves[2].vel_end = 0.0;
+        plan_junctions(0.0, &mut moves);
+
+        assert_eq!(moves[0].vel_end, LIMITS.vel_max);
+        assert_eq!(moves[1].vel_end, LIMITS.vel_max);
+        assert_eq!(moves[2].vel_end, 0.0);
+    }
+}
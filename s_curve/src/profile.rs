@@ -0,0 +1,443 @@
+// Jerk limited profile of one move, made of pieces of constant jerk. The profile is solved in
+// closed form, the interpolator samples it and the planner queries it, so the sampled move and
+// the plan are the same. The move is planned as a positive move, like the interpolator
+//
+// The move has an acceleration phase from the start velocity to the peak velocity, a constant
+// velocity phase and a deceleration phase to the end velocity. In each phase the acceleration
+// goes from its start value to a peak, holds the peak if it is at the limit, and goes to its end
+// value. The acceleration builds up with jerk-in and is released with jerk-out, a ramp that
+// crosses 0 is released first and built up again
+
+#[cfg(not(feature = "std"))]
+use num_traits::Float;
+
+use crate::planner::PlanLimits;
+
+// The peak velocity of a move that can't reach `vel_max` is solved in at most this number of
+// steps, each step evaluates the profile once. The move is planned in the control cycle, so the
+// cost is bounded instead of solving to the last bit, the distance that is left by the solve is
+// covered by a constant velocity phase at the solved peak
+const PEAK_SOLVE_STEPS: usize = 12;
+// The solve stops when the distance of the move is this close to the displacement, relative to it
+const PEAK_SOLVE_TOLERANCE: f32 = 1e-4;
+
+// A phase has a ramp that crosses 0, a constant acceleration and another ramp that crosses 0
+const PHASE_PIECES: usize = 5;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct State {
+    pub pos: f32,
+    pub vel: f32,
+    pub acc: f32,
+}
+
+impl State {
+    // State after `t` with constant `jerk`
+    fn advance(&self, t: f32, jerk: f32) -> Self {
+        Self {
+            pos: self.pos + self.vel * t + self.acc * t * t / 2.0 + jerk * t * t * t / 6.0,
+            vel: self.vel + self.acc * t + jerk * t * t / 2.0,
+            acc: self.acc + jerk * t,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+struct Piece {
+    duration: f32,
+    jerk: f32,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Phase {
+    pieces: [Piece; PHASE_PIECES],
+    len: usize,
+    // Peak acceleration and the duration of the ramp from the start acceleration to it
+    peak: f32,
+    rise: f32,
+}
+
+impl Phase {
+    fn push(&mut self, duration: f32, jerk: f32) {
+        if duration > 0.0 && self.len < PHASE_PIECES {
+            self.pieces[self.len] = Piece { duration, jerk };
+            self.len += 1;
+        }
+    }
+
+    // The acceleration changes linearly from `acc_from` to `acc_to`
+    fn push_ramp(&mut self, acc_from: f32, acc_to: f32, limits: &PlanLimits) {
+        if acc_from * acc_to < 0.0 {
+            self.push_ramp(acc_from, 0.0, limits);
+            self.push_ramp(0.0, acc_to, limits);
+            return;
+        }
+
+        let jerk = ramp_jerk(acc_from, acc_to, limits);
+        let sign = if acc_to >= acc_from { 1.0 } else { -1.0 };
+        self.push((acc_to - acc_from).abs() / jerk, sign * jerk);
+    }
+
+    fn pieces(&self) -> &[Piece] {
+        &self.pieces[..self.len]
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.pieces().iter().map(|x| x.duration).sum()
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+
+    pub fn rise(&self) -> f32 {
+        self.rise
+    }
+
+    fn end(&self, start: State) -> State {
+        self.pieces()
+            .iter()
+            .fold(start, |x, piece| x.advance(piece.duration, piece.jerk))
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Profile {
+    start: State,
+    acc_phase: Phase,
+    // Duration of the constant velocity phase
+    cruise: f32,
+    dec_phase: Phase,
+    // End of the move, the integration of the phases ends there up to rounding
+    end: State,
+}
+
+impl Profile {
+    // Profile over `dist` from the start state to the end velocity and acceleration. The peak
+    // velocity is the highest one that fits into the distance. If the end velocity can't be
+    // reached within the distance, the move decelerates right away and runs past the end
+    pub fn new_move(
+        dist: f32,
+        start: State,
+        vel_end: f32,
+        acc_end: f32,
+        limits: &PlanLimits,
+    ) -> Self {
+        let start = State { pos: 0.0, ..start };
+        let profile = |vel_peak: f32, cruise: f32| {
+            let mut profile = Self {
+                start,
+                acc_phase: transition(start.acc, 0.0, vel_peak - start.vel, limits),
+                cruise,
+                dec_phase: transition(0.0, acc_end, vel_end - vel_peak, limits),
+                ..Default::default()
+            };
+            profile.end = State {
+                pos: dist,
+                vel: vel_end,
+                acc: acc_end,
+            };
+            profile
+        };
+        let error = |vel_peak: f32| profile(vel_peak, 0.0).integrate().pos - dist;
+
+        let vel_max = limits.vel_max;
+        let vel_low = vel_end.max(0.0).min(vel_max);
+        let error_max = error(vel_max);
+        if error_max <= 0.0 {
+            return profile(vel_max, -error_max / vel_max);
+        }
+        let error_low = error(vel_low);
+        if error_low >= 0.0 {
+            let mut profile = profile(vel_low, 0.0);
+            profile.end.pos = profile.integrate().pos;
+            return profile;
+        }
+
+        // The distance grows with the peak velocity. It is smooth between the peak velocities
+        // where the peak acceleration of a phase passes a breakpoint of `transition`, the bounds
+        // are narrowed to one of these pieces before the solve
+        let (acc_max, acc_min) = (limits.acc_max, -limits.dec_max);
+        let acc_peaks = [acc_max, acc_min, 0.0, start.acc];
+        let dec_peaks = [acc_max, acc_min, 0.0, acc_end];
+        let mut breakpoints = [0.0; 8];
+        for (i, (acc_peak, dec_peak)) in acc_peaks.into_iter().zip(dec_peaks).enumerate() {
+            breakpoints[i] = start.vel + ramps_vel_diff(start.acc, acc_peak, 0.0, limits);
+            breakpoints[4 + i] = vel_end - ramps_vel_diff(0.0, dec_peak, acc_end, limits);
+        }
+        breakpoints.sort_unstable_by(|x, y| x.total_cmp(y));
+
+        // Binary search for the piece over the breakpoints between the bounds
+        let mut fit = (vel_low, error_low);
+        let mut over = (vel_max, error_max);
+        let (mut low, mut high) = (0, breakpoints.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let vel = breakpoints[mid];
+            if vel <= fit.0 {
+                low = mid + 1;
+                continue;
+            }
+            if vel >= over.0 {
+                high = mid;
+                continue;
+            }
+            let point = (vel, error(vel));
+            if point.1 > 0.0 {
+                over = point;
+                high = mid;
+            } else {
+                fit = point;
+                low = mid + 1;
+            }
+        }
+
+        let (vel_fit, error_fit) = solve_peak(fit, over, dist * PEAK_SOLVE_TOLERANCE, error);
+        let cruise = if vel_fit > 0.0 {
+            -error_fit / vel_fit
+        } else {
+            0.0
+        };
+        profile(vel_fit, cruise)
+    }
+
+    // Profile that decelerates from the start state to standstill right away
+    pub fn new_stop(start: State, limits: &PlanLimits) -> Self {
+        let start = State { pos: 0.0, ..start };
+        let mut profile = Self {
+            start,
+            acc_phase: transition(start.acc, 0.0, -start.vel, limits),
+            ..Default::default()
+        };
+        profile.end = State {
+            pos: profile.integrate().pos,
+            ..Default::default()
+        };
+        profile
+    }
+
+    pub fn acc_phase(&self) -> &Phase {
+        &self.acc_phase
+    }
+
+    pub fn cruise(&self) -> f32 {
+        self.cruise
+    }
+
+    pub fn dec_phase(&self) -> &Phase {
+        &self.dec_phase
+    }
+
+    pub fn total_time(&self) -> f32 {
+        self.acc_phase.duration() + self.cruise + self.dec_phase.duration()
+    }
+
+    pub fn end(&self) -> State {
+        self.end
+    }
+
+    // Velocity of the constant velocity phase, it is the highest velocity of the move unless the
+    // move starts or ends faster
+    pub fn vel_peak(&self) -> f32 {
+        self.acc_phase.end(self.start).vel
+    }
+
+    fn integrate(&self) -> State {
+        let state = self.acc_phase.end(self.start);
+        let state = state.advance(self.cruise, 0.0);
+        self.dec_phase.end(state)
+    }
+
+    // State and jerk at `t` after the start. After `total_time` the move continues from the end
+    // with the end velocity and acceleration
+    pub fn evaluate(&self, t: f32) -> (State, f32) {
+        let total_time = self.total_time();
+        if t >= total_time {
+            return (self.end.advance(t - total_time, 0.0), 0.0);
+        }
+
+        let cruise = Piece {
+            duration: self.cruise,
+            jerk: 0.0,
+        };
+        let pieces = self
+            .acc_phase
+            .pieces()
+            .iter()
+            .chain(core::iter::once(&cruise))
+            .chain(self.dec_phase.pieces());
+
+        let mut state = self.start;
+        let mut t = t.max(0.0);
+        for piece in pieces {
+            if t < piece.duration {
+                return (state.advance(t, piece.jerk), piece.jerk);
+            }
+            state = state.advance(piece.duration, piece.jerk);
+            t -= piece.duration;
+        }
+        (self.end, 0.0)
+    }
+}
+
+// Solves `error(vel) == 0` between `fit` and `over`, the points of the velocity and its error
+// where the error is below and above 0. It is the false position method, the bound that is kept
+// twice in a row is weighted down (Illinois), so it converges faster than bisection on the
+// smooth pieces of the distance and the steps are bounded. Returns the highest point that is
+// found below 0, so the move doesn't run past the end
+fn solve_peak(
+    mut fit: (f32, f32),
+    mut over: (f32, f32),
+    tolerance: f32,
+    error: impl Fn(f32) -> f32,
+) -> (f32, f32) {
+    // The weights of the errors in the interpolation, the bound that is kept twice in a row is
+    // weighted down. `last_fit` is the side that is replaced in the last step
+    let (mut weight_fit, mut weight_over) = (1.0, 1.0);
+    let mut last_fit = None;
+    for _ in 0..PEAK_SOLVE_STEPS {
+        if -fit.1 <= tolerance {
+            break;
+        }
+
+        let (error_fit, error_over) = (weight_fit * fit.1, weight_over * over.1);
+        let vel = fit.0 - error_fit * (over.0 - fit.0) / (error_over - error_fit);
+        // The rounding of the interpolation can land on a bound, bisect instead
+        let vel = if vel > fit.0 && vel < over.0 {
+            vel
+        } else {
+            (fit.0 + over.0) / 2.0
+        };
+        let point = (vel, error(vel));
+        let is_fit = point.1 <= 0.0;
+        if is_fit {
+            fit = point;
+            weight_fit = 1.0;
+        } else {
+            over = point;
+            weight_over = 1.0;
+        }
+        if last_fit == Some(is_fit) {
+            if is_fit {
+                weight_over /= 2.0;
+            } else {
+                weight_fit /= 2.0;
+            }
+        }
+        last_fit = Some(is_fit);
+    }
+    fit
+}
+
+// Velocity change of the phase that covers `dist`, the phase starts and ends without acceleration
+// like the phases at the junction of blended moves. `vel` is the lower velocity of the phase, where
+// an acceleration starts or a deceleration (`is_dec`) ends. The distance is a cubic of the peak
+// acceleration, or a quadratic of the hold at the limit, so it is solved in closed form
+pub(crate) fn phase_vel_diff(dist: f32, vel: f32, limits: &PlanLimits, is_dec: bool) -> f32 {
+    if dist <= 0.0 {
+        return 0.0;
+    }
+
+    // The deceleration runs backward in time from `vel` like an acceleration that builds up with
+    // jerk-out and releases with jerk-in
+    let (acc_max, jerk_first, jerk_last) = if is_dec {
+        (limits.dec_max, limits.jerk_out, limits.jerk_in)
+    } else {
+        (limits.acc_max, limits.jerk_in, limits.jerk_out)
+    };
+    // The ramps take `c1 * peak` and `c2 * peak`, the distance without hold is
+    // `vel * s * peak + c * peak^3`
+    let (c1, c2) = (1.0 / jerk_first, 1.0 / jerk_last);
+    let s = c1 + c2;
+    let c = c1 * c1 / 6.0 + c1 * c2 / 2.0 + c2 * c2 / 3.0;
+
+    let dist_ramps = vel * s * acc_max + c * acc_max * acc_max * acc_max;
+    if dist <= dist_ramps {
+        // `peak^3 + a * peak = b` has one positive root. It is scaled by the root of `b`, and
+        // Cardano's formula is rearranged, so the terms don't cancel out
+        let b = dist / c;
+        let scale = b.cbrt();
+        let w = vel * s / c / (scale * scale) / 3.0;
+        let u = (0.5 + (0.25 + w * w * w).sqrt()).cbrt();
+        let peak = scale / (u * u + w + w * w / (u * u));
+        return peak * peak * s / 2.0;
+    }
+
+    // The distance grows with `acc_max / 2 * hold^2 + b * hold` past the ramps
+    let b = vel + acc_max * acc_max * (c1 / 2.0 + c2);
+    let dist_hold = dist - dist_ramps;
+    let hold = 2.0 * dist_hold / (b + (b * b + 2.0 * acc_max * dist_hold).sqrt());
+    acc_max * acc_max * s / 2.0 + acc_max * hold
+}
+
+// Jerk of a ramp that doesn't cross 0, the acceleration builds up with jerk-in and is released
+// with jerk-out
+fn ramp_jerk(acc_from: f32, acc_to: f32, limits: &PlanLimits) -> f32 {
+    if acc_to.abs() > acc_from.abs() {
+        limits.jerk_in
+    } else {
+        limits.jerk_out
+    }
+}
+
+// Velocity change of the ramp from `acc_from` to `acc_to`
+fn ramp_vel_diff(acc_from: f32, acc_to: f32, limits: &PlanLimits) -> f32 {
+    if acc_from * acc_to < 0.0 {
+        return ramp_vel_diff(acc_from, 0.0, limits) + ramp_vel_diff(0.0, acc_to, limits);
+    }
+
+    // Mean acceleration times the duration
+    let jerk = ramp_jerk(acc_from, acc_to, limits);
+    (acc_from + acc_to) / 2.0 * (acc_to - acc_from).abs() / jerk
+}
+
+// Velocity change of the ramps of a phase, from `acc_start` to `peak` and then to `acc_end`
+fn ramps_vel_diff(acc_start: f32, peak: f32, acc_end: f32, limits: &PlanLimits) -> f32 {
+    ramp_vel_diff(acc_start, peak, limits) + ramp_vel_diff(peak, acc_end, limits)
+}
+
+// Phase that changes the velocity by `vel_diff`, the acceleration goes from `acc_start` to a peak
+// in `-dec_max..=acc_max` and then to `acc_end`. The peak is held if the velocity change needs
+// more than the ramps to the limit
+fn transition(acc_start: f32, acc_end: f32, vel_diff: f32, limits: &PlanLimits) -> Phase {
+    // The velocity change of the ramps grows with the peak. Between the breakpoints it is
+    // `a * peak^2 + b`, the jerk of each ramp only changes where the peak passes `acc_start`,
+    // `acc_end` or 0
+    let ramps = |peak: f32| ramps_vel_diff(acc_start, peak, acc_end, limits);
+
+    let (acc_max, acc_min) = (limits.acc_max, -limits.dec_max);
+    let (peak, hold) = if vel_diff >= ramps(acc_max) {
+        (acc_max, (vel_diff - ramps(acc_max)) / acc_max)
+    } else if vel_diff <= ramps(acc_min) {
+        (acc_min, (vel_diff - ramps(acc_min)) / acc_min)
+    } else {
+        let mut points = [acc_min, acc_start, acc_end, 0.0, acc_max];
+        points.sort_unstable_by(|x, y| x.total_cmp(y));
+        let peak = points
+            .windows(2)
+            .map(|x| (x[0].max(acc_min), x[1].min(acc_max)))
+            .find(|&(low, high)| low <= high && ramps(low) <= vel_diff && vel_diff <= ramps(high))
+            .map_or(0.0, |(low, high)| {
+                let (diff_low, diff_high) = (ramps(low), ramps(high));
+                if diff_high <= diff_low {
+                    return low;
+                }
+                // The interval doesn't cross 0, so the peak has the sign of the interval
+                let square = low * low
+                    + (vel_diff - diff_low) * (high * high - low * low) / (diff_high - diff_low);
+                let sign = if low + high >= 0.0 { 1.0 } else { -1.0 };
+                (sign * square.max(0.0).sqrt()).clamp(low, high)
+            });
+        (peak, 0.0)
+    };
+
+    let mut phase = Phase {
+        peak,
+        ..Default::default()
+    };
+    phase.push_ramp(acc_start, peak, limits);
+    phase.rise = phase.duration();
+    phase.push(hold, 0.0);
+    phase.push_ramp(peak, acc_end, limits);
+    phase
+}
@@ -0,0 +1,13 @@
+use std::process::Command;
+
+fn main() {
+    // The git hash is reported by `GetDeviceInfo` like the firmware, see `fw/build.rs`
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=SIM_GIT_HASH={}", git_hash.trim());
+}
@@ -0,0 +1,301 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::mpsc;
+
+use postcard_rpc::{
+    define_dispatch,
+    server::{
+        Dispatch,
+        impls::test_channels::{
+            ChannelWireRx, ChannelWireSpawn, ChannelWireTx,
+            dispatch_impl::{Settings, WireSpawnImpl, WireTxImpl, new_server},
+        },
+    },
+};
+
+use fw::device::{ControlLoop, DEFAULT_PARAMS, DeviceChannels, MOTOR_COUNT, device_motion};
+use fw::endpoints::*;
+use fw::hal::Clock;
+use fw::param_store::ParamStore;
+use fw::params::{DeviceParams, ParamRegistry};
+use motor_sim::params::MotorParams;
+use motor_sim::{SimClock, SimFlashPage, SimMotor, SimMotorDriver, SimPositionSensor};
+use protocol::*;
+
+const BOARD_NAME: &str = "sim";
+
+// Depth of the in-process channels between client and server
+const WIRE_CHANNEL_DEPTH: usize = 64;
+
+// The simulated device uses the same channels, control loop and endpoint handlers as the
+// firmware, see `fw::device` and `fw::endpoints`
+static CHANNELS: DeviceChannels = DeviceChannels::new();
+
+// The control loop runs once per process, all the sessions talk to the same simulated motors
+// and share the parameters, like the firmware keeps them across connections
+static CONTROL_LOOP: OnceLock<SimDevice> = OnceLock::new();
+
+struct SimDevice {
+    params: Mutex<ParamState<SimFlashPage>>,
+    info: DeviceInfo,
+    // Set while a session is served
+    connected: AtomicBool,
+}
+
+// The parameters of the simulated device outlive the sessions, the context of a session only
+// locks them for one request
+struct SharedParams(&'static Mutex<ParamState<SimFlashPage>>);
+
+impl ParamAccess for SharedParams {
+    type Page = SimFlashPage;
+
+    fn with<R>(
+        &mut self,
+        f: impl FnOnce(&mut ParamRegistry, &mut ParamStore<SimFlashPage>) -> R,
+    ) -> R {
+        let mut params = self.0.lock().unwrap();
+        let ParamState { registry, store } = &mut *params;
+        f(registry, store)
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    // Only one host can be connected at a time, like the usb device
+    Busy,
+    // `start_control_loop` is not called yet
+    NotStarted,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Busy => write!(f, "another session is connected"),
+            SessionError::NotStarted => write!(f, "the control loop is not started"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+// postcard-rpc
+type Context = EndpointContext<SharedParams>;
+
+define_dispatch! {
+    app: SimApp;
+    spawn_fn: spawn_fn;
+    tx_impl: WireTxImpl;
+    spawn_impl: WireSpawnImpl;
+    context: Context;
+
+    endpoints: {
+        list: ENDPOINT_LIST;
+
+        | EndpointTy                    | kind      | handler                       |
+        | ----------                    | ----      | -------                       |
+        | SetMotorCommandEndPoint       | async     | set_motor_cmd_handler         |
+        | GetPidGainsEndPoint           | async     | get_pid_gains_handler         |
+        | SetPidGainsEndPoint           | blocking  | set_pid_gains_handler         |
+        | GetMotionStatusEndPoint       | async     | get_motion_status_handler     |
+        | SetPositionBatchEndPoint      | async     | set_position_batch_handler    |
+        | GetDeviceInfoEndPoint         | blocking  | get_device_info_handler       |
+        | ListParamsEndPoint            | blocking  | list_params_handler           |
+        | GetParamEndPoint              | blocking  | get_param_handler             |
+        | SetParamEndPoint              | blocking  | set_param_handler             |
+        | SaveParamsEndPoint            | blocking  | save_params_handler           |
+        | LoadParamsEndPoint            | blocking  | load_params_handler           |
+        | FactoryResetEndPoint          | blocking  | factory_reset_handler         |
+        | SetTelemetryConfigEndPoint    | blocking  | set_telemetry_config_handler  |
+    };
+    topics_in: {
+        list: TOPICS_IN_LIST;
+
+        | TopicTy                       | kind      | handler                       |
+        | ----------                    | ----      | -------                       |
+        | HeartbeatTopic                | blocking  | heartbeat_handler             |
+    };
+    topics_out: {
+        list: TOPICS_OUT_LIST;
+    };
+}
+
+type SimControlLoop = ControlLoop<SimPositionSensor, SimPositionSensor, SimMotorDriver, SimClock>;
+
+/// Starts the control loop of the simulated device, the loop is only started once, the params
+/// of later calls are ignored
+pub fn start_control_loop(params: MotorParams) {
+    CONTROL_LOOP.get_or_init(|| {
+        // The encoder counts per revolution is taken from the motor params of the plant
+        let device_params = DeviceParams {
+            counts_per_rev: params.counts_per_rev,
+            ..DEFAULT_PARAMS
+        };
+        let left_motor = SimMotor::new(params.clone());
+        let right_motor = SimMotor::new(params);
+        let control_loop = ControlLoop::new(
+            sim_motion(MotorId::Left, &left_motor, &device_params),
+            sim_motion(MotorId::Right, &right_motor, &device_params),
+            &CHANNELS,
+        );
+
+        // A dedicated thread plays the role of the timer interrupt executor in the firmware
+        let period_s = device_params.period_s;
+        thread::spawn(move || motion_task(control_loop, [left_motor, right_motor], period_s));
+
+        SimDevice {
+            params: Mutex::new(ParamState {
+                registry: ParamRegistry::new(device_params),
+                store: ParamStore::new(SimFlashPage::new()),
+            }),
+            info: DeviceInfo {
+                fw_version: heapless::String::try_from(env!("CARGO_PKG_VERSION"))
+                    .unwrap_or_default(),
+                git_hash: heapless::String::try_from(env!("SIM_GIT_HASH")).unwrap_or_default(),
+                board_name: heapless::String::try_from(BOARD_NAME).unwrap_or_default(),
+                motor_count: MOTOR_COUNT,
+                control_period_us: (period_s * 1_000_000.0) as u32,
+                tick_hz: SimClock::TICK_HZ,
+                schema_hash: SCHEMA_HASH,
+            },
+            connected: AtomicBool::new(false),
+        }
+    });
+}
+
+/// Runs one session over a pair of channels, each message is one postcard-rpc frame. The
+/// session ends when the client closes the channels, then both motors are halted like the
+/// firmware does when the connection is lost.
+pub async fn serve(
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+) -> Result<(), SessionError> {
+    let device = CONTROL_LOOP.get().ok_or(SessionError::NotStarted)?;
+    if device.connected.swap(true, Ordering::AcqRel) {
+        return Err(SessionError::Busy);
+    }
+    let Some(context) = Context::new(&CHANNELS, SharedParams(&device.params), device.info.clone())
+    else {
+        device.connected.store(false, Ordering::Release);
+        return Err(SessionError::Busy);
+    };
+
+    let dispatcher = SimApp::new(context, ChannelWireSpawn {});
+    let kkind = dispatcher.min_key_len();
+    let mut server = new_server(
+        dispatcher,
+        Settings {
+            tx: ChannelWireTx::new(tx),
+            rx: ChannelWireRx::new(rx),
+            buf: DEVICE_RX_BUF_SIZE,
+            kkind,
+        },
+    );
+
+    // The samples that are recorded while no host is connected are stale
+    CHANNELS.telemetry.clear();
+    let motor_data_publish = tokio::spawn(motor_data_publish_loop(
+        &CHANNELS,
+        server.sender(),
+        SimClock::TICK_HZ,
+        || (),
+    ));
+    let auto_tune_publish = tokio::spawn(auto_tune_publish_loop(&CHANNELS, server.sender()));
+    let motion_event_publish = tokio::spawn(motion_event_publish_loop(&CHANNELS, server.sender()));
+
+    // The server only returns when the client is gone
+    let _ = server.run().await;
+
+    // Wait until the tasks are dropped, so the watch receivers are released for next session
+    motor_data_publish.abort();
+    auto_tune_publish.abort();
+    motion_event_publish.abort();
+    let _ = motor_data_publish.await;
+    let _ = auto_tune_publish.await;
+    let _ = motion_event_publish.await;
+    halt_motors(&CHANNELS);
+    println!("connection is lost, halt motors");
+
+    // The context is dropped with the server, so the next session can take the publishers
+    drop(server);
+    device.connected.store(false, Ordering::Release);
+
+    Ok(())
+}
+
+/// Starts the simulated device in this process and returns the client side of the channels,
+/// they can be passed to `postcard_rpc::host_client::test_channels::new_from_channels`.
+/// It must be called in a tokio runtime.
+pub fn start_in_process(params: MotorParams) -> (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) {
+    start_control_loop(params);
+
+    let (client_tx, server_rx) = mpsc::channel(WIRE_CHANNEL_DEPTH);
+    let (server_tx, client_rx) = mpsc::channel(WIRE_CHANNEL_DEPTH);
+    tokio::spawn(async move {
+        if let Err(e) = serve(server_tx, server_rx).await {
+            println!("in-process session is rejected: {e}");
+        }
+    });
+
+    (client_tx, client_rx)
+}
+
+/// Serves the simulated device on a tcp socket, the frames are sent with the framing in
+/// `host::tcp`. Connections are served one after another.
+pub async fn serve_tcp(addr: impl ToSocketAddrs, params: MotorParams) -> io::Result<()> {
+    start_control_loop(params);
+
+    let listener = TcpListener::bind(addr).await?;
+    println!("sim server is listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        println!("{peer} is connected");
+
+        let (tx, rx) = host::tcp::bridge(stream);
+        if let Err(e) = serve(tx, rx).await {
+            println!("{peer} is rejected: {e}");
+        }
+    }
+}
+
+fn sim_motion(
+    id: MotorId,
+    sim_motor: &SimMotor,
+    params: &DeviceParams,
+) -> fw::device::DeviceMotion<SimPositionSensor, SimMotorDriver, SimClock> {
+    device_motion(
+        id,
+        sim_motor.sensor(),
+        sim_motor.driver(),
+        sim_motor.clock(),
+        params,
+        &CHANNELS,
+    )
+}
+
+fn motion_task(mut control_loop: SimControlLoop, sim_motors: [SimMotor; 2], period_s: f32) {
+    let period = Duration::from_secs_f32(period_s);
+    let mut next_tick = Instant::now();
+
+    loop {
+        // The plants are advanced in simulated time, the loop is only paced in real time so
+        // the host sees the same data rate as the board. The simulated clock is used, so the
+        // watchdog keeps the same timing as the control loop when the simulation runs slower
+        // than real time
+        next_tick += period;
+        thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+
+        if control_loop.cycle() {
+            println!("heartbeat is lost, halt motors");
+        }
+
+        for sim_motor in sim_motors.iter() {
+            sim_motor.step(period_s);
+        }
+    }
+}
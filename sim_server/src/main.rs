@@ -0,0 +1,19 @@
+use motor_sim::params::MotorParams;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+// Serves a simulated device over tcp, the motor params (toml file) and the address can be given
+// as arguments, Ex: `cargo run -- ../motor_sim/params.toml 127.0.0.1:7878`
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let params = match args.next() {
+        Some(path) => MotorParams::from_file(path).unwrap_or_else(|e| panic!("{e}")),
+        None => MotorParams::default(),
+    };
+    let addr = args.next().unwrap_or(DEFAULT_ADDR.to_string());
+
+    if let Err(e) = sim_server::serve_tcp(addr, params).await {
+        println!("sim server is stopped: {e}");
+    }
+}
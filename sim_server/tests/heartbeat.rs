@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use fw::motion::STANDSTILL_VEL_RPM;
+use motor_sim::params::MotorParams;
+use postcard_rpc::header::{VarSeq, VarSeqKind};
+use postcard_rpc::host_client::test_channels;
+use protocol::*;
+
+const HEARTBEAT_TIMEOUT_MS: u32 = 100;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Waits for a sample of the left motor that meets the condition
+async fn wait_for_sample(
+    sub: &mut postcard_rpc::host_client::MultiSubscription<MotorDataBatch>,
+    mut cond: impl FnMut(&MotorProcessData) -> bool,
+) -> MotorProcessData {
+    let wait = async {
+        loop {
+            let batch = sub.recv().await.unwrap();
+            if batch.id != MotorId::Left {
+                continue;
+            }
+            if let Some(x) = batch.samples.iter().find(|x| cond(&x.data)) {
+                return x.data;
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, wait)
+        .await
+        .expect("no sample meets the condition")
+}
+
+// The connection stays open, only the heartbeats stop, so the motors are halted by the
+// watchdog in the control loop instead of the end of the session
+#[tokio::test(flavor = "multi_thread")]
+async fn lost_heartbeat_halts_the_motors() {
+    let (tx, rx) = sim_server::start_in_process(MotorParams::default());
+    let client = test_channels::new_from_channels(tx, rx, VarSeqKind::Seq2);
+    let mut sub = client
+        .subscribe_multi::<MotorDataBatchTopic>(64)
+        .await
+        .unwrap();
+
+    let heartbeat = Heartbeat {
+        timeout_ms: HEARTBEAT_TIMEOUT_MS,
+    };
+    client
+        .publish::<HeartbeatTopic>(VarSeq::Seq2(0), &heartbeat)
+        .await
+        .unwrap();
+    let cmd = SequencedCommand {
+        seq_id: 1,
+        command: MotorCommand::VelocityCommand(1000.0),
+    };
+    client
+        .send_resp::<SetMotorCommandEndPoint>(&(MotorId::Left, cmd))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let running = wait_for_sample(&mut sub, |x| x.actual_vel > 500.0).await;
+    assert_eq!(running.watchdog_state, WatchdogState::Armed);
+
+    // No heartbeat is sent anymore, the watchdog expires after the timeout of the last one, see
+    // the tests in `fw::watchdog` for the timing
+    wait_for_sample(&mut sub, |x| x.watchdog_state == WatchdogState::Expired).await;
+    let stopped = wait_for_sample(&mut sub, |x| x.actual_vel.abs() < STANDSTILL_VEL_RPM).await;
+    assert_eq!(stopped.watchdog_state, WatchdogState::Expired);
+}
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use host::client::Client;
+use motor_sim::params::MotorParams;
+use protocol::{MotorId, PositionCommand};
+
+// Position tolerance of the end of the move, unit: rad
+const POS_TOLERANCE: f32 = 0.05;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// The sim serves one session at a time, so the whole session is in one test
+#[tokio::test(flavor = "multi_thread")]
+async fn client_talks_to_simulated_device_in_process() {
+    let (tx, rx) = sim_server::start_in_process(MotorParams::default());
+    let client = Client::new_in_memory(tx, rx);
+
+    assert_eq!(client.ping(42).await.unwrap(), 42);
+    let info = client.check_compatibility().await.unwrap();
+    assert_eq!(info.motor_count, 2);
+
+    // The samples of the move are streamed while it runs
+    let mut telemetry = client.subscribe_telemetry(64).await.unwrap();
+
+    let cmd = PositionCommand {
+        displacement: 10.0,
+        vel_max: 1000.0,
+        ..Default::default()
+    };
+    tokio::time::timeout(TIMEOUT, client.move_and_wait(MotorId::Left, cmd))
+        .await
+        .expect("the move is not completed in time")
+        .unwrap();
+
+    // The position controller settles the wheel at the end of the move, the samples of each
+    // motor are recorded every cycle and are delivered in order
+    let mut last_seq = None;
+    loop {
+        let (id, sample) = tokio::time::timeout(TIMEOUT, telemetry.recv())
+            .await
+            .expect("the wheel doesn't settle at the end of the move")
+            .unwrap();
+        if id != MotorId::Left {
+            continue;
+        }
+        if let Some(seq) = last_seq {
+            assert!(sample.seq > seq);
+        }
+        last_seq = Some(sample.seq);
+
+        let data = sample.data;
+        if data.intp_pos == cmd.displacement
+            && (data.actual_pos - cmd.displacement).abs() < POS_TOLERANCE
+        {
+            break;
+        }
+    }
+    assert!(telemetry.clock().offset_s().is_some());
+}
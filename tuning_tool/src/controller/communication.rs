@@ -6,11 +6,20 @@ use log::{debug, error, warn};
 use postcard_rpc::host_client::{HostErr, MultiSubRxError};
 use tokio::select;
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{mpsc, oneshot, watch};
 
-use host::client::{Client, ClientError};
+use host::client::{Client, ClientError, ConnectError, Transport};
+use motor_sim::params::MotorParams;
 use protocol::*;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Connection {
+    // Board or `sim_server` that is reached through usb or tcp
+    Device(Transport),
+    // Simulated device that runs in the tuning tool process
+    Simulator,
+}
+
 struct MotorCommandActor {
     client: Arc<Client>,
     halt_command_recv: mpsc::Receiver<()>,
@@ -18,6 +27,8 @@ struct MotorCommandActor {
     command_queue_recv: mpsc::UnboundedReceiver<MotorCommand>,
     cancel_actor_recv: watch::Receiver<bool>,
     task_err_send: watch::Sender<Result<(), String>>,
+    // The commands that are rejected by the board, they are dropped and shown to the user
+    command_err_send: UnboundedSender<CommandError>,
 }
 
 impl MotorCommandActor {
@@ -36,13 +47,17 @@ impl MotorCommandActor {
         // This queue is used to store the command from `command_queue`, and it will
         // be used when sending position commands. Ex: The embedded board has 8 buffer
         // limit, but user wants to send 10 position commands:
-        // 1. First 8 commands are sent
-        // 2. `buffer_full` is set to true, need to wait embedded board to process commands
-        // 3. 9th and 10th commands get stored in internal queue when `buffer_full` is true
-        // 4. When `buffer_full` is false, the command in the internal queue will be sent to
-        //    the board
+        // 1. `GetMotionStatus` reports 8 credits (free slots), first 8 commands are sent
+        // 2. Credits are used up, 9th and 10th commands stay in internal queue
+        // 3. `GetMotionStatus` is queried again, it returns in the next control loop cycle,
+        //    so the board is polled once per cycle until it has processed some commands
+        // 4. The commands in the internal queue are sent with the new credits
+        //
+        // `Halt` doesn't take credits, the board always keeps space for it. Consecutive
+        // position commands are sent in one batch as many as the credits allow.
 
         let mut internal_command_cache = VecDeque::<MotorCommand>::new();
+        let mut credits = 0_u16;
 
         let _id = self
             .client
@@ -51,63 +66,163 @@ impl MotorCommandActor {
             .map_err(|_x| ClientError::Comms(HostErr::Closed))?;
 
         loop {
-            select! {
-                biased;
+            if internal_command_cache.is_empty() {
+                // Nothing to send, wait for the next command
+                select! {
+                    biased;
+
+                    flag = self.cancel_actor_recv.changed() => {
+                        if flag.is_ok() {
+                            debug!("process_motor_command(), cancel actor");
+                            break Ok(());
+                        }
+                    },
+                    Some(()) = self.halt_command_recv.recv() => self.on_halt(),
+                    Some(motor_command) = self.command_queue_recv.recv() => {
+                        push_command(&mut internal_command_cache, motor_command);
+                    },
+                }
+                continue;
+            }
 
-                flag = self.cancel_actor_recv.changed() => {
-                    if flag.is_ok() {
-                        debug!("process_motor_command(), cancel actor");
-                        break Ok(());
+            // Take the commands that arrive while the last request is running. The requests
+            // are not raced against the channels in `select!`, a request that is dropped half
+            // way may be queued by the board without the reply, and the commands are sent again
+            if let Ok(true) = self.cancel_actor_recv.has_changed() {
+                debug!("process_motor_command(), cancel actor");
+                break Ok(());
+            }
+            while let Ok(()) = self.halt_command_recv.try_recv() {
+                self.on_halt();
+            }
+            while let Ok(motor_command) = self.command_queue_recv.try_recv() {
+                push_command(&mut internal_command_cache, motor_command);
+            }
+
+            // The commands are only removed from the cache when the board replies
+            let mut attempted = 0_usize;
+            let result = self
+                .send_cached_commands(&internal_command_cache, &mut credits, &mut attempted)
+                .await;
+            match result {
+                Ok(sent) => {
+                    // Nothing is sent if there is no credit, the status is queried again in
+                    // next loop
+                    internal_command_cache.drain(..sent);
+                }
+                Err(e) => match e {
+                    ClientError::Comms(e) => {
+                        error!("process_motor_command(), unexpected error: {e:?}");
+                        break Err(ClientError::Comms(e));
                     }
-                },
-                Some(()) = self.halt_command_recv.recv() => {
-                    debug!("process_motor_command(), halt");
-                    while let Ok(_) = self.command_queue_recv.try_recv() {
-                        // Consume all the commands in the queue
+                    ClientError::Endpoint(CommandError::Faulted(id)) => {
+                        // The board doesn't accept the command until the fault is cleared, drop
+                        // it instead of retrying, the fault itself is shown with the motor data
+                        warn!("process_motor_command(), {id:?} is faulted, drop command");
+                        internal_command_cache.drain(..attempted);
                     }
-
-                    // Ignore the error because the receiver is held by the actor
-                    let _ = self.command_queue_send_internal.send(MotorCommand::Halt);
-                },
-                Some(motor_command) = self.command_queue_recv.recv() => {
-                    debug!("receive, command: {motor_command:?}");
-                    if motor_command == MotorCommand::Halt {
-                        internal_command_cache.clear();
+                    ClientError::Endpoint(
+                        CommandError::BufferFull(_) | CommandError::BatchTooLarge { .. },
+                    ) => {
+                        // The credits are out of date (Ex: the board is halted), query the
+                        // status before sending again
+                        credits = 0;
                     }
-                    internal_command_cache.push_back(motor_command);
-                },
-                result = async {
-                    if let Some(command) = internal_command_cache.front() {
-                        self
-                            .client
-                            .set_motor_cmd(MotorId::Left, command.clone())
-                            .await?;
+                    ClientError::Endpoint(
+                        e @ (CommandError::InvalidValue(..)
+                        | CommandError::NotReady(_)
+                        | CommandError::OutOfTravelLimits(_)
+                        | CommandError::UnsupportedInMode(..)),
+                    ) => {
+                        // Sending it again gets the same error, drop the command (or the whole
+                        // batch) and let the user know
+                        warn!("process_motor_command(), command is rejected: {e}");
+                        internal_command_cache.drain(..attempted);
+                        let _ = self.command_err_send.send(e);
                     }
+                },
+            }
+        }
+    }
 
-                    Ok(())
-                } => {
-                    match result {
-                            Ok(_) => {
-                                // If the command is sent successfully, pop it from the queue
-                                internal_command_cache.pop_front();
-                            }
-                            Err(e) => match e {
-                                ClientError::Comms(e) => {
-                                    error!("process_motor_command(), unexpected error: {e:?}");
-                                    break Err(ClientError::Comms(e));
-                                },
-                                _ => (),
-                            },
-                        }
-                }
+    fn on_halt(&mut self) {
+        debug!("process_motor_command(), halt");
+        while self.command_queue_recv.try_recv().is_ok() {
+            // Consume all the commands in the queue
+        }
+
+        // Ignore the error because the receiver is held by the actor
+        let _ = self.command_queue_send_internal.send(MotorCommand::Halt);
+    }
+
+    // Sends the commands at the front of the cache and returns the number of the commands that
+    // the board has queued, `attempted` is the number of the commands in the request
+    async fn send_cached_commands(
+        &self,
+        internal_command_cache: &VecDeque<MotorCommand>,
+        credits: &mut u16,
+        attempted: &mut usize,
+    ) -> Result<usize, ClientError<CommandError>> {
+        let command = internal_command_cache[0];
+        if command != MotorCommand::Halt && *credits == 0 {
+            *credits = self
+                .client
+                .get_motion_status(MotorId::Left)
+                .await
+                .map_err(|_x| ClientError::Comms(HostErr::Closed))?
+                .credits();
+            if *credits == 0 {
+                return Ok(0);
             }
         }
+
+        if let MotorCommand::PositionCommand(_) = command {
+            let batch_size = (*credits as usize).min(MAX_POSITION_BATCH_SIZE);
+            let batch: Vec<PositionCommand> = internal_command_cache
+                .iter()
+                .take(batch_size)
+                .map_while(|x| match x {
+                    MotorCommand::PositionCommand(x) => Some(*x),
+                    _ => None,
+                })
+                .collect();
+            *attempted = batch.len();
+            let (_, accepted) = self
+                .client
+                .set_position_batch(MotorId::Left, &batch)
+                .await?;
+            let accepted = (accepted as usize).min(batch.len());
+            *credits -= accepted as u16;
+
+            return Ok(accepted);
+        }
+
+        *attempted = 1;
+        self.client.set_motor_cmd(MotorId::Left, command).await?;
+        if command != MotorCommand::Halt {
+            *credits -= 1;
+        }
+
+        Ok(1)
     }
 }
 
+// A `Halt` makes the commands before it obsolete
+fn push_command(internal_command_cache: &mut VecDeque<MotorCommand>, motor_command: MotorCommand) {
+    debug!("receive, command: {motor_command:?}");
+    if motor_command == MotorCommand::Halt {
+        internal_command_cache.clear();
+    }
+    internal_command_cache.push_back(motor_command);
+}
+
+// Number of the samples that are kept until the ui takes them, 20 s of 200 Hz data. The samples
+// that don't fit are dropped, the ui shows the gap from `sample_counter`
+const MOTOR_DATA_QUEUE_SIZE: usize = 4096;
+
 struct MotorDataActor {
     client: Arc<Client>,
-    data_send: watch::Sender<MotorProcessData>,
+    data_send: mpsc::Sender<MotorProcessData>,
     cancel_actor_recv: watch::Receiver<bool>,
     task_err_send: watch::Sender<Result<(), String>>,
 }
@@ -125,12 +240,7 @@ impl MotorDataActor {
     }
 
     async fn run_internal(&mut self) -> Result<(), ClientError<Infallible>> {
-        let mut sub = self
-            .client
-            .client
-            .subscribe_multi::<protocol::MotorProcessDataTopic>(8)
-            .await
-            .map_err(|_x| ClientError::Comms(HostErr::Closed))?;
+        let mut sub = self.client.subscribe_telemetry(8).await?;
 
         // Check `ping` to make sure the device is connected
         let _id = self.client.ping(0).await?;
@@ -147,10 +257,17 @@ impl MotorDataActor {
                 },
                 res = sub.recv() => {
                     match res {
-                        Ok(data) => {
-                            if data.0 == MotorId::Left {
-                                if let Err(e) = self.data_send.send(data.1) {
-                                    error!("process_motor_data(), failed to send data: {e}");
+                        Ok((id, sample)) => {
+                            if id != MotorId::Left {
+                                continue;
+                            }
+                            match self.data_send.try_send(sample.data) {
+                                Ok(()) => (),
+                                Err(mpsc::error::TrySendError::Full(_)) => {
+                                    warn!("process_motor_data(), queue is full, drop sample");
+                                }
+                                Err(mpsc::error::TrySendError::Closed(_)) => {
+                                    error!("process_motor_data(), failed to send data, receiver is dropped");
                                     // I borrow the error type from HostError (it might be a bad idea, and this can be
                                     // impproved). When this error is triggered, it means when receiver is dropped
                                     // unexpected, and it should not happen, but I add this error and log message to
@@ -187,22 +304,42 @@ impl Drop for Communication {
 pub struct Communication {
     halt_command_send: mpsc::Sender<()>,
     command_queue_send: mpsc::UnboundedSender<MotorCommand>,
-    data_recv: watch::Receiver<MotorProcessData>,
+    // Every sample of the left motor in order, `last_data` is the latest one that is taken
+    data_recv: mpsc::Receiver<MotorProcessData>,
+    last_data: MotorProcessData,
     cancel_actor_send: watch::Sender<bool>,
     command_actor_err_recv: watch::Receiver<Result<(), String>>,
     data_actor_err_recv: watch::Receiver<Result<(), String>>,
+    command_err_recv: mpsc::UnboundedReceiver<CommandError>,
+    // Result of the compatibility check, it is taken once by `take_connect_result`
+    connect_result_recv: oneshot::Receiver<Result<DeviceInfo, ConnectError>>,
     prev_command: Option<MotorCommand>,
+    // Rate of the timestamps in the motor data, it is known after the compatibility check
+    tick_hz: u32,
 }
 
 impl Communication {
-    pub fn new(port_name: &str) -> Result<Self, String> {
-        let client = Arc::new(Client::new(port_name)?);
+    // It is called from the ui thread, only opening the transport is blocking. The device is
+    // checked by the actors, see `take_connect_result`
+    pub fn new(connection: &Connection) -> Result<Self, ConnectError> {
+        let client = match connection {
+            Connection::Device(transport) => {
+                Client::open(transport).map_err(ConnectError::Transport)?
+            }
+            Connection::Simulator => {
+                let (tx, rx) = sim_server::start_in_process(MotorParams::default());
+                Client::new_in_memory(tx, rx)
+            }
+        };
+        let client = Arc::new(client);
         let (halt_command_send, halt_command_recv) = mpsc::channel::<()>(1);
         let (command_queue_send, command_queue_recv) = mpsc::unbounded_channel::<MotorCommand>();
-        let (data_send, data_recv) = watch::channel(MotorProcessData::default());
+        let (data_send, data_recv) = mpsc::channel::<MotorProcessData>(MOTOR_DATA_QUEUE_SIZE);
         let (cancel_actor_send, cancel_actor_recv) = watch::channel(false);
         let (command_actor_err_send, command_actor_err_recv) = watch::channel(Ok(()));
         let (data_actor_err_send, data_actor_err_recv) = watch::channel(Ok(()));
+        let (command_err_send, command_err_recv) = mpsc::unbounded_channel::<CommandError>();
+        let (connect_result_send, connect_result_recv) = oneshot::channel();
 
         let mut motor_command_actor = MotorCommandActor {
             client: client.clone(),
@@ -211,6 +348,7 @@ impl Communication {
             command_queue_recv,
             cancel_actor_recv: cancel_actor_recv.clone(),
             task_err_send: command_actor_err_send,
+            command_err_send,
         };
 
         let mut motor_data_actor = MotorDataActor {
@@ -220,17 +358,29 @@ impl Communication {
             task_err_send: data_actor_err_send,
         };
 
-        tokio::spawn(async move { motor_command_actor.run().await });
-        tokio::spawn(async move { motor_data_actor.run().await });
+        // The actors only talk to a device that is built with the same `protocol`, the check
+        // is done for the usb/tcp device and the in-process simulator alike
+        tokio::spawn(async move {
+            let result = client.check_compatibility().await;
+            let compatible = result.is_ok();
+            let _ = connect_result_send.send(result);
+            if compatible {
+                tokio::join!(motor_command_actor.run(), motor_data_actor.run());
+            }
+        });
 
         Ok(Self {
             halt_command_send,
             command_queue_send,
             data_recv,
+            last_data: MotorProcessData::default(),
             cancel_actor_send,
             command_actor_err_recv,
             data_actor_err_recv,
+            command_err_recv,
+            connect_result_recv,
             prev_command: None,
+            tick_hz: 1,
         })
     }
 
@@ -263,8 +413,25 @@ impl Communication {
         self.prev_command = Some(data);
     }
 
+    /// Returns the samples that are received since the last call, in order
+    pub fn take_motor_process_data(&mut self) -> Vec<MotorProcessData> {
+        let mut samples = Vec::new();
+        while let Ok(data) = self.data_recv.try_recv() {
+            samples.push(data);
+        }
+        if let Some(data) = samples.last() {
+            self.last_data = *data;
+        }
+        samples
+    }
+
+    /// Returns the latest sample that is taken by `take_motor_process_data`
     pub fn get_motor_process_data(&self) -> MotorProcessData {
-        *self.data_recv.borrow()
+        self.last_data
+    }
+
+    pub fn tick_hz(&self) -> u32 {
+        self.tick_hz
     }
 
     pub fn get_motor_command_actor_err(&self) -> Result<(), String> {
@@ -274,4 +441,14 @@ impl Communication {
     pub fn get_motor_data_actor_err(&self) -> Result<(), String> {
         self.data_actor_err_recv.borrow().clone()
     }
+
+    /// Returns the result of the compatibility check once it is done
+    pub fn take_connect_result(&mut self) -> Option<Result<(), ConnectError>> {
+        let result = self.connect_result_recv.try_recv().ok()?;
+        Some(result.map(|device| self.tick_hz = device.tick_hz))
+    }
+
+    pub fn take_command_err(&mut self) -> Option<CommandError> {
+        self.command_err_recv.try_recv().ok()
+    }
 }
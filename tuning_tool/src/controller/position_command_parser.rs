@@ -2,9 +2,10 @@ use std::collections::VecDeque;
 
 use nom::{
     IResult, Parser,
+    branch::alt,
     bytes::complete::tag,
     character::complete::multispace0,
-    combinator::{all_consuming, opt},
+    combinator::{all_consuming, map, opt, value},
     error::Error,
     multi::separated_list0,
     number::complete::float,
@@ -64,10 +65,11 @@ impl CommandParser {
         // * '(A, B, C)'
         // * '(A, B)'
         // * '(A, B,)'
-        // so we need to optionally parse a comma and a third float.
+        // * '(A, B, auto)', the board plans the end velocity with the next commands
+        // so we need to optionally parse a comma and a third value, `Some(None)` is 'auto'.
         let (input, vel_end_opt) = preceded(
             delimited(multispace0, opt(tag(",")), multispace0),
-            opt(float),
+            opt(alt((value(None, tag("auto")), map(float, Some)))),
         )
         .parse(input)?;
 
@@ -75,13 +77,16 @@ impl CommandParser {
         let (input, _) = delimited(multispace0, tag(")"), multispace0).parse(input)?;
 
         // If user doesn't specify third float(vel_end), we will treat it as 0 to perform 'buffered mode' motion
-        let vel_end = vel_end_opt.unwrap_or(0.0);
+        let blend = vel_end_opt == Some(None);
+        let vel_end = vel_end_opt.flatten().unwrap_or(0.0);
         Ok((
             input,
             PositionCommand {
                 displacement,
                 vel_max,
                 vel_end,
+                blend,
+                ..Default::default()
             },
         ))
     }
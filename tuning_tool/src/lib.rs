@@ -4,6 +4,8 @@ use eframe::egui::Ui;
 
 use protocol::{ControlMode, MotorProcessData};
 
+use controller::communication::Connection;
+
 pub mod controller;
 pub mod view;
 
@@ -21,31 +23,43 @@ pub enum ErrorType {
     #[default]
     None,
     StartError,
+    // The device is built with a different protocol
+    IncompatibleDevice,
     StopError,
     ModeSwitchTimeout,
     ParseCommandError,
     CommunicationError,
+    MotorFault,
+    CommandRejected,
 }
 
 #[derive(Default, Clone, Copy)]
 pub struct ProfileData {
+    // Device time of the sample, unit: s
+    timestamp_s: f64,
+    sample_counter: u32,
     intp_pos: f32,
     intp_vel: f32,
     intp_acc: f32,
     intp_jerk: f32,
     act_pos: f32,
     act_vel: f32,
+    following_error: f32,
 }
 
 impl ProfileData {
-    pub fn from(motor_data: &MotorProcessData) -> Self {
+    // `tick_hz` is the rate of the device clock, see `DeviceInfo::tick_hz`
+    pub fn from(motor_data: &MotorProcessData, tick_hz: u32) -> Self {
         Self {
+            timestamp_s: motor_data.timestamp as f64 / tick_hz as f64,
+            sample_counter: motor_data.sample_counter,
             intp_pos: motor_data.intp_pos,
             intp_vel: motor_data.intp_vel,
             intp_acc: motor_data.intp_acc,
             intp_jerk: motor_data.intp_jerk,
             act_pos: motor_data.actual_pos,
             act_vel: motor_data.actual_vel,
+            following_error: motor_data.following_error,
         }
     }
 }
@@ -58,6 +72,7 @@ pub enum ProfileDataType {
     IntpJerk,
     ActPos,
     ActVel,
+    FollowingError,
 }
 
 impl Display for ProfileDataType {
@@ -69,14 +84,15 @@ impl Display for ProfileDataType {
             ProfileDataType::IntpJerk => write!(f, "intp_jerk"),
             ProfileDataType::ActPos => write!(f, "act_pos"),
             ProfileDataType::ActVel => write!(f, "act_vel"),
+            ProfileDataType::FollowingError => write!(f, "following_error"),
         }
     }
 }
 
 #[derive(Debug)]
 pub enum ViewRequest {
-    // A request that wants to start connection with a port name from connection window
-    ConnectionStart(String),
+    // A request that wants to start connection with selected transport from connection window
+    ConnectionStart(Connection),
     // A request that wants to stop connection from connection window
     ConnectionStop,
     // A request that wants to clear error from error window
@@ -16,7 +16,8 @@ pub(super) struct CommandWindow {
     // 1. dist: rad
     // 2. vel: rpm
     // 3. vel_end: rpm, the end velocity of position command block, it is optional.
-    //    If it is not given, the end velocity will be treated as 0
+    //    If it is not given, the end velocity will be treated as 0. 'auto' lets the board
+    //    plan the highest end velocity that the next commands allow, Ex: '(10, 500, auto);'
     pos_cmd: String,
 }
 
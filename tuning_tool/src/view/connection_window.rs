@@ -1,23 +1,48 @@
 use std::fmt::Display;
 
-use eframe::egui::{Button, ComboBox, Ui};
+use eframe::egui::{Button, ComboBox, TextEdit, Ui};
 use nusb;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
 
-use crate::{UiView, ViewEvent, ViewRequest};
+use host::client::Transport;
+
+use crate::{UiView, ViewEvent, ViewRequest, controller::communication::Connection};
+
+const DEFAULT_TCP_ADDR: &str = "127.0.0.1:7878";
+
+#[derive(Debug, Default, PartialEq, Clone, Copy, EnumIter)]
+enum TransportType {
+    #[default]
+    Usb,
+    Tcp,
+    Simulator,
+}
+
+impl Display for TransportType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportType::Usb => write!(f, "usb"),
+            TransportType::Tcp => write!(f, "tcp"),
+            TransportType::Simulator => write!(f, "simulator"),
+        }
+    }
+}
 
 #[derive(Default, PartialEq)]
 struct UsbInfo {
     vendor_id: u16,
     product_id: u16,
     product_string: String,
+    serial_number: String,
 }
 
 impl Display for UsbInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{:#06x}, {:#06x}, {}",
-            self.vendor_id, self.product_id, self.product_string
+            "{:#06x}, {:#06x}, {}, {}",
+            self.vendor_id, self.product_id, self.product_string, self.serial_number
         )
     }
 }
@@ -28,13 +53,16 @@ impl From<nusb::DeviceInfo> for UsbInfo {
             vendor_id: device.vendor_id(),
             product_id: device.product_id(),
             product_string: device.product_string().unwrap_or_default().to_string(),
+            serial_number: device.serial_number().unwrap_or_default().to_string(),
         }
     }
 }
 
 #[derive(Default)]
 pub(super) struct ConnectionWindow {
+    selected_transport: TransportType,
     selected_usb: UsbInfo,
+    tcp_addr: String,
     target: bool,
     curr: bool,
     request: Option<ViewRequest>,
@@ -43,39 +71,89 @@ pub(super) struct ConnectionWindow {
 impl ConnectionWindow {
     pub fn new() -> Self {
         Self {
+            tcp_addr: DEFAULT_TCP_ADDR.to_string(),
             ..Default::default()
         }
     }
+
+    // Returns `None` if the settings of selected transport are not complete
+    fn selected_connection(&self) -> Option<Connection> {
+        match self.selected_transport {
+            TransportType::Usb => {
+                if self.selected_usb.product_string.is_empty() {
+                    return None;
+                }
+
+                let serial_number =
+                    Some(self.selected_usb.serial_number.clone()).filter(|x| !x.is_empty());
+                Some(Connection::Device(Transport::Usb {
+                    vendor_id: self.selected_usb.vendor_id,
+                    product_id: self.selected_usb.product_id,
+                    serial_number,
+                }))
+            }
+            TransportType::Tcp => {
+                let addr = self.tcp_addr.trim();
+                if addr.is_empty() {
+                    return None;
+                }
+
+                Some(Connection::Device(Transport::Tcp(addr.to_string())))
+            }
+            TransportType::Simulator => Some(Connection::Simulator),
+        }
+    }
 }
 
 impl UiView for ConnectionWindow {
     fn show(&mut self, ui: &mut Ui) {
-        let devices = nusb::list_devices().unwrap();
-
         ui.heading("Connection setup");
         ui.horizontal_centered(|ui| {
-            ComboBox::new("usb", "usbs")
-                .selected_text(format!("{}", self.selected_usb))
-                .show_ui(ui, |ui| {
-                    for device in devices {
-                        let usb_info = UsbInfo::from(device);
-                        let text = usb_info.to_string();
-                        ui.selectable_value(&mut self.selected_usb, usb_info, text);
+            // The transport can't be changed when it is connected
+            ui.add_enabled_ui(!self.curr, |ui| {
+                ComboBox::new("transport", "transport")
+                    .selected_text(format!("{}", self.selected_transport))
+                    .show_ui(ui, |ui| {
+                        for transport in TransportType::iter() {
+                            let text = transport.to_string();
+                            ui.selectable_value(&mut self.selected_transport, transport, text);
+                        }
+                    });
+
+                match self.selected_transport {
+                    TransportType::Usb => {
+                        let devices = nusb::list_devices().unwrap();
+                        ComboBox::new("usb", "usbs")
+                            .selected_text(format!("{}", self.selected_usb))
+                            .show_ui(ui, |ui| {
+                                for device in devices {
+                                    let usb_info = UsbInfo::from(device);
+                                    let text = usb_info.to_string();
+                                    ui.selectable_value(&mut self.selected_usb, usb_info, text);
+                                }
+                            });
+                    }
+                    TransportType::Tcp => {
+                        ui.add(TextEdit::singleline(&mut self.tcp_addr).desired_width(150.0));
                     }
-                });
+                    TransportType::Simulator => (),
+                }
+            });
 
             let text_in_button = if self.curr { "Stop" } else { "Start" };
             let conn_button = Button::new(text_in_button);
+            let connection = self.selected_connection();
 
             if ui
-                .add_enabled(!self.selected_usb.product_string.is_empty(), conn_button)
+                .add_enabled(self.curr || connection.is_some(), conn_button)
                 .clicked()
             {
                 self.target = !self.curr;
                 if self.target {
-                    self.request = Some(ViewRequest::ConnectionStart(
-                        self.selected_usb.product_string.clone(),
-                    ));
+                    // The button is only enabled when connection is valid
+                    if let Some(connection) = connection {
+                        self.request = Some(ViewRequest::ConnectionStart(connection));
+                    }
                 } else {
                     self.request = Some(ViewRequest::ConnectionStop);
                 }
@@ -3,7 +3,8 @@ use eframe::{
     egui::{self, Ui, Vec2},
 };
 
-use protocol::{ControlMode, MotorCommand, MotorProcessData, PositionCommand};
+use host::client::ConnectError;
+use protocol::{ControlMode, FaultCode, MotorCommand, MotorProcessData, PositionCommand};
 
 use crate::{
     ErrorType, ProfileData, ViewEvent, ViewRequest,
@@ -45,6 +46,7 @@ pub struct TuningTool {
 
     // Others
     velocity_command: f32,
+    motor_fault: FaultCode,
 }
 
 impl App for TuningTool {
@@ -107,7 +109,21 @@ impl App for TuningTool {
         self.view_events.push(ViewEvent::ConnectionStatusUpdate(
             self.communication.is_some(),
         ));
+        let samples = self.take_motor_samples();
         if let Some(motor_data) = self.get_motor_data() {
+            // Report the fault once when it is latched, the fault is cleared when the user
+            // dismisses the error
+            if motor_data.fault_code != FaultCode::None && self.motor_fault == FaultCode::None {
+                self.view_events.push(ViewEvent::ErrorOccurred(
+                    ErrorType::MotorFault,
+                    format!(
+                        "Motor fault: {}, the motor is braked. Press Ok to clear the fault",
+                        motor_data.fault_code
+                    ),
+                ));
+            }
+            self.motor_fault = motor_data.fault_code;
+
             // Run mode switch to decide current control mode
             let mode_switch_result = self.mode_switch.process(&motor_data);
             if let Err(e) = mode_switch_result {
@@ -122,8 +138,14 @@ impl App for TuningTool {
                 motor_data.control_mode_display,
             )));
 
-            self.view_events
-                .push(ViewEvent::ProfileDataUpdate(ProfileData::from(&motor_data)));
+            // Every sample is plotted, the latest one drives the mode switch
+            let tick_hz = self.communication.as_ref().map_or(1, |x| x.tick_hz());
+            for sample in samples.iter() {
+                self.view_events
+                    .push(ViewEvent::ProfileDataUpdate(ProfileData::from(
+                        sample, tick_hz,
+                    )));
+            }
 
             if let Ok(mode) = mode_switch_result {
                 // Send motor command when mode switch gives valud output mode
@@ -149,6 +171,7 @@ impl TuningTool {
             view_events: Vec::new(),
 
             velocity_command: 0.0,
+            motor_fault: FaultCode::None,
         }
     }
 
@@ -192,9 +215,22 @@ impl TuningTool {
                 self.position_command_parser.reset();
                 communication.send_motor_command(MotorCommand::Halt)
             }
+            // Auto tuning is started by command, there is no command that needs to be sent
+            // continuously in this mode
+            ControlMode::AutoTune => (),
+            // Fault mode is entered by the board, it is left by `ClearFault` when the user
+            // dismisses the error
+            ControlMode::Fault => (),
         }
     }
 
+    // The samples that are received since the last update
+    fn take_motor_samples(&mut self) -> Vec<MotorProcessData> {
+        self.communication
+            .as_mut()
+            .map_or_else(Vec::new, |x| x.take_motor_process_data())
+    }
+
     fn get_motor_data(&mut self) -> Option<MotorProcessData> {
         if self.communication.is_none() {
             return None;
@@ -211,7 +247,21 @@ impl TuningTool {
             return;
         }
 
-        let communication = self.communication.as_ref().unwrap();
+        let communication = self.communication.as_mut().unwrap();
+        if let Some(Err(e)) = communication.take_connect_result() {
+            self.view_events.push(ViewEvent::ErrorOccurred(
+                connect_error_type(&e),
+                e.to_string(),
+            ));
+        }
+
+        if let Some(e) = communication.take_command_err() {
+            self.view_events.push(ViewEvent::ErrorOccurred(
+                ErrorType::CommandRejected,
+                format!("Command is rejected, {e}"),
+            ));
+        }
+
         if let Err(e) = communication.get_motor_command_actor_err() {
             self.view_events
                 .push(ViewEvent::ErrorOccurred(ErrorType::CommunicationError, e));
@@ -233,6 +283,7 @@ impl TuningTool {
             match request {
                 ViewRequest::ErrorDismiss(prev_error_type) => match prev_error_type {
                     ErrorType::StartError
+                    | ErrorType::IncompatibleDevice
                     | ErrorType::StopError
                     | ErrorType::CommunicationError => {
                         self.communication.take();
@@ -243,6 +294,19 @@ impl TuningTool {
                             self.window_wrapper.get_window(window_type).reset();
                         }
                     }
+                    ErrorType::MotorFault => {
+                        if let Some(communication) = self.communication.as_mut() {
+                            communication.send_motor_command(MotorCommand::ClearFault);
+                        }
+
+                        // The board is in standstill mode after the fault is cleared
+                        self.mode_switch.reset();
+                        self.reset(false);
+
+                        self.window_wrapper
+                            .get_window(WindowType::ControlModeWindow)
+                            .reset();
+                    }
                     ErrorType::ModeSwitchTimeout => {
                         error!("handle mode switch error");
                         self.mode_switch.reset();
@@ -263,16 +327,18 @@ impl TuningTool {
             let a = self.window_wrapper.get_window(window_type).take_request();
             if let Some(request) = a {
                 match request {
-                    ViewRequest::ConnectionStart(port_name) => {
-                        match Communication::new(&port_name) {
+                    ViewRequest::ConnectionStart(connection) => {
+                        match Communication::new(&connection) {
                             Ok(comm) => {
                                 self.communication = Some(comm);
                                 self.view_events
                                     .push(ViewEvent::ConnectionStatusUpdate(true));
                             }
                             Err(e) => {
-                                self.view_events
-                                    .push(ViewEvent::ErrorOccurred(ErrorType::StartError, e));
+                                self.view_events.push(ViewEvent::ErrorOccurred(
+                                    connect_error_type(&e),
+                                    e.to_string(),
+                                ));
                             }
                         }
                     }
@@ -375,3 +441,12 @@ impl TuningTool {
         }
     }
 }
+
+fn connect_error_type(e: &ConnectError) -> ErrorType {
+    match e {
+        ConnectError::UnknownDevice | ConnectError::Incompatible { .. } => {
+            ErrorType::IncompatibleDevice
+        }
+        _ => ErrorType::StartError,
+    }
+}
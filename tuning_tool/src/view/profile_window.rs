@@ -1,12 +1,13 @@
 use std::collections::VecDeque;
 
 use crate::{ProfileData, ProfileDataType, UiView, ViewEvent, ViewRequest};
+use eframe::egui::{Color32, ecolor::Hsva};
 use egui_plot::{Legend, Line, Plot, PlotPoints};
 
 pub struct DataGraph {
     window_values: VecDeque<ProfileData>,
     window_size: usize,
-    data_flags: [(ProfileDataType, bool); 6],
+    data_flags: [(ProfileDataType, bool); 7],
     can_update: bool,
 }
 
@@ -22,6 +23,7 @@ impl DataGraph {
                 (ProfileDataType::IntpJerk, false),
                 (ProfileDataType::ActPos, false),
                 (ProfileDataType::ActVel, false),
+                (ProfileDataType::FollowingError, false),
             ],
             can_update: false,
         }
@@ -38,21 +40,55 @@ impl DataGraph {
         self.window_values.push_back(data);
     }
 
-    fn get_data(&self, get_data_type: ProfileDataType) -> PlotPoints {
-        let iter = self.window_values.iter().enumerate();
-        match get_data_type {
-            ProfileDataType::IntpPos => iter.map(|(x, y)| [x as f64, y.intp_pos as f64]).collect(),
-            ProfileDataType::IntpVel => iter.map(|(x, y)| [x as f64, y.intp_vel as f64]).collect(),
-            ProfileDataType::IntpAcc => iter.map(|(x, y)| [x as f64, y.intp_acc as f64]).collect(),
-            ProfileDataType::IntpJerk => {
-                iter.map(|(x, y)| [x as f64, y.intp_jerk as f64]).collect()
+    // The x axis is the device time. The line is split where `sample_counter` skips samples
+    // (Ex: lost telemetry), so a gap isn't drawn as a straight line
+    fn get_data(&self, get_data_type: ProfileDataType) -> Vec<PlotPoints> {
+        let value = |x: &ProfileData| match get_data_type {
+            ProfileDataType::IntpPos => x.intp_pos,
+            ProfileDataType::IntpVel => x.intp_vel,
+            ProfileDataType::IntpAcc => x.intp_acc,
+            ProfileDataType::IntpJerk => x.intp_jerk,
+            ProfileDataType::ActPos => x.act_pos,
+            ProfileDataType::ActVel => x.act_vel,
+            ProfileDataType::FollowingError => x.following_error,
+        };
+
+        let mut segments = Vec::new();
+        let mut segment: Vec<[f64; 2]> = Vec::new();
+        let mut prev_counter = None;
+        for x in self.window_values.iter() {
+            if prev_counter.is_some_and(|y: u32| x.sample_counter.wrapping_sub(y) != 1) {
+                segments.push(PlotPoints::from(std::mem::take(&mut segment)));
             }
-            ProfileDataType::ActPos => iter.map(|(x, y)| [x as f64, y.act_pos as f64]).collect(),
-            ProfileDataType::ActVel => iter.map(|(x, y)| [x as f64, y.act_vel as f64]).collect(),
+            segment.push([x.timestamp_s, value(x) as f64]);
+            prev_counter = Some(x.sample_counter);
+        }
+        if !segment.is_empty() {
+            segments.push(PlotPoints::from(segment));
         }
+        segments
+    }
+
+    // Number of the samples that are missing between the samples in the window
+    fn dropped_samples(&self) -> u32 {
+        self.window_values
+            .iter()
+            .zip(self.window_values.iter().skip(1))
+            .map(|(x, y)| {
+                y.sample_counter
+                    .wrapping_sub(x.sample_counter)
+                    .saturating_sub(1)
+            })
+            .sum()
     }
 }
 
+// The segments of one data type share the color and the legend entry
+fn line_color(index: usize) -> Color32 {
+    let golden_ratio = (5.0_f32.sqrt() - 1.0) / 2.0;
+    Hsva::new((index as f32 * golden_ratio).fract(), 0.85, 0.5, 1.0).into()
+}
+
 impl UiView for DataGraph {
     fn show(&mut self, ui: &mut eframe::egui::Ui) {
         let x = ui.available_width();
@@ -64,10 +100,15 @@ impl UiView for DataGraph {
                 .width(x * 0.85)
                 .height(y)
                 .show(ui, |plot_ui| {
-                    for (data_type, enable) in self.data_flags.iter() {
+                    for (i, (data_type, enable)) in self.data_flags.iter().enumerate() {
                         if *enable {
-                            let data_points = self.get_data(*data_type);
-                            plot_ui.line(Line::new(data_points).name(data_type.to_string()));
+                            for data_points in self.get_data(*data_type) {
+                                plot_ui.line(
+                                    Line::new(data_points)
+                                        .name(data_type.to_string())
+                                        .color(line_color(i)),
+                                );
+                            }
                         }
                     }
                 });
@@ -82,6 +123,8 @@ impl UiView for DataGraph {
                 for item in self.data_flags.iter_mut() {
                     ui.checkbox(&mut item.1, item.0.to_string());
                 }
+
+                ui.label(format!("dropped: {}", self.dropped_samples()));
             })
         });
     }
@@ -103,3 +146,61 @@ impl UiView for DataGraph {
         self.window_values.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(counters: &[u32]) -> DataGraph {
+        let mut graph = DataGraph::new(counters.len());
+        graph.can_update = true;
+        for (i, &x) in counters.iter().enumerate() {
+            graph.add_data_point(ProfileData {
+                timestamp_s: i as f64,
+                sample_counter: x,
+                ..Default::default()
+            });
+        }
+        graph
+    }
+
+    fn segment_lens(graph: &DataGraph) -> Vec<usize> {
+        graph
+            .get_data(ProfileDataType::IntpPos)
+            .iter()
+            .map(|x| x.points().len())
+            .collect()
+    }
+
+    #[test]
+    fn gaps_are_counted_and_split_the_line() {
+        let graph = graph(&[1, 2, 5, 6, 10]);
+        assert_eq!(graph.dropped_samples(), 2 + 3);
+        assert_eq!(segment_lens(&graph), [2, 2, 1]);
+    }
+
+    #[test]
+    fn wrap_around_of_counter_is_not_a_gap() {
+        let graph = graph(&[u32::MAX - 1, u32::MAX, 0, 1]);
+        assert_eq!(graph.dropped_samples(), 0);
+        assert_eq!(segment_lens(&graph), [4]);
+
+        let graph = self::graph(&[u32::MAX, 2]);
+        assert_eq!(graph.dropped_samples(), 2);
+        assert_eq!(segment_lens(&graph), [1, 1]);
+    }
+
+    #[test]
+    fn only_gaps_in_window_are_counted() {
+        let mut graph = graph(&[1, 5, 6]);
+        assert_eq!(graph.dropped_samples(), 3);
+
+        // The sample before the gap leaves the window
+        graph.add_data_point(ProfileData {
+            sample_counter: 7,
+            ..Default::default()
+        });
+        assert_eq!(graph.dropped_samples(), 0);
+        assert_eq!(segment_lens(&graph), [3]);
+    }
+}